@@ -0,0 +1,434 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use tokio::fs;
+use tokio::io::AsyncRead;
+use tokio_util::sync::CancellationToken;
+
+use crate::header::{BatchHeaderConfig, BatchProvenance};
+use crate::index::IndexRecord;
+use crate::reader::{self, ElementOutput, OsmReader, RootElementInfo};
+use crate::utils::{Compression, InputStream};
+use crate::validation::{IdOrder, IdOrderCheck};
+use crate::{BatchElement, BatchParser, ElementType, ParsedInput, TimeWindow, TypeBatches};
+
+type Source = InputStream;
+
+/// What a batching source contains. `osmChange` and full-history XML are plain `Xml`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Xml,
+    GzipXml,
+    Bzip2Xml,
+    ZstdXml,
+    /// Converted to XML by piping it through osmium.
+    Pbf,
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    pub elements_per_batch: usize,
+    /// Full-history input: keeps every version of an element in one batch and only
+    /// batches versions inside `time_window`.
+    pub history: bool,
+    pub time_window: TimeWindow,
+    pub output: ElementOutput,
+    pub header: BatchHeaderConfig,
+    pub provenance: BatchProvenance,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            elements_per_batch: 500,
+            history: false,
+            time_window: TimeWindow::default(),
+            output: ElementOutput::Passthrough,
+            header: BatchHeaderConfig::default(),
+            provenance: BatchProvenance::default(),
+        }
+    }
+}
+
+/// One complete batch document.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub element_type: ElementType,
+    pub batch_number: usize,
+    pub content: String,
+    /// Where each element sits in `content`.
+    pub index_records: Vec<IndexRecord>,
+}
+
+#[derive(Debug, Default)]
+pub struct BatchSummary {
+    pub elements: usize,
+    /// Batches written per element type.
+    pub batches: HashMap<ElementType, usize>,
+}
+
+/// Receives batches in the order they are cut. Batch numbers count per element type.
+pub trait BatchSink: Send + Sync {
+    fn write_batch(&self, batch: &Batch) -> impl Future<Output = Result<()>> + Send;
+
+    /// Called for every element type once its last batch is written.
+    fn finish(
+        &self,
+        _element_type: ElementType,
+        _batch_count: usize,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// The layout the service itself uses: `{dir}/{type}/{prefix}.batch_NNNNNN.xml` and a
+/// `{prefix}.batches_complete` marker per element type.
+pub struct DirectorySink {
+    dir: String,
+    file_prefix: String,
+}
+
+impl DirectorySink {
+    pub fn new(dir: &str, file_prefix: &str) -> Self {
+        DirectorySink {
+            dir: dir.to_string(),
+            file_prefix: file_prefix.to_string(),
+        }
+    }
+}
+
+impl BatchSink for DirectorySink {
+    async fn write_batch(&self, batch: &Batch) -> Result<()> {
+        let type_dir = format!("{}/{}", self.dir, batch.element_type);
+        fs::create_dir_all(&type_dir).await?;
+
+        let batch_path = format!(
+            "{}/{}.batch_{:06}.xml",
+            type_dir, self.file_prefix, batch.batch_number
+        );
+        let temp_path = format!("{}.temp", batch_path);
+        fs::write(&temp_path, &batch.content).await?;
+        fs::rename(&temp_path, &batch_path).await?;
+        Ok(())
+    }
+
+    async fn finish(&self, element_type: ElementType, batch_count: usize) -> Result<()> {
+        let type_dir = format!("{}/{}", self.dir, element_type);
+        fs::create_dir_all(&type_dir).await?;
+
+        let completion_file = format!("{}/{}.batches_complete", type_dir, self.file_prefix);
+        let completion_message =
+            format!("wrote {} batches from {}\n", batch_count, self.file_prefix);
+        fs::write(&completion_file, completion_message).await?;
+        Ok(())
+    }
+}
+
+/// Keeps every batch in memory, for callers that post-process them directly.
+#[derive(Default)]
+pub struct MemorySink {
+    batches: Mutex<Vec<Batch>>,
+}
+
+impl MemorySink {
+    pub fn into_batches(self) -> Vec<Batch> {
+        self.batches.into_inner().unwrap()
+    }
+}
+
+impl BatchSink for MemorySink {
+    async fn write_batch(&self, batch: &Batch) -> Result<()> {
+        self.batches.lock().unwrap().push(batch.clone());
+        Ok(())
+    }
+}
+
+/// Batches OSM data read from `source` into `sink`. Parsing runs on a blocking thread.
+pub async fn batch_reader<R, S>(
+    source: R,
+    format: InputFormat,
+    options: &BatchOptions,
+    sink: &S,
+    cancel: &CancellationToken,
+) -> Result<BatchSummary>
+where
+    R: BufRead + Send + 'static,
+    S: BatchSink,
+{
+    batch_source(Box::new(source), format, options, sink, cancel).await
+}
+
+/// Like `batch_reader`, for async sources such as network streams.
+pub async fn batch_async_reader<R, S>(
+    source: R,
+    format: InputFormat,
+    options: &BatchOptions,
+    sink: &S,
+    cancel: &CancellationToken,
+) -> Result<BatchSummary>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    S: BatchSink,
+{
+    let source = BufReader::new(tokio_util::io::SyncIoBridge::new(source));
+    batch_source(Box::new(source), format, options, sink, cancel).await
+}
+
+async fn batch_source<S: BatchSink>(
+    source: Source,
+    format: InputFormat,
+    options: &BatchOptions,
+    sink: &S,
+    cancel: &CancellationToken,
+) -> Result<BatchSummary> {
+    // A batch size of 0 would cut an empty batch before every element.
+    if options.elements_per_batch == 0 {
+        anyhow::bail!("elements_per_batch must be at least 1");
+    }
+
+    let history = options.history;
+    let output = options.output;
+    let (osm_reader, mut osmium) = tokio::task::spawn_blocking(move || {
+        let (source, osmium) = match format {
+            InputFormat::Xml => (source, None),
+            InputFormat::GzipXml => (Compression::Gzip.decoder(source)?, None),
+            InputFormat::Bzip2Xml => (Compression::Bzip2.decoder(source)?, None),
+            InputFormat::ZstdXml => (Compression::Zstd.decoder(source)?, None),
+            InputFormat::Pbf => {
+                let (xml, osmium) = OsmiumConversion::start(source, history)?;
+                (xml, Some(osmium))
+            }
+        };
+        let osm_reader = OsmReader::new(source)?.with_output(output);
+        Ok::<_, anyhow::Error>((osm_reader, osmium))
+    })
+    .await??;
+
+    let root_info = options
+        .header
+        .batch_root_element(osm_reader.root(), &options.provenance);
+
+    let (job_sender, mut job_receiver) = tokio::sync::mpsc::channel(4);
+    let type_batches = ElementType::ALL
+        .into_iter()
+        .map(|element_type| (element_type, TypeBatches::default()))
+        .collect();
+    let parser = BatchParser {
+        osm_reader,
+        type_batches,
+        spatial_index_builder: None,
+        quality_report_builder: None,
+        tag_statistics_builder: None,
+        total_elements_processed: 0,
+        import_type: if history { "history" } else { "full" }.to_string(),
+        elements_per_batch: options.elements_per_batch,
+        time_window: options.time_window.clone(),
+        id_order: IdOrderCheck::new("The input", IdOrder::Any),
+        input_size: None,
+        cancel: cancel.clone(),
+        jobs: job_sender,
+    };
+    let parser = tokio::task::spawn_blocking(move || parser.run());
+
+    while let Some(job) = job_receiver.recv().await {
+        let batch = render_batch(
+            job.element_type,
+            &job.elements,
+            job.batch_number,
+            &root_info,
+        );
+        sink.write_batch(&batch).await?;
+    }
+
+    let ParsedInput {
+        batch_counts,
+        total_elements_processed,
+        ..
+    } = parser.await??;
+
+    if let Some(osmium) = osmium.as_mut() {
+        osmium.finish()?;
+    }
+
+    for element_type in ElementType::ALL {
+        sink.finish(element_type, batch_counts[&element_type])
+            .await?;
+    }
+
+    Ok(BatchSummary {
+        elements: total_elements_processed,
+        batches: batch_counts,
+    })
+}
+
+/// Serializes one batch: the root element, the header elements and one element per line.
+pub(crate) fn render_batch(
+    element_type: ElementType,
+    elements: &[BatchElement],
+    batch_number: usize,
+    root_info: &RootElementInfo,
+) -> Batch {
+    let mut content = String::new();
+    content.push_str("<?xml version='1.0' encoding='UTF-8'?>\n");
+
+    content.push_str(&format!("<{}", root_info.tag));
+    for (key, value) in &root_info.attributes {
+        content.push_str(&format!(" {}=\"{}\"", key, reader::escape_xml(value)));
+    }
+    content.push_str(">\n");
+    for header_element in &root_info.header {
+        content.push_str(header_element);
+        content.push('\n');
+    }
+
+    let mut index_records = Vec::with_capacity(elements.len());
+    for element in elements {
+        index_records.push(IndexRecord {
+            id: element.id,
+            batch_number: batch_number as u32,
+            offset: content.len() as u32,
+            length: element.xml.len() as u32,
+        });
+        content.push_str(&element.xml);
+        content.push('\n');
+    }
+
+    content.push_str(&format!("</{}>\n", root_info.tag));
+
+    Batch {
+        element_type,
+        batch_number,
+        content,
+        index_records,
+    }
+}
+
+/// An osmium process turning PBF on its stdin into XML on its stdout. Killed when
+/// dropped before `finish`.
+struct OsmiumConversion {
+    child: std::process::Child,
+}
+
+impl OsmiumConversion {
+    fn start(mut pbf: Source, history: bool) -> Result<(Source, Self)> {
+        let (input_format, output_format) = if history {
+            ("osh.pbf", "osh")
+        } else {
+            ("osm.pbf", "osm")
+        };
+        let mut child = Command::new("osmium")
+            .args(["cat", "-", "-F", input_format, "-f", output_format])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("osmium-tool not available: {}", e))?;
+
+        let mut stdin = child.stdin.take().unwrap();
+        std::thread::spawn(move || {
+            // A failed copy shows up as an osmium error or truncated XML.
+            let _ = std::io::copy(&mut pbf, &mut stdin);
+            let _ = stdin.flush();
+        });
+
+        let stdout = child.stdout.take().unwrap();
+        Ok((Box::new(BufReader::new(stdout)), OsmiumConversion { child }))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let status = self.child.wait()?;
+        if !status.success() {
+            let mut stderr = String::new();
+            if let Some(mut pipe) = self.child.stderr.take() {
+                let _ = pipe.read_to_string(&mut stderr);
+            }
+            anyhow::bail!("osmium failed with {}: {}", status, stderr.trim());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OsmiumConversion {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6" generator="test">
+<node id="1" lat="23.1" lon="90.1"/>
+<node id="2" lat="23.2" lon="90.2"/>
+<node id="3" lat="23.3" lon="90.3"/>
+<way id="1"><nd ref="1"/><nd ref="2"/></way>
+</osm>
+"#;
+
+    async fn batch(options: &BatchOptions) -> Result<(BatchSummary, Vec<Batch>)> {
+        let sink = MemorySink::default();
+        let summary = batch_reader(
+            INPUT.as_bytes(),
+            InputFormat::Xml,
+            options,
+            &sink,
+            &CancellationToken::new(),
+        )
+        .await?;
+        Ok((summary, sink.into_batches()))
+    }
+
+    #[tokio::test]
+    async fn cuts_batches_per_element_type() {
+        let options = BatchOptions {
+            elements_per_batch: 2,
+            ..Default::default()
+        };
+        let (summary, batches) = batch(&options).await.unwrap();
+        assert_eq!(summary.elements, 4);
+        assert_eq!(summary.batches[&ElementType::Node], 2);
+        assert_eq!(summary.batches[&ElementType::Way], 1);
+        assert_eq!(summary.batches[&ElementType::Relation], 0);
+
+        let numbers: Vec<_> = batches
+            .iter()
+            .map(|batch| {
+                (
+                    batch.element_type,
+                    batch.batch_number,
+                    batch.index_records.len(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            numbers,
+            [
+                (ElementType::Node, 0, 2),
+                (ElementType::Node, 1, 1),
+                (ElementType::Way, 0, 1)
+            ]
+        );
+        let record = batches[0].index_records[1];
+        let start = record.offset as usize;
+        assert_eq!(
+            &batches[0].content[start..start + record.length as usize],
+            r#"<node id="2" lat="23.2" lon="90.2"/>"#
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_empty_batches() {
+        let options = BatchOptions {
+            elements_per_batch: 0,
+            ..Default::default()
+        };
+        let error = batch(&options).await.unwrap_err();
+        assert!(error.to_string().contains("elements_per_batch"));
+    }
+}
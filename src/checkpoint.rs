@@ -0,0 +1,269 @@
+use anyhow::Result;
+use tokio::fs;
+
+use crate::reader::ReaderPosition;
+use crate::{BatchElement, ElementType};
+
+/// Progress of one element type at the time of a checkpoint.
+pub struct TypeCheckpoint {
+    pub element_type: ElementType,
+    pub batch_count: usize,
+    pub index_records: u64,
+    pub last_batched_id: Option<i64>,
+    /// Elements read but not yet written to a batch.
+    pub pending: Vec<BatchElement>,
+}
+
+/// Everything `batch_osm_xml` needs to continue an interrupted run after the last
+/// batch it wrote. Batches written after the checkpoint are simply written again.
+pub struct BatchCheckpoint {
+    pub input_size: u64,
+    pub position: ReaderPosition,
+    pub elements_processed: usize,
+    pub spatial_journal_len: u64,
+    pub quality_journal_len: u64,
+    /// `None` when tag statistics were not gathered.
+    pub tag_journal_len: Option<u64>,
+    pub types: Vec<TypeCheckpoint>,
+}
+
+// A few `key value` lines followed by the pending elements of each type, each as an
+// `id length` line and the raw element xml, which may itself contain newlines.
+impl BatchCheckpoint {
+    pub async fn write(&self, checkpoint_path: &str) -> Result<()> {
+        let mut content = Vec::new();
+        content.extend_from_slice(
+            format!(
+                "input_size {}\noffset {}\naction {}\nelements_processed {}\nspatial_journal {}\nquality_journal {}\ntag_journal {}\n",
+                self.input_size,
+                self.position.offset,
+                self.position.action.as_deref().unwrap_or("-"),
+                self.elements_processed,
+                self.spatial_journal_len,
+                self.quality_journal_len,
+                self.tag_journal_len
+                    .map(|len| len.to_string())
+                    .unwrap_or_else(|| "-".to_string())
+            )
+            .as_bytes(),
+        );
+
+        for progress in &self.types {
+            let last_batched_id = progress
+                .last_batched_id
+                .map(|id| id.to_string())
+                .unwrap_or_else(|| "-".to_string());
+            content.extend_from_slice(
+                format!(
+                    "type {} {} {} {} {}\n",
+                    progress.element_type,
+                    progress.batch_count,
+                    progress.index_records,
+                    last_batched_id,
+                    progress.pending.len()
+                )
+                .as_bytes(),
+            );
+            for element in &progress.pending {
+                content.extend_from_slice(
+                    format!("{} {}\n", element.id, element.xml.len()).as_bytes(),
+                );
+                content.extend_from_slice(element.xml.as_bytes());
+                content.push(b'\n');
+            }
+        }
+
+        let temp_path = format!("{}.temp", checkpoint_path);
+        fs::write(&temp_path, content).await?;
+        fs::rename(&temp_path, checkpoint_path).await?;
+        Ok(())
+    }
+
+    pub async fn read(checkpoint_path: &str) -> Result<Option<Self>> {
+        let content = match fs::read(checkpoint_path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut parser = Parser { rest: &content };
+        let input_size = parser.value("input_size")?.parse()?;
+        let offset = parser.value("offset")?.parse()?;
+        let action = match parser.value("action")? {
+            "-" => None,
+            action => Some(action.to_string()),
+        };
+        let elements_processed = parser.value("elements_processed")?.parse()?;
+        let spatial_journal_len = parser.value("spatial_journal")?.parse()?;
+        let quality_journal_len = parser.value("quality_journal")?.parse()?;
+        let tag_journal_len = match parser.value("tag_journal")? {
+            "-" => None,
+            len => Some(len.parse()?),
+        };
+
+        let mut types = Vec::new();
+        while !parser.rest.is_empty() {
+            let fields: Vec<&str> = parser.value("type")?.split(' ').collect();
+            let [element_type, batch_count, index_records, last_batched_id, pending_count] =
+                fields[..]
+            else {
+                anyhow::bail!("Invalid checkpoint type line");
+            };
+
+            let mut pending = Vec::new();
+            for _ in 0..pending_count.parse::<usize>()? {
+                let (id, length) = parser
+                    .line()?
+                    .split_once(' ')
+                    .ok_or_else(|| anyhow::anyhow!("Invalid checkpoint element line"))?;
+                let (id, length): (i64, usize) = (id.parse()?, length.parse()?);
+                let xml = String::from_utf8(parser.take(length)?.to_vec())?;
+                parser.line()?;
+                pending.push(BatchElement { id, xml });
+            }
+
+            types.push(TypeCheckpoint {
+                element_type: element_type.parse().map_err(anyhow::Error::msg)?,
+                batch_count: batch_count.parse()?,
+                index_records: index_records.parse()?,
+                last_batched_id: match last_batched_id {
+                    "-" => None,
+                    id => Some(id.parse()?),
+                },
+                pending,
+            });
+        }
+
+        Ok(Some(BatchCheckpoint {
+            input_size,
+            position: ReaderPosition { offset, action },
+            elements_processed,
+            spatial_journal_len,
+            quality_journal_len,
+            tag_journal_len,
+            types,
+        }))
+    }
+}
+
+struct Parser<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Parser<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        let (taken, rest) = self
+            .rest
+            .split_at_checked(length)
+            .ok_or_else(|| anyhow::anyhow!("Truncated checkpoint"))?;
+        self.rest = rest;
+        Ok(taken)
+    }
+
+    fn line(&mut self) -> Result<&'a str> {
+        let length = self
+            .rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| anyhow::anyhow!("Truncated checkpoint"))?;
+        let line = self.take(length)?;
+        self.take(1)?;
+        Ok(std::str::from_utf8(line)?)
+    }
+
+    fn value(&mut self, key: &str) -> Result<&'a str> {
+        let line = self.line()?;
+        line.strip_prefix(key)
+            .and_then(|value| value.strip_prefix(' '))
+            .ok_or_else(|| anyhow::anyhow!("Expected {} in checkpoint, found {:?}", key, line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(action: Option<&str>, tag_journal_len: Option<u64>) -> BatchCheckpoint {
+        BatchCheckpoint {
+            input_size: 123_456,
+            position: ReaderPosition {
+                offset: 4_096,
+                action: action.map(str::to_string),
+            },
+            elements_processed: 700,
+            spatial_journal_len: 9_000,
+            quality_journal_len: 0,
+            tag_journal_len,
+            types: vec![
+                TypeCheckpoint {
+                    element_type: ElementType::Node,
+                    batch_count: 3,
+                    index_records: 300,
+                    last_batched_id: Some(-5),
+                    pending: vec![
+                        BatchElement {
+                            id: 7,
+                            xml: "<node id=\"7\">\n  <tag k=\"a\" v=\"b\"/>\n</node>".to_string(),
+                        },
+                        BatchElement {
+                            id: 8,
+                            xml: "<node id=\"8\" k=\"é\"/>".to_string(),
+                        },
+                    ],
+                },
+                TypeCheckpoint {
+                    element_type: ElementType::Relation,
+                    batch_count: 0,
+                    index_records: 0,
+                    last_batched_id: None,
+                    pending: Vec::new(),
+                },
+            ],
+        }
+    }
+
+    #[tokio::test]
+    async fn checkpoints_round_trip() {
+        let dir = std::env::temp_dir().join(format!("checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("input.osm.checkpoint");
+        let path = path.to_str().unwrap();
+
+        assert!(BatchCheckpoint::read(path).await.unwrap().is_none());
+        for (action, tag_journal_len) in [(None, None), (Some("modify"), Some(42))] {
+            checkpoint(action, tag_journal_len)
+                .write(path)
+                .await
+                .unwrap();
+            let read = BatchCheckpoint::read(path).await.unwrap().unwrap();
+            assert_eq!(read.input_size, 123_456);
+            assert_eq!(read.position.offset, 4_096);
+            assert_eq!(read.position.action.as_deref(), action);
+            assert_eq!(read.elements_processed, 700);
+            assert_eq!(read.spatial_journal_len, 9_000);
+            assert_eq!(read.quality_journal_len, 0);
+            assert_eq!(read.tag_journal_len, tag_journal_len);
+
+            let expected = checkpoint(action, tag_journal_len);
+            assert_eq!(read.types.len(), expected.types.len());
+            for (read, expected) in read.types.iter().zip(&expected.types) {
+                assert_eq!(read.element_type, expected.element_type);
+                assert_eq!(read.batch_count, expected.batch_count);
+                assert_eq!(read.index_records, expected.index_records);
+                assert_eq!(read.last_batched_id, expected.last_batched_id);
+                let pending = |progress: &TypeCheckpoint| {
+                    progress
+                        .pending
+                        .iter()
+                        .map(|element| (element.id, element.xml.clone()))
+                        .collect::<Vec<_>>()
+                };
+                assert_eq!(pending(read), pending(expected));
+            }
+        }
+
+        std::fs::write(path, "input_size 1\noffset").unwrap();
+        assert!(BatchCheckpoint::read(path).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
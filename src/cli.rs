@@ -0,0 +1,368 @@
+use osm_import_rust::{DeltaAbc, FullDate, ImportOptions, InputFormat, OsmFileType, TimeWindow};
+
+pub const USAGE: &str = "\
+Usage: osm-import-rust [COMMAND]
+
+Commands:
+  serve                                Run the gRPC server (default)
+  import <IMPORT>                      Download and batch one import, then exit
+  batch --input <FILE> --out <DIR>     Batch a local OSM file into <DIR>
+        [--format xml|gz|bz2|zst|pbf] [--elements-per-batch <N>] [--history]
+  status                               List imports under ./data
+  clean [<IMPORT> | --intermediates]   Delete one import, only its intermediate files,
+                                       or apply the RETENTION_* policy when no option is given
+  help                                 Show this message
+
+<IMPORT> is one of:
+  --full <YYYY-MM-DD|latest>           latest is the newest extract published upstream;
+                                       import only, clean needs a date
+  --delta <AAA/BBB/CCC>
+  --history <YYYY-MM-DD> [--since <TIMESTAMP>] [--until <TIMESTAMP>]
+  --diff <FROM_YYYY-MM-DD> <TO_YYYY-MM-DD>
+  --source <PATH|URL>                  An .osm, .osm.pbf or .osc file, optionally .gz, .bz2 or .zst
+                                       compressed, named by its content hash
+";
+
+pub enum Command {
+    Serve,
+    Import(ImportTarget),
+    Batch(BatchArgs),
+    Status,
+    Clean(CleanTarget),
+    Help,
+}
+
+pub struct BatchArgs {
+    pub input: String,
+    pub out: String,
+    pub format: InputFormat,
+    pub elements_per_batch: usize,
+    pub history: bool,
+}
+
+/// A custom source is only hashed, and `latest` only resolved, once the command runs.
+pub enum ImportTarget {
+    Options(ImportOptions),
+    LatestFull,
+    Source(String),
+}
+
+pub enum CleanTarget {
+    Import(ImportTarget),
+    Intermediates,
+    RetentionPolicy,
+}
+
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut args = Args(args.into_iter().collect::<Vec<_>>().into_iter());
+
+    let command = match args.next().as_deref() {
+        None | Some("serve") => Command::Serve,
+        Some("help" | "--help" | "-h") => Command::Help,
+        Some("import") => Command::Import(
+            parse_import(&mut args)?
+                .ok_or("import needs --full, --delta, --history, --diff or --source")?,
+        ),
+        Some("batch") => Command::Batch(parse_batch(&mut args)?),
+        Some("status") => Command::Status,
+        Some("clean") => Command::Clean(parse_clean(&mut args)?),
+        Some(other) => return Err(format!("Unknown command {:?}", other)),
+    };
+
+    match args.next() {
+        Some(unexpected) => Err(format!("Unexpected argument {:?}", unexpected)),
+        None => Ok(command),
+    }
+}
+
+struct Args(std::vec::IntoIter<String>);
+
+impl Args {
+    fn next(&mut self) -> Option<String> {
+        self.0.next()
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.0.as_slice().first().map(String::as_str)
+    }
+
+    fn value(&mut self, flag: &str) -> Result<String, String> {
+        self.0
+            .next()
+            .filter(|value| !value.starts_with("--"))
+            .ok_or_else(|| format!("{} needs a value", flag))
+    }
+}
+
+fn parse_import(args: &mut Args) -> Result<Option<ImportTarget>, String> {
+    let osm_file_type = match args.peek() {
+        Some("--full") => {
+            args.next();
+            let date = args.value("--full")?;
+            if date == "latest" {
+                return Ok(Some(ImportTarget::LatestFull));
+            }
+            OsmFileType::Full(FullDate::new(date)?)
+        }
+        Some("--delta") => {
+            args.next();
+            OsmFileType::Delta(DeltaAbc::new(args.value("--delta")?)?)
+        }
+        Some("--history") => {
+            args.next();
+            let date = FullDate::new(args.value("--history")?)?;
+            let (mut since, mut until) = (None, None);
+            while let Some(flag @ ("--since" | "--until")) = args.peek() {
+                let flag = flag.to_string();
+                args.next();
+                let value = Some(args.value(&flag)?);
+                if flag == "--since" {
+                    since = value;
+                } else {
+                    until = value;
+                }
+            }
+            OsmFileType::History(date, TimeWindow::new(since, until)?)
+        }
+        Some("--diff") => {
+            args.next();
+            let from_date = FullDate::new(args.value("--diff")?)?;
+            let to_date = FullDate::new(args.value("--diff")?)?;
+            if from_date.as_str() == to_date.as_str() {
+                return Err("diff dates must differ".to_string());
+            }
+            OsmFileType::Diff(from_date, to_date)
+        }
+        Some("--source") => {
+            args.next();
+            return Ok(Some(ImportTarget::Source(args.value("--source")?)));
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(ImportTarget::Options(ImportOptions {
+        osm_file_type,
+        base_path: "./data/".to_string(),
+    })))
+}
+
+fn parse_batch(args: &mut Args) -> Result<BatchArgs, String> {
+    let (mut input, mut out, mut format) = (None, None, None);
+    let mut elements_per_batch = 500;
+    let mut history = false;
+
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--input" => input = Some(args.value(&flag)?),
+            "--out" => out = Some(args.value(&flag)?),
+            "--format" => {
+                format = Some(match args.value(&flag)?.as_str() {
+                    "xml" => InputFormat::Xml,
+                    "gz" => InputFormat::GzipXml,
+                    "bz2" => InputFormat::Bzip2Xml,
+                    "zst" => InputFormat::ZstdXml,
+                    "pbf" => InputFormat::Pbf,
+                    other => {
+                        return Err(format!(
+                            "--format must be xml, gz, bz2, zst or pbf, not {:?}",
+                            other
+                        ))
+                    }
+                })
+            }
+            "--elements-per-batch" => {
+                elements_per_batch = args
+                    .value(&flag)?
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or("--elements-per-batch must be a positive integer")?
+            }
+            "--history" => history = true,
+            other => return Err(format!("Unknown batch option {:?}", other)),
+        }
+    }
+
+    let input = input.ok_or("batch needs --input")?;
+    let out = out.ok_or("batch needs --out")?;
+    let format = match format {
+        Some(format) => format,
+        None => guess_format(&input)?,
+    };
+
+    Ok(BatchArgs {
+        input,
+        out,
+        format,
+        elements_per_batch,
+        history,
+    })
+}
+
+fn guess_format(input: &str) -> Result<InputFormat, String> {
+    if input.ends_with(".pbf") {
+        Ok(InputFormat::Pbf)
+    } else if input.ends_with(".gz") {
+        Ok(InputFormat::GzipXml)
+    } else if input.ends_with(".bz2") {
+        Ok(InputFormat::Bzip2Xml)
+    } else if input.ends_with(".zst") {
+        Ok(InputFormat::ZstdXml)
+    } else if [".osm", ".osc", ".osh", ".xml"]
+        .iter()
+        .any(|extension| input.ends_with(extension))
+    {
+        Ok(InputFormat::Xml)
+    } else {
+        Err(format!(
+            "Cannot tell the format of {} from its name; pass --format",
+            input
+        ))
+    }
+}
+
+fn parse_clean(args: &mut Args) -> Result<CleanTarget, String> {
+    if args.peek() == Some("--intermediates") {
+        args.next();
+        return Ok(CleanTarget::Intermediates);
+    }
+    Ok(match parse_import(args)? {
+        // Deleting addresses an existing import, never whatever upstream published last.
+        Some(ImportTarget::LatestFull) => {
+            return Err("clean needs the date of a full import, not latest".to_string())
+        }
+        Some(import_target) => CleanTarget::Import(import_target),
+        None => CleanTarget::RetentionPolicy,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Command, String> {
+        parse_args(args.split_whitespace().map(str::to_string))
+    }
+
+    fn import_handle(args: &str) -> String {
+        match parse(args) {
+            Ok(Command::Import(ImportTarget::Options(options))) => options.get_import_handle(),
+            _ => panic!("{} is not an import", args),
+        }
+    }
+
+    fn error(args: &str) -> String {
+        match parse(args) {
+            Err(e) => e,
+            Ok(_) => panic!("{} parsed", args),
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert!(matches!(parse(""), Ok(Command::Serve)));
+        assert!(matches!(parse("serve"), Ok(Command::Serve)));
+        assert!(matches!(parse("status"), Ok(Command::Status)));
+        for help in ["help", "--help", "-h"] {
+            assert!(matches!(parse(help), Ok(Command::Help)));
+        }
+        assert!(error("upload").contains("Unknown command"));
+        assert!(error("status --all").contains("Unexpected argument"));
+    }
+
+    #[test]
+    fn parses_imports() {
+        assert_eq!(import_handle("import --full 2025-09-01"), "full/250901");
+        assert_eq!(
+            import_handle("import --delta 006/123/456"),
+            "delta/006_123_456"
+        );
+        assert_eq!(
+            import_handle("import --history 2025-09-01"),
+            "history/250901"
+        );
+        assert_ne!(
+            import_handle("import --history 2025-09-01 --since 2025-01-01T00:00:00Z"),
+            import_handle("import --history 2025-09-01 --until 2025-01-01T00:00:00Z")
+        );
+        assert_eq!(
+            import_handle("import --diff 2025-09-01 2025-09-02"),
+            "diff/250901_250902"
+        );
+        assert!(matches!(
+            parse("import --full latest"),
+            Ok(Command::Import(ImportTarget::LatestFull))
+        ));
+        assert!(matches!(
+            parse("import --source https://example.org/a.osm"),
+            Ok(Command::Import(ImportTarget::Source(source))) if source == "https://example.org/a.osm"
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_imports() {
+        assert!(error("import").contains("import needs"));
+        assert!(error("import --full").contains("--full needs a value"));
+        assert!(error("import --full --delta 006/123/456").contains("--full needs a value"));
+        error("import --full 01-09-2025");
+        assert!(error("import --delta 6/123/456").contains("AAA/BBB/CCC"));
+        assert!(error("import --diff 2025-09-01").contains("--diff needs a value"));
+        assert!(error("import --diff 2025-09-01 2025-09-01").contains("must differ"));
+        assert!(error("import --history 2025-09-01 --since").contains("--since needs a value"));
+        assert!(error("import --full 2025-09-01 --delta 006/123/456").contains("Unexpected"));
+    }
+
+    #[test]
+    fn parses_batch_options() {
+        let Ok(Command::Batch(args)) = parse("batch --input extract.osm.bz2 --out batches") else {
+            panic!("batch did not parse");
+        };
+        assert_eq!(args.input, "extract.osm.bz2");
+        assert_eq!(args.out, "batches");
+        assert_eq!(args.format, InputFormat::Bzip2Xml);
+        assert_eq!(args.elements_per_batch, 500);
+        assert!(!args.history);
+
+        let Ok(Command::Batch(args)) = parse(
+            "batch --history --input extract --format pbf --out batches --elements-per-batch 20",
+        ) else {
+            panic!("batch did not parse");
+        };
+        assert_eq!(args.format, InputFormat::Pbf);
+        assert_eq!(args.elements_per_batch, 20);
+        assert!(args.history);
+    }
+
+    #[test]
+    fn rejects_bad_batch_options() {
+        assert!(error("batch --out batches").contains("needs --input"));
+        assert!(error("batch --input a.osm").contains("needs --out"));
+        assert!(error("batch --input extract --out batches").contains("pass --format"));
+        assert!(error("batch --input a.osm --out b --format json").contains("--format must be"));
+        for count in ["0", "-1", "many"] {
+            let args = format!("batch --input a.osm --out b --elements-per-batch {}", count);
+            assert!(error(&args).contains("positive integer"), "{}", count);
+        }
+        assert!(error("batch --input a.osm --out b --verbose").contains("Unknown batch option"));
+    }
+
+    #[test]
+    fn parses_clean_targets() {
+        assert!(matches!(
+            parse("clean"),
+            Ok(Command::Clean(CleanTarget::RetentionPolicy))
+        ));
+        assert!(matches!(
+            parse("clean --intermediates"),
+            Ok(Command::Clean(CleanTarget::Intermediates))
+        ));
+        assert!(matches!(
+            parse("clean --full 2025-09-01"),
+            Ok(Command::Clean(CleanTarget::Import(ImportTarget::Options(
+                _
+            ))))
+        ));
+        assert!(error("clean --full latest").contains("not latest"));
+        assert!(error("clean --everything").contains("Unexpected argument"));
+    }
+}
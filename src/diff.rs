@@ -0,0 +1,283 @@
+use anyhow::Result;
+use std::io::{BufReader, BufWriter, Write};
+use tokio_util::sync::CancellationToken;
+
+use crate::jobs::check_cancelled;
+use crate::reader::{OsmElement, OsmReader};
+use crate::ElementType;
+
+#[derive(Debug, Default)]
+pub struct DiffSummary {
+    pub created: usize,
+    pub modified: usize,
+    pub deleted: usize,
+}
+
+fn sort_key(element: &OsmElement) -> (ElementType, i64) {
+    (element.element_type, element.id)
+}
+
+struct SortedElements {
+    reader: OsmReader<BufReader<std::fs::File>>,
+    input_file: String,
+    last_key: Option<(ElementType, i64)>,
+}
+
+impl SortedElements {
+    fn open(input_file: &str) -> Result<Self> {
+        let input = std::fs::File::open(input_file)?;
+        Ok(SortedElements {
+            reader: OsmReader::new(BufReader::new(input))?,
+            input_file: input_file.to_string(),
+            last_key: None,
+        })
+    }
+
+    // A merge join is only correct on sorted input, which osmium always produces.
+    fn next(&mut self) -> Result<Option<OsmElement>> {
+        let element = self.reader.next_element()?;
+        if let Some(element) = &element {
+            let key = sort_key(element);
+            if self.last_key.is_some_and(|last_key| key <= last_key) {
+                anyhow::bail!(
+                    "{} is not sorted by type and id at {} {}",
+                    self.input_file,
+                    element.element_type,
+                    element.id
+                );
+            }
+            self.last_key = Some(key);
+        }
+        Ok(element)
+    }
+}
+
+struct ChangeWriter {
+    writer: BufWriter<std::fs::File>,
+    open_action: Option<&'static str>,
+}
+
+impl ChangeWriter {
+    fn write(&mut self, action: &'static str, element: &OsmElement) -> Result<()> {
+        if self.open_action != Some(action) {
+            if let Some(open_action) = self.open_action {
+                writeln!(self.writer, "</{}>", open_action)?;
+            }
+            writeln!(self.writer, "<{}>", action)?;
+            self.open_action = Some(action);
+        }
+        writeln!(self.writer, "{}", element.xml)?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if let Some(open_action) = self.open_action {
+            writeln!(self.writer, "</{}>", open_action)?;
+        }
+        writeln!(self.writer, "</osmChange>")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Merge-joins two sorted full extracts and writes an osmChange file that turns
+/// `old_file` into `new_file`. Elements whose version changed are `modify`, elements
+/// only in the new file are `create` and elements only in the old file are `delete`.
+pub fn write_osm_change(
+    old_file: &str,
+    new_file: &str,
+    output_file: &str,
+    generator: &str,
+    cancel: &CancellationToken,
+) -> Result<DiffSummary> {
+    let temp_file = format!("{}.temp", output_file);
+    match merge(old_file, new_file, &temp_file, generator, cancel) {
+        Ok(summary) => {
+            std::fs::rename(&temp_file, output_file)?;
+            Ok(summary)
+        }
+        Err(e) => {
+            // Unsorted input or cancellation leaves nothing half-written behind.
+            let _ = std::fs::remove_file(&temp_file);
+            Err(e)
+        }
+    }
+}
+
+fn merge(
+    old_file: &str,
+    new_file: &str,
+    temp_file: &str,
+    generator: &str,
+    cancel: &CancellationToken,
+) -> Result<DiffSummary> {
+    let mut old_elements = SortedElements::open(old_file)?;
+    let mut new_elements = SortedElements::open(new_file)?;
+
+    let mut writer = BufWriter::new(std::fs::File::create(temp_file)?);
+    writeln!(writer, "<?xml version='1.0' encoding='UTF-8'?>")?;
+    writeln!(
+        writer,
+        "<osmChange version=\"0.6\" generator=\"{}\">",
+        generator.replace("&", "&amp;").replace("\"", "&quot;")
+    )?;
+    let mut changes = ChangeWriter {
+        writer,
+        open_action: None,
+    };
+
+    let mut summary = DiffSummary::default();
+    let mut old_element = old_elements.next()?;
+    let mut new_element = new_elements.next()?;
+
+    loop {
+        check_cancelled(cancel)?;
+
+        match (&old_element, &new_element) {
+            (None, None) => break,
+            (Some(old), Some(new)) if sort_key(old) == sort_key(new) => {
+                let modified = match (old.version, new.version) {
+                    (Some(old_version), Some(new_version)) => old_version != new_version,
+                    _ => old.xml != new.xml,
+                };
+                if modified {
+                    changes.write("modify", new)?;
+                    summary.modified += 1;
+                }
+                old_element = old_elements.next()?;
+                new_element = new_elements.next()?;
+            }
+            (Some(old), Some(new)) if sort_key(old) < sort_key(new) => {
+                changes.write("delete", old)?;
+                summary.deleted += 1;
+                old_element = old_elements.next()?;
+            }
+            (Some(old), None) => {
+                changes.write("delete", old)?;
+                summary.deleted += 1;
+                old_element = old_elements.next()?;
+            }
+            (_, Some(new)) => {
+                changes.write("create", new)?;
+                summary.created += 1;
+                new_element = new_elements.next()?;
+            }
+        }
+    }
+
+    changes.finish()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn write_extract(path: &str, elements: &[(&str, i64, u32)]) {
+        let mut xml =
+            String::from("<?xml version='1.0' encoding='UTF-8'?>\n<osm version=\"0.6\">\n");
+        for (tag, id, version) in elements {
+            xml.push_str(&format!(
+                "<{} id=\"{}\" version=\"{}\"/>\n",
+                tag, id, version
+            ));
+        }
+        xml.push_str("</osm>\n");
+        std::fs::write(path, xml).unwrap();
+    }
+
+    #[test]
+    fn merges_sorted_extracts() {
+        let dir = std::env::temp_dir().join(format!("diff-sorted-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        write_extract(
+            &path("old.osm"),
+            &[("node", 1, 1), ("node", 2, 1), ("way", 1, 1), ("way", 5, 2)],
+        );
+        write_extract(
+            &path("new.osm"),
+            &[
+                ("node", 1, 2),
+                ("node", 3, 1),
+                ("way", 1, 1),
+                ("relation", 1, 1),
+            ],
+        );
+
+        let summary = write_osm_change(
+            &path("old.osm"),
+            &path("new.osm"),
+            &path("change.osc"),
+            "test \"diff\"",
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            (summary.created, summary.modified, summary.deleted),
+            (2, 1, 2)
+        );
+
+        let change = std::fs::read_to_string(path("change.osc")).unwrap();
+        let body: Vec<&str> = change.lines().skip(2).collect();
+        assert_eq!(
+            body,
+            [
+                "<modify>",
+                "<node id=\"1\" version=\"2\"/>",
+                "</modify>",
+                "<delete>",
+                "<node id=\"2\" version=\"1\"/>",
+                "</delete>",
+                "<create>",
+                "<node id=\"3\" version=\"1\"/>",
+                "</create>",
+                "<delete>",
+                "<way id=\"5\" version=\"2\"/>",
+                "</delete>",
+                "<create>",
+                "<relation id=\"1\" version=\"1\"/>",
+                "</create>",
+                "</osmChange>",
+            ]
+        );
+        assert!(change.contains("generator=\"test &quot;diff&quot;\""));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_unsorted_extracts() {
+        let dir = std::env::temp_dir().join(format!("diff-unsorted-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        write_extract(&path("old.osm"), &[("node", 1, 1), ("way", 1, 1)]);
+        write_extract(&path("new.osm"), &[("way", 1, 1), ("node", 1, 1)]);
+
+        let error = write_osm_change(
+            &path("old.osm"),
+            &path("new.osm"),
+            &path("change.osc"),
+            "test",
+            &CancellationToken::new(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("is not sorted by type and id"));
+        assert!(!Path::new(&path("change.osc")).exists());
+        assert!(!Path::new(&path("change.osc.temp")).exists());
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let error = write_osm_change(
+            &path("old.osm"),
+            &path("old.osm"),
+            &path("change.osc"),
+            "test",
+            &cancel,
+        )
+        .unwrap_err();
+        assert!(error.is::<crate::jobs::ImportCancelled>());
+        assert!(!Path::new(&path("change.osc.temp")).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
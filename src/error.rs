@@ -0,0 +1,212 @@
+use anyhow::Result;
+use std::path::Path;
+use tokio::fs;
+
+use crate::validation::InvalidInput;
+
+/// Written into an import directory when its last attempt failed for any reason but
+/// invalid input, so clients see why until the next attempt starts.
+const MARKER_FILE: &str = "import_error";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request names something that cannot be imported or looked up.
+    InvalidArgument,
+    /// The upstream server has no such file.
+    UpstreamNotFound,
+    /// The upstream server could not be reached or answered with an error.
+    UpstreamUnavailable,
+    /// osmium-tool could not convert a PBF file.
+    ConversionFailed,
+    /// The input could not be read as OSM data.
+    ParseFailed,
+    /// Reading or writing the storage backend failed.
+    StorageError,
+    ImportFailed,
+    /// A download does not match the checksum its provider published.
+    ChecksumMismatch,
+}
+
+const ERROR_CODES: [ErrorCode; 8] = [
+    ErrorCode::InvalidArgument,
+    ErrorCode::UpstreamNotFound,
+    ErrorCode::UpstreamUnavailable,
+    ErrorCode::ConversionFailed,
+    ErrorCode::ParseFailed,
+    ErrorCode::StorageError,
+    ErrorCode::ImportFailed,
+    ErrorCode::ChecksumMismatch,
+];
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidArgument => "invalid_argument",
+            ErrorCode::UpstreamNotFound => "upstream_not_found",
+            ErrorCode::UpstreamUnavailable => "upstream_unavailable",
+            ErrorCode::ConversionFailed => "conversion_failed",
+            ErrorCode::ParseFailed => "parse_failed",
+            ErrorCode::StorageError => "storage_error",
+            ErrorCode::ImportFailed => "import_failed",
+            ErrorCode::ChecksumMismatch => "checksum_mismatch",
+        }
+    }
+
+    fn from_str(code: &str) -> Option<Self> {
+        ERROR_CODES
+            .into_iter()
+            .find(|candidate| candidate.as_str() == code)
+    }
+}
+
+/// A failure clients can branch on, with details such as the URL or HTTP status that
+/// caused it. Returned inside `anyhow::Error` like `InvalidInput`.
+#[derive(Debug, Clone)]
+pub struct ServiceError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Vec<(String, String)>,
+}
+
+impl ServiceError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServiceError {
+            code,
+            message: message.into(),
+            details: Vec::new(),
+        }
+    }
+
+    pub fn invalid_argument(message: impl Into<String>) -> Self {
+        ServiceError::new(ErrorCode::InvalidArgument, message)
+    }
+
+    pub fn storage(message: impl Into<String>) -> Self {
+        ServiceError::new(ErrorCode::StorageError, message)
+    }
+
+    pub fn with_detail(mut self, key: &str, value: impl ToString) -> Self {
+        self.details.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// What made an import fail: the first `ServiceError` in the chain, invalid input
+    /// and XML errors as `ParseFailed`, anything else as `ImportFailed`.
+    pub fn from_import_error(e: &anyhow::Error) -> Self {
+        for cause in e.chain() {
+            if let Some(error) = cause.downcast_ref::<ServiceError>() {
+                return error.clone();
+            }
+            if let Some(invalid) = cause.downcast_ref::<InvalidInput>() {
+                return ServiceError::new(ErrorCode::ParseFailed, invalid.message.clone())
+                    .with_detail("input_error", invalid.code.as_str());
+            }
+            if cause.is::<quick_xml::Error>() {
+                return ServiceError::new(ErrorCode::ParseFailed, format!("{:#}", e));
+            }
+        }
+        ServiceError::new(ErrorCode::ImportFailed, format!("{:#}", e))
+    }
+}
+
+impl std::fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+pub(crate) async fn record(import_dir: &str, error: &ServiceError) -> Result<()> {
+    let mut content = format!(
+        "{}\n{}\n",
+        error.code.as_str(),
+        error.message.replace('\n', " ")
+    );
+    for (key, value) in &error.details {
+        content.push_str(&format!("{}\t{}\n", key, value.replace('\n', " ")));
+    }
+    fs::write(format!("{}/{}", import_dir, MARKER_FILE), content).await?;
+    Ok(())
+}
+
+pub(crate) async fn clear(import_dir: &str) -> Result<()> {
+    match fs::remove_file(format!("{}/{}", import_dir, MARKER_FILE)).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Why the last attempt of the import in `import_dir` failed, if it did.
+pub(crate) async fn recorded(import_dir: &str) -> Option<ServiceError> {
+    let content = fs::read_to_string(Path::new(import_dir).join(MARKER_FILE))
+        .await
+        .ok()?;
+    let mut lines = content.lines();
+    let mut error = ServiceError::new(ErrorCode::from_str(lines.next()?)?, lines.next()?);
+    for line in lines {
+        let (key, value) = line.split_once('\t')?;
+        error = error.with_detail(key, value);
+    }
+    Some(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::InputErrorCode;
+
+    #[test]
+    fn error_codes_round_trip_through_their_names() {
+        for code in ERROR_CODES {
+            assert_eq!(ErrorCode::from_str(code.as_str()), Some(code));
+        }
+        assert_eq!(ErrorCode::from_str("unknown"), None);
+    }
+
+    #[test]
+    fn classifies_import_errors() {
+        let upstream = ServiceError::new(ErrorCode::UpstreamNotFound, "gone");
+        let e = anyhow::Error::from(upstream).context("Failed to download");
+        assert_eq!(
+            ServiceError::from_import_error(&e).code,
+            ErrorCode::UpstreamNotFound
+        );
+
+        let invalid =
+            crate::validation::invalid_input(InputErrorCode::EmptyFile, "empty".to_string());
+        let error = ServiceError::from_import_error(&invalid);
+        assert_eq!(error.code, ErrorCode::ParseFailed);
+        assert_eq!(
+            error.details,
+            [("input_error".to_string(), "empty_file".to_string())]
+        );
+
+        let e = anyhow::anyhow!("disk full");
+        assert_eq!(
+            ServiceError::from_import_error(&e).code,
+            ErrorCode::ImportFailed
+        );
+    }
+
+    #[tokio::test]
+    async fn records_errors_in_the_import_directory() {
+        let dir = std::env::temp_dir().join(format!("error-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let import_dir = dir.to_str().unwrap();
+
+        let error = ServiceError::new(ErrorCode::ChecksumMismatch, "checksum\nmismatch")
+            .with_detail("url", "https://example.org/a.osm.pbf");
+        record(import_dir, &error).await.unwrap();
+        let read = recorded(import_dir).await.unwrap();
+        assert_eq!(read.code, ErrorCode::ChecksumMismatch);
+        assert_eq!(read.message, "checksum mismatch");
+        assert_eq!(read.details, error.details);
+
+        clear(import_dir).await.unwrap();
+        assert!(recorded(import_dir).await.is_none());
+        clear(import_dir).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
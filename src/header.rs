@@ -0,0 +1,245 @@
+use crate::reader::RootElementInfo;
+
+const DEFAULT_GENERATOR: &str = "Chaldal osm-import-rust";
+
+/// The extra root attributes a batch can carry, in the order they are written.
+const HEADER_ATTRIBUTES: [&str; 5] = [
+    "import_instance",
+    "import_version",
+    "import_id",
+    "import_source",
+    "replication_timestamp",
+];
+
+/// Where the batches of one import came from.
+#[derive(Debug, Clone, Default)]
+pub struct BatchProvenance {
+    pub import_id: String,
+    pub source_url: Option<String>,
+    pub replication_timestamp: Option<String>,
+}
+
+impl BatchProvenance {
+    pub fn new(import_id: &str) -> Self {
+        BatchProvenance {
+            import_id: import_id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_source(mut self, source_url: Option<String>) -> Self {
+        self.source_url = source_url;
+        self
+    }
+
+    pub fn with_replication_timestamp(mut self, replication_timestamp: Option<String>) -> Self {
+        self.replication_timestamp = replication_timestamp;
+        self
+    }
+}
+
+/// What the service writes into the root element of every batch.
+#[derive(Debug, Clone)]
+pub struct BatchHeaderConfig {
+    pub generator: String,
+    pub instance_name: Option<String>,
+    pub attributes: Vec<&'static str>,
+}
+
+impl Default for BatchHeaderConfig {
+    fn default() -> Self {
+        BatchHeaderConfig {
+            generator: DEFAULT_GENERATOR.to_string(),
+            instance_name: None,
+            attributes: HEADER_ATTRIBUTES.to_vec(),
+        }
+    }
+}
+
+impl BatchHeaderConfig {
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Like `from_env`, with the variables looked up through `var`.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let generator = var("BATCH_GENERATOR")
+            .filter(|generator| !generator.is_empty())
+            .unwrap_or_else(|| DEFAULT_GENERATOR.to_string());
+        let instance_name = var("SERVICE_INSTANCE_NAME").filter(|name| !name.is_empty());
+
+        let attributes = match var("BATCH_HEADER_ATTRIBUTES") {
+            None => HEADER_ATTRIBUTES.to_vec(),
+            Some(list) => {
+                let mut attributes = Vec::new();
+                for name in list
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                {
+                    let Some(&attribute) = HEADER_ATTRIBUTES.iter().find(|&&a| a == name) else {
+                        return Err(format!(
+                            "Unknown BATCH_HEADER_ATTRIBUTES entry {:?} (expected any of {})",
+                            name,
+                            HEADER_ATTRIBUTES.join(", ")
+                        ));
+                    };
+                    attributes.push(attribute);
+                }
+                attributes
+            }
+        };
+
+        Ok(BatchHeaderConfig {
+            generator,
+            instance_name,
+            attributes,
+        })
+    }
+
+    /// The source root with our generator in front of the source's own and the
+    /// provenance attributes appended. Attributes without a value are left out.
+    pub(crate) fn batch_root_element(
+        &self,
+        source_root: &RootElementInfo,
+        provenance: &BatchProvenance,
+    ) -> RootElementInfo {
+        let mut root_info = source_root.clone();
+
+        let generator = match source_root.attribute("generator") {
+            Some(source_generator) if !source_generator.is_empty() => {
+                format!("{}; {}", self.generator, source_generator)
+            }
+            _ => self.generator.clone(),
+        };
+        root_info.set_attribute("generator", generator);
+
+        for &attribute in &self.attributes {
+            let value = match attribute {
+                "import_instance" => self.instance_name.clone(),
+                "import_version" => Some(env!("CARGO_PKG_VERSION").to_string()),
+                "import_id" => Some(provenance.import_id.clone()),
+                "import_source" => provenance.source_url.clone(),
+                "replication_timestamp" => provenance.replication_timestamp.clone(),
+                _ => None,
+            };
+            if let Some(value) = value {
+                root_info.set_attribute(attribute, value);
+            }
+        }
+
+        root_info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(vars: &[(&str, &str)]) -> Result<BatchHeaderConfig, String> {
+        BatchHeaderConfig::from_vars(|name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    fn root(attributes: &[(&str, &str)]) -> RootElementInfo {
+        RootElementInfo {
+            tag: "osm".to_string(),
+            attributes: attributes
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            header: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn reads_the_configuration() {
+        let defaults = config(&[]).unwrap();
+        assert_eq!(defaults.generator, DEFAULT_GENERATOR);
+        assert_eq!(defaults.instance_name, None);
+        assert_eq!(defaults.attributes, HEADER_ATTRIBUTES);
+
+        let configured = config(&[
+            ("BATCH_GENERATOR", "Mapper"),
+            ("SERVICE_INSTANCE_NAME", "replica-1"),
+            ("BATCH_HEADER_ATTRIBUTES", " import_id, ,import_source "),
+        ])
+        .unwrap();
+        assert_eq!(configured.generator, "Mapper");
+        assert_eq!(configured.instance_name.as_deref(), Some("replica-1"));
+        assert_eq!(configured.attributes, ["import_id", "import_source"]);
+
+        let empty = config(&[("BATCH_GENERATOR", ""), ("BATCH_HEADER_ATTRIBUTES", "")]).unwrap();
+        assert_eq!(empty.generator, DEFAULT_GENERATOR);
+        assert!(empty.attributes.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_attributes() {
+        let e = config(&[("BATCH_HEADER_ATTRIBUTES", "import_id,import_date")]).unwrap_err();
+        assert!(e.contains("\"import_date\""), "{}", e);
+    }
+
+    #[test]
+    fn prepends_the_generator() {
+        let config = BatchHeaderConfig::default();
+        let provenance = BatchProvenance::new("full/250901");
+
+        let root_info = config.batch_root_element(
+            &root(&[("version", "0.6"), ("generator", "osmium/1.16.0")]),
+            &provenance,
+        );
+        assert_eq!(
+            root_info.attribute("generator"),
+            Some("Chaldal osm-import-rust; osmium/1.16.0")
+        );
+        // The source's attributes keep their place.
+        assert_eq!(root_info.attributes[0].0, "version");
+
+        for source in [root(&[]), root(&[("generator", "")])] {
+            let root_info = config.batch_root_element(&source, &provenance);
+            assert_eq!(root_info.attribute("generator"), Some(DEFAULT_GENERATOR));
+        }
+    }
+
+    #[test]
+    fn leaves_out_attributes_without_a_value() {
+        let config = BatchHeaderConfig::default();
+        let root_info = config.batch_root_element(&root(&[]), &BatchProvenance::new("full/250901"));
+        assert_eq!(root_info.attribute("import_id"), Some("full/250901"));
+        assert_eq!(
+            root_info.attribute("import_version"),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+        for attribute in ["import_instance", "import_source", "replication_timestamp"] {
+            assert_eq!(root_info.attribute(attribute), None, "{}", attribute);
+        }
+
+        let config = BatchHeaderConfig {
+            instance_name: Some("replica-1".to_string()),
+            attributes: vec!["import_instance", "import_source", "replication_timestamp"],
+            ..BatchHeaderConfig::default()
+        };
+        let provenance = BatchProvenance::new("full/250901")
+            .with_source(Some("https://example.org/a.osm.pbf".to_string()))
+            .with_replication_timestamp(Some("2025-09-01T20:21:02Z".to_string()));
+        let root_info = config.batch_root_element(&root(&[]), &provenance);
+        let names: Vec<&str> = root_info
+            .attributes
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "generator",
+                "import_instance",
+                "import_source",
+                "replication_timestamp"
+            ]
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use anyhow::Result;
+use std::io::SeekFrom;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
+
+// id (i64) + batch number (u32) + byte offset (u32) + byte length (u32), little endian.
+const RECORD_SIZE: u64 = 20;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IndexRecord {
+    pub id: i64,
+    pub batch_number: u32,
+    pub offset: u32,
+    pub length: u32,
+}
+
+impl IndexRecord {
+    fn to_bytes(self) -> [u8; RECORD_SIZE as usize] {
+        let mut bytes = [0u8; RECORD_SIZE as usize];
+        bytes[0..8].copy_from_slice(&self.id.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.batch_number.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.length.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        IndexRecord {
+            id: i64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            batch_number: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            offset: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            length: u32::from_le_bytes(bytes[16..20].try_into().unwrap()),
+        }
+    }
+}
+
+/// Streams index records to disk while batches are written. OSM files are sorted by id
+/// per element type, so the records normally arrive in order; anything else (hand-made
+/// deltas, merged files) is sorted once in `finish`.
+pub struct IndexBuilder {
+    index_path: String,
+    ranges_path: String,
+    writer: BufWriter<fs::File>,
+    ranges: String,
+    last_id: Option<i64>,
+    is_sorted: bool,
+    records_written: u64,
+}
+
+// Not a `.temp` file: it survives a cancelled import so batching can resume.
+fn partial_path(index_path: &str) -> String {
+    format!("{}.partial", index_path)
+}
+
+impl IndexBuilder {
+    pub async fn create(index_path: &str, ranges_path: &str) -> Result<Self> {
+        let file = fs::File::create(partial_path(index_path)).await?;
+        Ok(IndexBuilder {
+            index_path: index_path.to_string(),
+            ranges_path: ranges_path.to_string(),
+            writer: BufWriter::new(file),
+            ranges: String::new(),
+            last_id: None,
+            is_sorted: true,
+            records_written: 0,
+        })
+    }
+
+    /// Reopens the partial index of an interrupted run, dropping every record written
+    /// after the checkpoint that reported `records`.
+    pub async fn resume(index_path: &str, ranges_path: &str, records: u64) -> Result<Self> {
+        let partial_path = partial_path(index_path);
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&partial_path)
+            .await?;
+        if file.metadata().await?.len() < records * RECORD_SIZE {
+            anyhow::bail!("{} is shorter than its checkpoint", partial_path);
+        }
+        file.set_len(records * RECORD_SIZE).await?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).await?;
+
+        let mut builder = IndexBuilder {
+            index_path: index_path.to_string(),
+            ranges_path: ranges_path.to_string(),
+            writer: BufWriter::new(file),
+            ranges: String::new(),
+            last_id: None,
+            is_sorted: true,
+            records_written: 0,
+        };
+        let records: Vec<IndexRecord> = bytes
+            .chunks_exact(RECORD_SIZE as usize)
+            .map(IndexRecord::from_bytes)
+            .collect();
+        for batch in records.chunk_by(|a, b| a.batch_number == b.batch_number) {
+            builder.track_batch(batch[0].batch_number as usize, batch);
+        }
+        Ok(builder)
+    }
+
+    pub async fn add_batch(&mut self, batch_number: usize, records: &[IndexRecord]) -> Result<()> {
+        self.track_batch(batch_number, records);
+        for record in records {
+            self.writer.write_all(&record.to_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    fn track_batch(&mut self, batch_number: usize, records: &[IndexRecord]) {
+        let min_id = records.iter().map(|r| r.id).min();
+        let max_id = records.iter().map(|r| r.id).max();
+        if let (Some(min_id), Some(max_id)) = (min_id, max_id) {
+            self.ranges
+                .push_str(&format!("{} {} {}\n", batch_number, min_id, max_id));
+        }
+
+        for record in records {
+            if self.last_id.is_some_and(|last_id| record.id < last_id) {
+                self.is_sorted = false;
+            }
+            self.last_id = Some(record.id);
+        }
+        self.records_written += records.len() as u64;
+    }
+
+    /// Flushes the records written so far and returns how many there are.
+    pub async fn checkpoint(&mut self) -> Result<u64> {
+        self.writer.flush().await?;
+        Ok(self.records_written)
+    }
+
+    pub async fn finish(mut self) -> Result<()> {
+        self.writer.flush().await?;
+        drop(self.writer);
+
+        let partial_path = partial_path(&self.index_path);
+        if !self.is_sorted {
+            let bytes = fs::read(&partial_path).await?;
+            let mut records: Vec<IndexRecord> = bytes
+                .chunks_exact(RECORD_SIZE as usize)
+                .map(IndexRecord::from_bytes)
+                .collect();
+            records.sort_by_key(|r| (r.id, r.batch_number, r.offset));
+            let sorted: Vec<u8> = records.iter().flat_map(|r| r.to_bytes()).collect();
+            fs::write(&partial_path, sorted).await?;
+        }
+
+        fs::rename(&partial_path, &self.index_path).await?;
+        fs::write(&self.ranges_path, &self.ranges).await?;
+        Ok(())
+    }
+}
+
+/// Binary searches the index file on disk and returns every record for `id`, in file
+/// order. History imports hold one record per version.
+pub async fn find_records(index_path: &str, id: i64) -> Result<Vec<IndexRecord>> {
+    let mut file = fs::File::open(index_path).await?;
+    let record_count = file.metadata().await?.len() / RECORD_SIZE;
+    let mut buffer = [0u8; RECORD_SIZE as usize];
+
+    let mut low = 0;
+    let mut high = record_count;
+    while low < high {
+        let mid = low + (high - low) / 2;
+        file.seek(SeekFrom::Start(mid * RECORD_SIZE)).await?;
+        file.read_exact(&mut buffer).await?;
+        if IndexRecord::from_bytes(&buffer).id < id {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+
+    let mut records = Vec::new();
+    file.seek(SeekFrom::Start(low * RECORD_SIZE)).await?;
+    for _ in low..record_count {
+        file.read_exact(&mut buffer).await?;
+        let record = IndexRecord::from_bytes(&buffer);
+        if record.id != id {
+            break;
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("index-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn record(id: i64, batch_number: u32, offset: u32) -> IndexRecord {
+        IndexRecord {
+            id,
+            batch_number,
+            offset,
+            length: 10,
+        }
+    }
+
+    async fn found(index_path: &str, id: i64) -> Vec<(u32, u32)> {
+        find_records(index_path, id)
+            .await
+            .unwrap()
+            .iter()
+            .map(|record| (record.batch_number, record.offset))
+            .collect()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let record = IndexRecord {
+            id: -42,
+            batch_number: 7,
+            offset: 1234,
+            length: u32::MAX,
+        };
+        let decoded = IndexRecord::from_bytes(&record.to_bytes());
+        assert_eq!(
+            (
+                decoded.id,
+                decoded.batch_number,
+                decoded.offset,
+                decoded.length
+            ),
+            (-42, 7, 1234, u32::MAX)
+        );
+    }
+
+    #[tokio::test]
+    async fn finds_records_in_sorted_and_unsorted_input() {
+        let dir = test_dir("find");
+        for sorted in [true, false] {
+            let index_path = dir.join(format!("{}.index", sorted));
+            let index_path = index_path.to_str().unwrap();
+            let ranges_path = format!("{}.ranges", index_path);
+            let mut builder = IndexBuilder::create(index_path, &ranges_path)
+                .await
+                .unwrap();
+            let batches = if sorted {
+                [
+                    vec![record(1, 0, 0), record(3, 0, 10)],
+                    vec![record(3, 1, 0), record(8, 1, 10)],
+                ]
+            } else {
+                [
+                    vec![record(8, 0, 0), record(3, 0, 10)],
+                    vec![record(1, 1, 0), record(3, 1, 10)],
+                ]
+            };
+            for (batch_number, records) in batches.iter().enumerate() {
+                builder.add_batch(batch_number, records).await.unwrap();
+            }
+            builder.finish().await.unwrap();
+
+            assert_eq!(found(index_path, 3).await.len(), 2);
+            assert_eq!(found(index_path, 1).await.len(), 1);
+            assert_eq!(found(index_path, 8).await.len(), 1);
+            assert!(found(index_path, 2).await.is_empty());
+            assert!(found(index_path, 9).await.is_empty());
+            let ranges = std::fs::read_to_string(&ranges_path).unwrap();
+            let expected = if sorted {
+                "0 1 3\n1 3 8\n"
+            } else {
+                "0 3 8\n1 1 3\n"
+            };
+            assert_eq!(ranges, expected);
+            assert!(!Path::new(&partial_path(index_path)).exists());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_drops_records_after_the_checkpoint() {
+        let dir = test_dir("resume");
+        let index_path = dir.join("input.osm.index");
+        let index_path = index_path.to_str().unwrap();
+        let ranges_path = format!("{}.ranges", index_path);
+
+        let mut builder = IndexBuilder::create(index_path, &ranges_path)
+            .await
+            .unwrap();
+        builder
+            .add_batch(0, &[record(1, 0, 0), record(2, 0, 10)])
+            .await
+            .unwrap();
+        assert_eq!(builder.checkpoint().await.unwrap(), 2);
+        builder.add_batch(1, &[record(5, 1, 0)]).await.unwrap();
+        builder.checkpoint().await.unwrap();
+        drop(builder);
+
+        assert!(IndexBuilder::resume(index_path, &ranges_path, 4)
+            .await
+            .is_err());
+        let mut builder = IndexBuilder::resume(index_path, &ranges_path, 2)
+            .await
+            .unwrap();
+        builder.add_batch(1, &[record(4, 1, 0)]).await.unwrap();
+        builder.finish().await.unwrap();
+
+        assert_eq!(found(index_path, 2).await, [(0, 10)]);
+        assert_eq!(found(index_path, 4).await, [(1, 0)]);
+        assert!(found(index_path, 5).await.is_empty());
+        assert_eq!(
+            std::fs::read_to_string(&ranges_path).unwrap(),
+            "0 1 2\n1 4 4\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use crate::error::{self, ServiceError};
+use crate::validation::{self, InvalidInput};
+use crate::{process_osm_import, ImportOptions, OsmFileType, DATA_DIR};
+
+const DEFAULT_MAX_CONCURRENT_IMPORTS: usize = 2;
+
+/// How many imports may download, convert or batch at the same time.
+static MAX_CONCURRENT_IMPORTS: LazyLock<usize> = LazyLock::new(|| {
+    std::env::var("MAX_CONCURRENT_IMPORTS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|&limit| limit > 0)
+        .unwrap_or(DEFAULT_MAX_CONCURRENT_IMPORTS)
+});
+
+/// Imports started by this process, running or waiting for a slot.
+static JOB_QUEUE: LazyLock<Mutex<JobQueue>> = LazyLock::new(|| Mutex::new(JobQueue::default()));
+
+/// Signalled whenever a queued import finishes, for imports waiting on another one.
+static IMPORT_FINISHED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// How often an import waiting on one locked by another process checks again.
+const LOCKED_PREREQUISITE_POLL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct ImportCancelled;
+
+impl std::fmt::Display for ImportCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Import cancelled")
+    }
+}
+
+impl std::error::Error for ImportCancelled {}
+
+pub fn check_cancelled(cancel: &CancellationToken) -> anyhow::Result<()> {
+    if cancel.is_cancelled() {
+        return Err(ImportCancelled.into());
+    }
+    Ok(())
+}
+
+/// Who is waiting for an import. Interactive imports always run before backfill.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ImportPriority {
+    Interactive,
+    Backfill,
+}
+
+#[derive(Debug)]
+pub enum StartImportStatus {
+    ImportStarted(String),
+    ImportAlreadyRunning(String),
+}
+
+#[derive(Debug)]
+pub enum CancelImportStatus {
+    CancelRequested,
+    ImportNotRunning,
+}
+
+#[derive(Debug)]
+pub enum ImportQueueStatus {
+    ImportRunning,
+    /// 1-based position among the imports waiting for a slot.
+    ImportQueued(usize),
+    ImportNotQueued,
+    /// Not running because its input failed validation.
+    ImportInvalid(InvalidInput),
+    /// Not running because its last attempt failed otherwise.
+    ImportFailed(ServiceError),
+}
+
+struct QueuedImport {
+    handle: String,
+    import_options: ImportOptions,
+    priority: ImportPriority,
+    sequence: u64,
+    cancel: CancellationToken,
+    done: Option<oneshot::Sender<anyhow::Result<()>>>,
+}
+
+impl QueuedImport {
+    // Deltas are applied on top of full extracts, so within one priority the
+    // extract-sized imports go first; otherwise first come, first served.
+    fn rank(&self) -> (ImportPriority, u8, u64) {
+        let kind = match self.import_options.osm_file_type {
+            OsmFileType::Delta(_) => 1,
+            _ => 0,
+        };
+        (self.priority, kind, self.sequence)
+    }
+}
+
+#[derive(Default)]
+struct JobQueue {
+    running: HashMap<String, CancellationToken>,
+    queued: Vec<QueuedImport>,
+    next_sequence: u64,
+    /// Running imports blocked on a prerequisite, which do not hold a slot meanwhile.
+    waiting: usize,
+}
+
+impl JobQueue {
+    fn enqueue(
+        &mut self,
+        import_options: ImportOptions,
+        priority: ImportPriority,
+        done: Option<oneshot::Sender<anyhow::Result<()>>>,
+    ) -> StartImportStatus {
+        let status = self.add(import_options, priority, done);
+        if let StartImportStatus::ImportStarted(_) = status {
+            self.dispatch();
+        }
+        status
+    }
+
+    /// Queues an import without starting anything; see [`JobQueue::enqueue`].
+    fn add(
+        &mut self,
+        import_options: ImportOptions,
+        priority: ImportPriority,
+        done: Option<oneshot::Sender<anyhow::Result<()>>>,
+    ) -> StartImportStatus {
+        let handle = import_options.get_import_handle();
+
+        if let Some(queued) = self.queued.iter_mut().find(|job| job.handle == handle) {
+            // A client now waiting on a backfill import moves it up the queue.
+            queued.priority = queued.priority.min(priority);
+            return StartImportStatus::ImportAlreadyRunning(handle);
+        }
+
+        // The lock file also covers imports run by another process sharing `./data`.
+        if self.running.contains_key(&handle) || Path::new(&import_options.get_lock_file()).exists()
+        {
+            return StartImportStatus::ImportAlreadyRunning(handle);
+        }
+
+        self.queued.push(QueuedImport {
+            handle: handle.clone(),
+            import_options,
+            priority,
+            sequence: self.next_sequence,
+            cancel: CancellationToken::new(),
+            done,
+        });
+        self.next_sequence += 1;
+
+        StartImportStatus::ImportStarted(handle)
+    }
+
+    fn dispatch(&mut self) {
+        for job in self.take_next(*MAX_CONCURRENT_IMPORTS) {
+            tokio::spawn(run_job(job));
+        }
+
+        if !self.queued.is_empty() {
+            info!(
+                "⏳ {} imports running, {} waiting for a slot",
+                self.running.len(),
+                self.queued.len()
+            );
+        }
+    }
+
+    /// Marks the best-ranked queued imports as running, as many as there are free slots
+    /// out of `limit`, and returns them to be run.
+    fn take_next(&mut self, limit: usize) -> Vec<QueuedImport> {
+        let mut started = Vec::new();
+        while self.running.len().saturating_sub(self.waiting) < limit {
+            let Some(next) = self
+                .queued
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, job)| job.rank())
+                .map(|(index, _)| index)
+            else {
+                break;
+            };
+
+            let job = self.queued.remove(next);
+            self.running.insert(job.handle.clone(), job.cancel.clone());
+            started.push(job);
+        }
+        started
+    }
+
+    fn cancel(&mut self, handle: &str) -> CancelImportStatus {
+        if let Some(cancel) = self.running.get(handle) {
+            cancel.cancel();
+            return CancelImportStatus::CancelRequested;
+        }
+
+        match self.queued.iter().position(|job| job.handle == handle) {
+            Some(index) => {
+                let job = self.queued.remove(index);
+                info!("🛑 Import {} removed from the queue", handle);
+                if let Some(done) = job.done {
+                    let _ = done.send(Err(ImportCancelled.into()));
+                }
+                CancelImportStatus::CancelRequested
+            }
+            None => CancelImportStatus::ImportNotRunning,
+        }
+    }
+
+    fn position(&self, handle: &str) -> Option<usize> {
+        let job = self.queued.iter().find(|job| job.handle == handle)?;
+        Some(
+            self.queued
+                .iter()
+                .filter(|other| other.rank() < job.rank())
+                .count()
+                + 1,
+        )
+    }
+}
+
+async fn run_job(job: QueuedImport) {
+    info!("🎯 Background task started for {}", job.handle);
+    let result = process_osm_import(&job.import_options, &job.cancel).await;
+
+    {
+        let mut queue = JOB_QUEUE.lock().unwrap();
+        queue.running.remove(&job.handle);
+        queue.dispatch();
+    }
+    IMPORT_FINISHED.notify_waiters();
+
+    match &result {
+        Ok(()) => info!("🎉 Background processing completed successfully"),
+        Err(e) if e.is::<ImportCancelled>() => info!("🛑 Import {} cancelled", job.handle),
+        Err(e) => error!("💥 Background processing failed: {e}"),
+    }
+
+    if let Some(done) = job.done {
+        let _ = done.send(result);
+    }
+}
+
+/// Queues an import and returns as soon as it is accepted.
+pub fn start_import(import_options: ImportOptions, priority: ImportPriority) -> StartImportStatus {
+    JOB_QUEUE
+        .lock()
+        .unwrap()
+        .enqueue(import_options, priority, None)
+}
+
+/// Queues an import as backfill and waits for it to finish. Returns `false` when
+/// the import is already queued or running.
+pub async fn run_import(import_options: &ImportOptions) -> anyhow::Result<bool> {
+    let (done, finished) = oneshot::channel();
+    let status = JOB_QUEUE.lock().unwrap().enqueue(
+        import_options.clone(),
+        ImportPriority::Backfill,
+        Some(done),
+    );
+    if let StartImportStatus::ImportAlreadyRunning(_) = status {
+        return Ok(false);
+    }
+
+    finished.await??;
+    Ok(true)
+}
+
+/// Runs an import another import needs first, such as the full extracts of a diff,
+/// through the queue and waits for it. If it is already queued or running, it is
+/// waited for and then run again, which returns at once when it completed. The
+/// waiting import gives up its slot meanwhile, so it can never starve its own
+/// prerequisite. Cancelling `cancel` stops the wait, not the prerequisite.
+pub(crate) async fn run_prerequisite(
+    import_options: &ImportOptions,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    JOB_QUEUE.lock().unwrap().waiting += 1;
+    let result = wait_for_prerequisite(import_options, cancel).await;
+    JOB_QUEUE.lock().unwrap().waiting -= 1;
+    result
+}
+
+async fn wait_for_prerequisite(
+    import_options: &ImportOptions,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    loop {
+        // Registered before the queue is checked, so a finish in between is not missed.
+        let finished = IMPORT_FINISHED.notified();
+        tokio::pin!(finished);
+        finished.as_mut().enable();
+
+        let (done, result) = oneshot::channel();
+        let status = {
+            let mut queue = JOB_QUEUE.lock().unwrap();
+            // The slot given up by the waiting import may be the one it needs.
+            queue.dispatch();
+            queue.enqueue(
+                import_options.clone(),
+                ImportPriority::Interactive,
+                Some(done),
+            )
+        };
+
+        match status {
+            StartImportStatus::ImportStarted(_) => {
+                return tokio::select! {
+                    result = result => result?,
+                    _ = cancel.cancelled() => Err(ImportCancelled.into()),
+                };
+            }
+            // Another process holding the lock never signals, so it is polled.
+            StartImportStatus::ImportAlreadyRunning(handle) => {
+                info!("⏳ Waiting for import {} to finish", handle);
+                tokio::select! {
+                    _ = finished => {}
+                    _ = tokio::time::sleep(LOCKED_PREREQUISITE_POLL) => {}
+                    _ = cancel.cancelled() => return Err(ImportCancelled.into()),
+                }
+            }
+        }
+    }
+}
+
+pub fn cancel_import(handle: &str) -> CancelImportStatus {
+    JOB_QUEUE.lock().unwrap().cancel(handle)
+}
+
+pub async fn import_status(handle: &str) -> ImportQueueStatus {
+    {
+        let queue = JOB_QUEUE.lock().unwrap();
+        if queue.running.contains_key(handle) {
+            return ImportQueueStatus::ImportRunning;
+        }
+        if let Some(position) = queue.position(handle) {
+            return ImportQueueStatus::ImportQueued(position);
+        }
+    }
+
+    // Handles are `{type}/{scope}`, which is also where the import lives under `./data`.
+    let is_handle = handle.split_once('/').is_some_and(|(import_type, scope)| {
+        [import_type, scope].iter().all(|part| {
+            !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+    });
+    if !is_handle {
+        return ImportQueueStatus::ImportNotQueued;
+    }
+    let import_dir = format!("{}/{}", DATA_DIR, handle);
+    if let Some(invalid) = validation::recorded(&import_dir).await {
+        return ImportQueueStatus::ImportInvalid(invalid);
+    }
+    match error::recorded(&import_dir).await {
+        Some(error) => ImportQueueStatus::ImportFailed(error),
+        None => ImportQueueStatus::ImportNotQueued,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeltaAbc, FullDate};
+
+    fn full(date: &str) -> ImportOptions {
+        ImportOptions {
+            osm_file_type: OsmFileType::Full(FullDate::new(date.to_string()).unwrap()),
+            base_path: std::env::temp_dir()
+                .join(format!("jobs-{}", std::process::id()))
+                .to_str()
+                .unwrap()
+                .to_string(),
+        }
+    }
+
+    fn delta(abc: &str) -> ImportOptions {
+        ImportOptions {
+            osm_file_type: OsmFileType::Delta(DeltaAbc::new(abc.to_string()).unwrap()),
+            ..full("2025-09-01")
+        }
+    }
+
+    fn handles(jobs: &[QueuedImport]) -> Vec<&str> {
+        jobs.iter().map(|job| job.handle.as_str()).collect()
+    }
+
+    #[test]
+    fn runs_at_most_the_limit() {
+        let mut queue = JobQueue::default();
+        for date in ["2025-09-01", "2025-09-02", "2025-09-03"] {
+            queue.add(full(date), ImportPriority::Interactive, None);
+        }
+
+        let started = queue.take_next(2);
+        assert_eq!(handles(&started), ["full/250901", "full/250902"]);
+        assert!(queue.take_next(2).is_empty());
+        assert!(matches!(
+            queue.add(full("2025-09-01"), ImportPriority::Interactive, None),
+            StartImportStatus::ImportAlreadyRunning(_)
+        ));
+
+        queue.running.remove("full/250901");
+        assert_eq!(handles(&queue.take_next(2)), ["full/250903"]);
+    }
+
+    #[test]
+    fn waiting_imports_give_up_their_slot() {
+        let mut queue = JobQueue::default();
+        queue.add(full("2025-09-01"), ImportPriority::Interactive, None);
+        assert_eq!(queue.take_next(1).len(), 1);
+
+        queue.add(full("2025-09-02"), ImportPriority::Interactive, None);
+        assert!(queue.take_next(1).is_empty());
+        queue.waiting += 1;
+        assert_eq!(handles(&queue.take_next(1)), ["full/250902"]);
+    }
+
+    #[test]
+    fn orders_by_priority_then_full_before_delta() {
+        let mut queue = JobQueue::default();
+        queue.add(delta("006/000/001"), ImportPriority::Interactive, None);
+        queue.add(full("2025-09-01"), ImportPriority::Backfill, None);
+        queue.add(full("2025-09-02"), ImportPriority::Interactive, None);
+        queue.add(delta("006/000/000"), ImportPriority::Interactive, None);
+
+        assert_eq!(queue.position("full/250902"), Some(1));
+        assert_eq!(queue.position("delta/006_000_001"), Some(2));
+        assert_eq!(queue.position("delta/006_000_000"), Some(3));
+        assert_eq!(queue.position("full/250901"), Some(4));
+
+        // A client waiting on the backfill import moves it up.
+        assert!(matches!(
+            queue.add(full("2025-09-01"), ImportPriority::Interactive, None),
+            StartImportStatus::ImportAlreadyRunning(_)
+        ));
+        assert_eq!(
+            handles(&queue.take_next(5)),
+            [
+                "full/250901",
+                "full/250902",
+                "delta/006_000_001",
+                "delta/006_000_000"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn cancels_running_and_queued_imports() {
+        let mut queue = JobQueue::default();
+        queue.add(full("2025-09-01"), ImportPriority::Interactive, None);
+        let (done, finished) = oneshot::channel();
+        queue.add(full("2025-09-02"), ImportPriority::Interactive, Some(done));
+        let running = queue.take_next(1).remove(0);
+
+        assert!(matches!(
+            queue.cancel("full/250901"),
+            CancelImportStatus::CancelRequested
+        ));
+        assert!(running.cancel.is_cancelled());
+
+        assert!(matches!(
+            queue.cancel("full/250902"),
+            CancelImportStatus::CancelRequested
+        ));
+        assert!(queue.queued.is_empty());
+        assert!(finished.await.unwrap().unwrap_err().is::<ImportCancelled>());
+
+        assert!(matches!(
+            queue.cancel("full/250903"),
+            CancelImportStatus::ImportNotRunning
+        ));
+    }
+}
@@ -1,30 +1,177 @@
 use anyhow::Result;
+use chrono::{Days, Months, NaiveDate, Utc};
 use quick_xml::events::Event;
 use quick_xml::Reader;
 use regex::Regex;
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::LazyLock;
 use tokio::fs;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, warn};
 
+mod batching;
+mod checkpoint;
+mod diff;
+mod error;
+mod header;
+mod index;
+mod jobs;
+mod provider;
+mod quality;
+mod reader;
+mod retention;
+mod scheduler;
+mod source;
+mod spatial;
+mod storage;
+mod tag_stats;
 mod utils;
+mod validation;
 
+/// The date of a Geofabrik extract. Clients give it as an ISO date; Geofabrik names
+/// extracts, and this service names import directories, by its `yymmdd` form.
 #[derive(Debug, Clone)]
-pub struct FullDate(String);
+pub struct FullDate {
+    date: NaiveDate,
+    yymmdd: String,
+}
 
 #[derive(Debug, Clone)]
 pub struct DeltaAbc(String);
 
+#[derive(Debug, Clone, Default)]
+pub struct TimeWindow {
+    since: Option<String>,
+    until: Option<String>,
+}
+
 impl FullDate {
+    /// Parses a `YYYY-MM-DD` date. `latest` is resolved by `resolve_full_date` instead,
+    /// for full imports only.
     pub fn new(date: String) -> Result<Self, String> {
-        let date_regex = Regex::new(r"^[0-9]{6}$").map_err(|_| "Failed to compile date regex")?;
+        if date == "latest" {
+            return Err(
+                "latest is only supported for full imports; history and diff imports need a YYYY-MM-DD date"
+                    .to_string(),
+            );
+        }
+        let date_regex = Regex::new(r"^[0-9]{4}-[0-9]{2}-[0-9]{2}$")
+            .map_err(|_| "Failed to compile date regex")?;
         if !date_regex.is_match(&date) {
-            return Err(format!("Invalid date format: {} (expected ddmmyy)", date));
+            return Err(format!(
+                "Invalid date format: {} (expected YYYY-MM-DD)",
+                date
+            ));
         }
-        Ok(FullDate(date))
+        let date = NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+            .map_err(|_| format!("Invalid date: {} is not a calendar date", date))?;
+        FullDate::from_date(date)
     }
 
+    fn from_date(date: NaiveDate) -> Result<Self, String> {
+        let today = Utc::now().date_naive();
+        if date > today {
+            return Err(format!("Invalid date: {} is in the future", date));
+        }
+        // Extracts are named by a two-digit year, which only tells dates apart within
+        // one century.
+        if today
+            .checked_sub_months(Months::new(100 * 12))
+            .is_some_and(|century_ago| date <= century_ago)
+        {
+            return Err(format!(
+                "Invalid date: {} (extracts are named by a two-digit year, so only the last 100 years are supported)",
+                date
+            ));
+        }
+        Ok(FullDate {
+            date,
+            yymmdd: date.format("%y%m%d").to_string(),
+        })
+    }
+
+    /// The `yymmdd` form used in upstream URLs, import directories and handles.
     pub fn as_str(&self) -> &str {
-        &self.0
+        &self.yymmdd
+    }
+
+    pub fn date(&self) -> NaiveDate {
+        self.date
+    }
+
+    /// Rejects dates the upstream provider no longer serves. Providers without an
+    /// archive only publish extracts inside their `latest` lookback window.
+    pub fn check_published(&self) -> Result<(), ServiceError> {
+        self.check_published_by(provider::provider())
+    }
+
+    fn check_published_by(&self, provider: &dyn Provider) -> Result<(), ServiceError> {
+        if provider.keeps_full_extract_archive() {
+            return Ok(());
+        }
+        let lookback_days = provider.full_extract_lookback_days();
+        let oldest = Utc::now().date_naive() - Days::new(lookback_days);
+        if self.date >= oldest {
+            return Ok(());
+        }
+        let window = match lookback_days {
+            0 => "today's full extract".to_string(),
+            days => format!("full extracts from the last {} days", days),
+        };
+        Err(ServiceError::invalid_argument(format!(
+            "Invalid date: {} ({} only publishes {})",
+            self.date,
+            provider.describe(),
+            window
+        )))
+    }
+}
+
+impl TimeWindow {
+    pub fn new(since: Option<String>, until: Option<String>) -> Result<Self, String> {
+        let timestamp_regex =
+            Regex::new(r"^[0-9]{4}-[0-9]{2}-[0-9]{2}T[0-9]{2}:[0-9]{2}:[0-9]{2}Z$")
+                .map_err(|_| "Failed to compile timestamp regex")?;
+        for timestamp in since.iter().chain(until.iter()) {
+            if !timestamp_regex.is_match(timestamp) {
+                return Err(format!(
+                    "Invalid timestamp format: {} (expected YYYY-MM-DDThh:mm:ssZ)",
+                    timestamp
+                ));
+            }
+        }
+        if let (Some(since), Some(until)) = (&since, &until) {
+            if since > until {
+                return Err(format!("Invalid time window: {} is after {}", since, until));
+            }
+        }
+        Ok(TimeWindow { since, until })
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.since.is_none() && self.until.is_none()
+    }
+
+    // OSM timestamps share one fixed-width UTC format, so string order is time order.
+    pub fn contains(&self, timestamp: &str) -> bool {
+        let after_since = self.since.as_deref().is_none_or(|since| timestamp >= since);
+        let before_until = self.until.as_deref().is_none_or(|until| timestamp <= until);
+        after_since && before_until
+    }
+
+    fn as_scope_suffix(&self) -> String {
+        let compact = |timestamp: &Option<String>, fallback: &str| {
+            timestamp
+                .as_deref()
+                .map(|t| t.replace(['-', ':'], ""))
+                .unwrap_or_else(|| fallback.to_string())
+        };
+        format!(
+            "{}_{}",
+            compact(&self.since, "start"),
+            compact(&self.until, "end")
+        )
     }
 }
 
@@ -41,6 +188,18 @@ impl DeltaAbc {
         Ok(DeltaAbc(abc))
     }
 
+    pub fn from_sequence(sequence: u64) -> Result<Self, String> {
+        if sequence > 999_999_999 {
+            return Err(format!("Sequence number {} is out of range", sequence));
+        }
+        Ok(DeltaAbc(format!(
+            "{:03}/{:03}/{:03}",
+            sequence / 1_000_000,
+            sequence / 1_000 % 1_000,
+            sequence % 1_000
+        )))
+    }
+
     pub fn as_str(&self) -> &str {
         &self.0
     }
@@ -50,27 +209,157 @@ impl DeltaAbc {
     }
 }
 
+/// The three kinds of OSM elements. Batches, indexes and statistics are kept per type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ElementType {
+    Node,
+    Way,
+    Relation,
+}
+
+impl ElementType {
+    /// In the order OSM files list them.
+    pub const ALL: [ElementType; 3] = [ElementType::Node, ElementType::Way, ElementType::Relation];
+
+    /// The XML tag name, which is also the batch directory name.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ElementType::Node => "node",
+            ElementType::Way => "way",
+            ElementType::Relation => "relation",
+        }
+    }
+
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl std::str::FromStr for ElementType {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        ElementType::ALL
+            .into_iter()
+            .find(|element_type| element_type.as_str() == value)
+            .ok_or_else(|| format!("Invalid element type: {:?}", value))
+    }
+}
+
+impl std::fmt::Display for ElementType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 #[derive(Debug)]
 pub enum BatchFileStatus {
     FileReadSuccessfully(String),
-    FileReadError(String),
+    FileReadError(ServiceError),
     FileDoesNotExistYet,
     FileWillNeverExist,
+    /// The import stopped because its input failed validation.
+    InputInvalid(InvalidInput),
+    /// The last attempt failed for another reason; the next request starts a new one.
+    ImportFailed(ServiceError),
 }
 
-#[derive(Debug, Clone)]
-struct RootElementInfo {
-    tag: String,
-    attributes: std::collections::HashMap<String, String>,
+#[derive(Debug)]
+pub enum ElementLookupStatus {
+    ElementFound {
+        batch_number: usize,
+        content: String,
+    },
+    ElementNotFound,
+    IndexNotReady,
+    ElementReadError(ServiceError),
 }
 
+#[derive(Debug)]
+pub enum BboxQueryStatus {
+    ElementsFound {
+        elements: Vec<MatchedElement>,
+        truncated: bool,
+    },
+    IndexNotReady,
+    QueryError(ServiceError),
+}
+
+#[derive(Debug)]
+pub enum QualityReportStatus {
+    ReportFound(QualityReport),
+    ReportNotReady,
+    /// Batched before quality reports were written.
+    ReportNotFound,
+    ReportReadError(ServiceError),
+}
+
+#[derive(Debug)]
+pub enum TagStatisticsStatus {
+    StatisticsFound(TagStatistics),
+    StatisticsNotReady,
+    /// Batched without `TAG_STATISTICS=1`.
+    StatisticsNotGathered,
+    StatisticsReadError(ServiceError),
+}
+
+#[derive(Debug)]
+pub struct MatchedElement {
+    pub element_type: ElementType,
+    pub id: i64,
+    pub batch_number: usize,
+    pub content: String,
+}
+
+pub use batching::{
+    batch_async_reader, batch_reader, Batch, BatchOptions, BatchSink, BatchSummary, DirectorySink,
+    InputFormat, MemorySink,
+};
+pub use error::{ErrorCode, ServiceError};
+pub use header::{BatchHeaderConfig, BatchProvenance};
+pub use index::IndexRecord;
+pub use jobs::{
+    cancel_import, import_status, start_import, CancelImportStatus, ImportCancelled,
+    ImportPriority, ImportQueueStatus, StartImportStatus,
+};
+pub use provider::{provider_from_env, set_provider, Provider};
+pub use quality::{QualityCheck, QualityCheckResult, QualityIssue, QualityReport};
+pub use reader::ElementOutput;
+pub use retention::{
+    apply_retention_policy, delete_import, list_imports, DeleteImportStatus, ImportSummary,
+    RetentionPolicy, RetentionReport,
+};
+pub use scheduler::{prewarm, PrewarmTarget, Schedule};
+pub use source::{
+    prepare_custom_source, resolve_custom_source, CustomSource, CustomSourceStatus, SourceFormat,
+};
+pub use spatial::BoundingBox;
+pub use storage::{set_storage, S3Storage, Storage};
+pub use tag_stats::{TagCount, TagStatistics, TypeCounts};
+pub use utils::Compression;
+pub use validation::{InputErrorCode, InvalidInput};
+
+pub(crate) const DATA_DIR: &str = "./data";
+
+#[derive(Clone)]
+struct BatchElement {
+    id: i64,
+    xml: String,
+}
+
+#[derive(Debug, Clone)]
 pub enum OsmFileType {
     Full(FullDate),
     Delta(DeltaAbc),
+    History(FullDate, TimeWindow),
+    Diff(FullDate, FullDate),
+    Custom(CustomSource),
 }
 
+#[derive(Debug, Clone)]
 pub struct ImportOptions {
     pub osm_file_type: OsmFileType,
+    /// The directory imports live under; `./data/` everywhere but in tests.
     pub base_path: String,
 }
 impl ImportOptions {
@@ -78,17 +367,29 @@ impl ImportOptions {
         match &self.osm_file_type {
             OsmFileType::Full(_) => "full",
             OsmFileType::Delta(_) => "delta",
+            OsmFileType::History(_, _) => "history",
+            OsmFileType::Diff(_, _) => "diff",
+            OsmFileType::Custom(_) => "custom",
         }
     }
     fn get_import_scope(&self) -> String {
         match &self.osm_file_type {
             OsmFileType::Full(date) => date.as_str().to_string(),
             OsmFileType::Delta(abc) => abc.as_underscore(),
+            OsmFileType::History(date, window) if window.is_unbounded() => {
+                date.as_str().to_string()
+            }
+            OsmFileType::History(date, window) => {
+                format!("{}_{}", date.as_str(), window.as_scope_suffix())
+            }
+            OsmFileType::Diff(from, to) => format!("{}_{}", from.as_str(), to.as_str()),
+            OsmFileType::Custom(source) => source.scope().to_string(),
         }
     }
-    fn get_import_dir(&self) -> String {
+    pub(crate) fn get_import_dir(&self) -> String {
         format!(
-            "./data/{}/{}",
+            "{}/{}/{}",
+            self.base_path.trim_end_matches('/'),
             self.get_import_type(),
             self.get_import_scope()
         )
@@ -98,14 +399,45 @@ impl ImportOptions {
         match &self.osm_file_type {
             OsmFileType::Full(_) => format!("{}.osm", self.get_import_scope()),
             OsmFileType::Delta(_) => format!("{}.osc", self.get_import_scope()),
+            // Every window of a date batches the same file.
+            OsmFileType::History(date, _) => format!("{}.osh", date.as_str()),
+            OsmFileType::Diff(_, _) => format!("{}.osc", self.get_import_scope()),
+            OsmFileType::Custom(source) if source.format().is_change() => {
+                format!("{}.osc", self.get_import_scope())
+            }
+            OsmFileType::Custom(_) => format!("{}.osm", self.get_import_scope()),
+        }
+    }
+
+    /// Rejects starting an import of full extracts the upstream provider no longer
+    /// serves. Only checked when an import starts, so finished imports stay addressable
+    /// after their date leaves the provider's window. History files come from
+    /// `HISTORY_PBF_URL_TEMPLATE` rather than the provider.
+    pub fn check_published(&self) -> Result<(), ServiceError> {
+        self.check_published_by(provider::provider())
+    }
+
+    fn check_published_by(&self, provider: &dyn Provider) -> Result<(), ServiceError> {
+        match &self.osm_file_type {
+            OsmFileType::Full(date) => date.check_published_by(provider),
+            OsmFileType::Diff(from_date, to_date) => {
+                from_date.check_published_by(provider)?;
+                to_date.check_published_by(provider)
+            }
+            OsmFileType::Delta(_) | OsmFileType::History(_, _) | OsmFileType::Custom(_) => Ok(()),
         }
     }
 
+    /// Identifies one import across the API, e.g. `full/250901` or `delta/000_000_001`.
+    pub fn get_import_handle(&self) -> String {
+        format!("{}/{}", self.get_import_type(), self.get_import_scope())
+    }
+
     pub fn get_lock_file(&self) -> String {
         format!("{}/lock", self.get_import_dir())
     }
 
-    pub fn get_batch_file(&self, element_type: &str, batch_number: usize) -> String {
+    pub fn get_batch_file(&self, element_type: ElementType, batch_number: usize) -> String {
         format!(
             "{}/batches/{}/{}.batch_{:06}.xml",
             self.get_import_dir(),
@@ -115,7 +447,7 @@ impl ImportOptions {
         )
     }
 
-    pub fn get_batches_complete_file(&self, element_type: &str) -> String {
+    pub fn get_batches_complete_file(&self, element_type: ElementType) -> String {
         format!(
             "{}/batches/{}/{}.batches_complete",
             self.get_import_dir(),
@@ -123,63 +455,481 @@ impl ImportOptions {
             self.get_filename_base(),
         )
     }
+
+    pub fn get_spatial_index_file(&self, element_type: ElementType) -> String {
+        format!(
+            "{}/batches/{}/{}.spatial",
+            self.get_import_dir(),
+            element_type,
+            self.get_filename_base(),
+        )
+    }
+
+    pub fn get_quality_report_file(&self) -> String {
+        format!(
+            "{}/batches/{}.quality",
+            self.get_import_dir(),
+            self.get_filename_base(),
+        )
+    }
+
+    pub fn get_tag_statistics_file(&self, element_type: ElementType) -> String {
+        format!(
+            "{}/batches/{}/{}.tag_stats",
+            self.get_import_dir(),
+            element_type,
+            self.get_filename_base(),
+        )
+    }
+
+    pub fn get_index_file(&self, element_type: ElementType) -> String {
+        format!(
+            "{}/batches/{}/{}.index",
+            self.get_import_dir(),
+            element_type,
+            self.get_filename_base(),
+        )
+    }
 }
 
 pub async fn check_batch_file_status(
     import_options: &ImportOptions,
-    element_type: &str,
+    element_type: ElementType,
     batch_number: usize,
 ) -> BatchFileStatus {
     let batch_file_path = import_options.get_batch_file(element_type, batch_number);
     let batches_complete_file_path = import_options.get_batches_complete_file(element_type);
+    let storage = storage::storage();
+
+    match storage.read(&batch_file_path).await {
+        Ok(Some(content)) => match String::from_utf8(content) {
+            Ok(content) => BatchFileStatus::FileReadSuccessfully(content),
+            Err(_) => {
+                error!("Batch file is not valid UTF-8: {}", batch_file_path);
+                BatchFileStatus::FileReadError(ServiceError::storage("Failed to read batch file"))
+            }
+        },
+        Ok(None) => match storage.exists(&batches_complete_file_path).await {
+            Ok(true) => BatchFileStatus::FileWillNeverExist,
+            Ok(false) => {
+                let import_dir = import_options.get_import_dir();
+                match (
+                    validation::recorded(&import_dir).await,
+                    error::recorded(&import_dir).await,
+                ) {
+                    (Some(invalid), _) => BatchFileStatus::InputInvalid(invalid),
+                    (None, Some(error)) => BatchFileStatus::ImportFailed(error),
+                    (None, None) => BatchFileStatus::FileDoesNotExistYet,
+                }
+            }
+            Err(e) => {
+                error!(
+                    "Failed to check completion marker {}: {}",
+                    batches_complete_file_path, e
+                );
+                BatchFileStatus::FileReadError(ServiceError::storage("Failed to read batch file"))
+            }
+        },
+        Err(e) => {
+            error!("Batch file failed to read: {}: {}", batch_file_path, e);
+            BatchFileStatus::FileReadError(ServiceError::storage("Failed to read batch file"))
+        }
+    }
+}
+
+pub async fn find_element(
+    import_options: &ImportOptions,
+    element_type: ElementType,
+    id: i64,
+) -> ElementLookupStatus {
+    let index_file_path = import_options.get_index_file(element_type);
+    match index_ready(import_options, element_type, &[&index_file_path]).await {
+        Ok(true) => {}
+        Ok(false) => return ElementLookupStatus::IndexNotReady,
+        Err(e) => {
+            error!("Failed to fetch index {}: {}", index_file_path, e);
+            return ElementLookupStatus::ElementReadError(ServiceError::storage(
+                "Failed to read element index",
+            ));
+        }
+    }
 
-    match (
-        Path::new(&batch_file_path).exists(),
-        tokio::fs::read_to_string(&batch_file_path).await,
-    ) {
-        (true, Ok(content)) => BatchFileStatus::FileReadSuccessfully(content),
-        (true, Err(_)) => {
-            error!("Batch file exists but failed to read: {}", batch_file_path);
-            BatchFileStatus::FileReadError("Failed to read batch file".to_string())
+    let records = match index::find_records(&index_file_path, id).await {
+        Ok(records) => records,
+        Err(e) => {
+            error!("Failed to search index {}: {}", index_file_path, e);
+            return ElementLookupStatus::ElementReadError(ServiceError::storage(
+                "Failed to read element index",
+            ));
+        }
+    };
+
+    let Some(first_record) = records.first() else {
+        return ElementLookupStatus::ElementNotFound;
+    };
+
+    let batch_number = first_record.batch_number as usize;
+    let batch_file_path = import_options.get_batch_file(element_type, batch_number);
+    let batch_content = match storage::storage().read(&batch_file_path).await {
+        Ok(Some(content)) => content,
+        _ => {
+            error!("Indexed batch file failed to read: {}", batch_file_path);
+            return ElementLookupStatus::ElementReadError(ServiceError::storage(
+                "Failed to read batch file",
+            ));
         }
-        (false, _) => {
-            if Path::new(&batches_complete_file_path).exists() {
-                BatchFileStatus::FileWillNeverExist
-            } else {
-                BatchFileStatus::FileDoesNotExistYet
+    };
+
+    let mut content = String::new();
+    for record in &records {
+        match slice_indexed_element(&batch_content, record) {
+            Some(element) => {
+                content.push_str(element);
+                content.push('\n');
+            }
+            None => {
+                error!("Index entry for {} {} is out of date", element_type, id);
+                return ElementLookupStatus::ElementReadError(ServiceError::storage(
+                    "Element index does not match batch file",
+                ));
             }
         }
     }
+
+    ElementLookupStatus::ElementFound {
+        batch_number,
+        content,
+    }
 }
 
-pub async fn maybe_start_background_processing(import_options: ImportOptions) {
-    let import_lock_file = import_options.get_lock_file();
-    if !Path::new(&import_lock_file).exists() {
-        tokio::spawn(async move {
-            info!("🎯 Background task started");
-            if let Err(e) = process_osm_import(&import_options).await {
-                error!("💥 Background processing failed: {e}");
-            } else {
-                info!("🎉 Background processing completed successfully");
+/// The quality report of an import, once every element type is batched.
+pub async fn get_quality_report(import_options: &ImportOptions) -> QualityReportStatus {
+    let storage = storage::storage();
+    for element_type in ElementType::ALL {
+        match storage
+            .exists(&import_options.get_batches_complete_file(element_type))
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => return QualityReportStatus::ReportNotReady,
+            Err(e) => {
+                error!(
+                    "Failed to check completion marker for {}: {}",
+                    element_type, e
+                );
+                return QualityReportStatus::ReportReadError(ServiceError::storage(
+                    "Failed to read quality report",
+                ));
             }
-        });
+        }
+    }
+
+    let report_file = import_options.get_quality_report_file();
+    let content = match storage.read(&report_file).await {
+        Ok(Some(content)) => content,
+        Ok(None) => return QualityReportStatus::ReportNotFound,
+        Err(e) => {
+            error!("Quality report failed to read: {}: {}", report_file, e);
+            return QualityReportStatus::ReportReadError(ServiceError::storage(
+                "Failed to read quality report",
+            ));
+        }
+    };
+    match std::str::from_utf8(&content)
+        .map_err(anyhow::Error::from)
+        .and_then(QualityReport::parse)
+    {
+        Ok(report) => QualityReportStatus::ReportFound(report),
+        Err(e) => {
+            error!("Invalid quality report {}: {}", report_file, e);
+            QualityReportStatus::ReportReadError(ServiceError::new(
+                ErrorCode::ParseFailed,
+                "Failed to read quality report",
+            ))
+        }
+    }
+}
+
+/// The `limit` most used keys across `element_types`, or with `key` the `limit` most
+/// used values of that key.
+pub async fn get_tag_statistics(
+    import_options: &ImportOptions,
+    element_types: &[ElementType],
+    key: Option<&str>,
+    limit: usize,
+) -> TagStatisticsStatus {
+    let storage = storage::storage();
+    let mut statistics = Vec::new();
+    for &element_type in element_types {
+        match storage
+            .exists(&import_options.get_batches_complete_file(element_type))
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => return TagStatisticsStatus::StatisticsNotReady,
+            Err(e) => {
+                error!(
+                    "Failed to check completion marker for {}: {}",
+                    element_type, e
+                );
+                return TagStatisticsStatus::StatisticsReadError(ServiceError::storage(
+                    "Failed to read tag statistics",
+                ));
+            }
+        }
+
+        let statistics_file = import_options.get_tag_statistics_file(element_type);
+        let content = match storage.read(&statistics_file).await {
+            Ok(Some(content)) => content,
+            Ok(None) => return TagStatisticsStatus::StatisticsNotGathered,
+            Err(e) => {
+                error!("Tag statistics failed to read: {}: {}", statistics_file, e);
+                return TagStatisticsStatus::StatisticsReadError(ServiceError::storage(
+                    "Failed to read tag statistics",
+                ));
+            }
+        };
+        match std::str::from_utf8(&content)
+            .map_err(anyhow::Error::from)
+            .and_then(tag_stats::TypeStatistics::parse)
+        {
+            Ok(type_statistics) => statistics.push((element_type, type_statistics)),
+            Err(e) => {
+                error!("Invalid tag statistics {}: {}", statistics_file, e);
+                return TagStatisticsStatus::StatisticsReadError(ServiceError::new(
+                    ErrorCode::ParseFailed,
+                    "Failed to read tag statistics",
+                ));
+            }
+        }
+    }
+
+    TagStatisticsStatus::StatisticsFound(tag_stats::summarize(&statistics, key, limit))
+}
+
+/// Whether `element_type` is completely batched, with `index_files` fetched to the local
+/// disk for lookups.
+async fn index_ready(
+    import_options: &ImportOptions,
+    element_type: ElementType,
+    index_files: &[&str],
+) -> Result<bool> {
+    let storage = storage::storage();
+    if !storage
+        .exists(&import_options.get_batches_complete_file(element_type))
+        .await?
+    {
+        return Ok(false);
+    }
+    for index_file in index_files {
+        if !storage.fetch(index_file).await? {
+            anyhow::bail!("{} is missing although batching completed", index_file);
+        }
+    }
+    Ok(true)
+}
+
+fn slice_indexed_element<'a>(
+    batch_content: &'a [u8],
+    record: &index::IndexRecord,
+) -> Option<&'a str> {
+    let start = record.offset as usize;
+    let end = start + record.length as usize;
+    batch_content
+        .get(start..end)
+        .and_then(|element| std::str::from_utf8(element).ok())
+}
+
+pub async fn query_bbox(
+    import_options: &ImportOptions,
+    bbox: &BoundingBox,
+    element_types: &[ElementType],
+    max_results: usize,
+) -> BboxQueryStatus {
+    for &element_type in element_types {
+        let index_files = [
+            import_options.get_index_file(element_type),
+            import_options.get_spatial_index_file(element_type),
+        ];
+        let index_files: Vec<&str> = index_files.iter().map(String::as_str).collect();
+        match index_ready(import_options, element_type, &index_files).await {
+            Ok(true) => {}
+            Ok(false) => return BboxQueryStatus::IndexNotReady,
+            Err(e) => {
+                error!("Failed to fetch {} indexes: {}", element_type, e);
+                return BboxQueryStatus::QueryError(ServiceError::storage(
+                    "Failed to query spatial index",
+                ));
+            }
+        }
+    }
+
+    // One match past the limit is looked for, so `truncated` is only set when matches
+    // were actually left out.
+    let limit = max_results.saturating_add(1);
+    let mut elements = Vec::new();
+    for &element_type in element_types {
+        if elements.len() >= limit {
+            break;
+        }
+        match query_bbox_for_type(
+            import_options,
+            bbox,
+            element_type,
+            limit.saturating_sub(elements.len()),
+        )
+        .await
+        {
+            Ok(mut found) => elements.append(&mut found),
+            Err(e) => {
+                error!("Bbox query over {} failed: {}", element_type, e);
+                return BboxQueryStatus::QueryError(ServiceError::storage(
+                    "Failed to query spatial index",
+                ));
+            }
+        }
+    }
+
+    let truncated = elements.len() > max_results;
+    elements.truncate(max_results);
+    BboxQueryStatus::ElementsFound {
+        elements,
+        truncated,
     }
 }
 
-pub async fn process_osm_import(import_options: &ImportOptions) -> Result<()> {
+async fn query_bbox_for_type(
+    import_options: &ImportOptions,
+    bbox: &BoundingBox,
+    element_type: ElementType,
+    max_results: usize,
+) -> Result<Vec<MatchedElement>> {
+    let spatial_index_file = import_options.get_spatial_index_file(element_type);
+    let index_file = import_options.get_index_file(element_type);
+    let mut batch_contents: HashMap<u32, Vec<u8>> = HashMap::new();
+
+    let mut elements = Vec::new();
+    for id in spatial::find_ids_in_bbox(&spatial_index_file, bbox).await? {
+        // History imports index one record per version, so the limit is checked for
+        // each record rather than each id.
+        for record in index::find_records(&index_file, id).await? {
+            if elements.len() >= max_results {
+                return Ok(elements);
+            }
+            let batch_content = match batch_contents.entry(record.batch_number) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    let batch_file =
+                        import_options.get_batch_file(element_type, record.batch_number as usize);
+                    let Some(content) = storage::storage().read(&batch_file).await? else {
+                        anyhow::bail!("Indexed batch file {} is missing", batch_file);
+                    };
+                    entry.insert(content)
+                }
+            };
+
+            let Some(content) = slice_indexed_element(batch_content, &record) else {
+                anyhow::bail!("Element index does not match batch file");
+            };
+
+            // Cells only narrow the search; nodes carry exact coordinates to check against.
+            if element_type == ElementType::Node && !node_in_bbox(content, bbox)? {
+                continue;
+            }
+
+            elements.push(MatchedElement {
+                element_type,
+                id,
+                batch_number: record.batch_number as usize,
+                content: content.to_string(),
+            });
+        }
+    }
+
+    Ok(elements)
+}
+
+fn node_in_bbox(node_xml: &str, bbox: &BoundingBox) -> Result<bool> {
+    let mut reader = Reader::from_str(node_xml);
+    loop {
+        match reader.read_event()? {
+            Event::Start(ref e) | Event::Empty(ref e) if e.local_name().as_ref() == b"node" => {
+                return Ok(
+                    reader::get_coordinates(e)?.is_some_and(|(lat, lon)| bbox.contains(lat, lon))
+                );
+            }
+            Event::Eof => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+pub async fn maybe_start_background_processing(
+    import_options: ImportOptions,
+    priority: ImportPriority,
+) {
+    jobs::start_import(import_options, priority);
+}
+
+pub async fn process_osm_import(
+    import_options: &ImportOptions,
+    cancel: &CancellationToken,
+) -> Result<()> {
     let import_scope = import_options.get_import_scope();
     let import_dir = import_options.get_import_dir();
+    let provenance = header::BatchProvenance::new(&import_options.get_import_handle());
 
     fs::create_dir_all(&import_dir).await?;
 
     let lock_file_path = import_options.get_lock_file();
     fs::write(&lock_file_path, "locked").await?;
+    validation::clear(&import_dir).await?;
+    error::clear(&import_dir).await?;
 
-    let result = match import_options.osm_file_type {
-        OsmFileType::Full(_) => process_full_import(&import_scope, &import_dir).await,
-        OsmFileType::Delta(_) => process_delta_import(&import_scope, &import_dir).await,
+    let result = match &import_options.osm_file_type {
+        OsmFileType::Full(_) => {
+            process_full_import(&import_scope, &import_dir, provenance, cancel).await
+        }
+        OsmFileType::Delta(abc) => {
+            process_delta_import(abc.as_str(), &import_dir, provenance, cancel).await
+        }
+        OsmFileType::History(date, window) => {
+            process_history_import(date.as_str(), window, &import_dir, provenance, cancel).await
+        }
+        OsmFileType::Diff(from, to) => {
+            process_diff_import(from, to, &import_scope, &import_dir, provenance, cancel).await
+        }
+        OsmFileType::Custom(source) => {
+            let xml_file = format!("{}/{}", import_dir, import_options.get_filename_base());
+            process_custom_import(source, &xml_file, &import_dir, provenance, cancel).await
+        }
     };
 
+    match &result {
+        Err(e) if e.is::<jobs::ImportCancelled>() => {}
+        Err(e) => match e.downcast_ref::<InvalidInput>() {
+            Some(invalid) => {
+                if let Err(e) = validation::record(&import_dir, invalid).await {
+                    warn!("Failed to record invalid input: {}", e);
+                }
+            }
+            None => {
+                if let Err(e) =
+                    error::record(&import_dir, &ServiceError::from_import_error(e)).await
+                {
+                    warn!("Failed to record import error: {}", e);
+                }
+            }
+        },
+        Ok(()) => {}
+    }
+
+    if cancel.is_cancelled() {
+        if let Err(e) = utils::remove_temp_files(&import_dir).await {
+            warn!("Failed to clean up temp files of cancelled import: {}", e);
+        }
+    }
+
     match fs::remove_file(&lock_file_path).await {
         Ok(_) => {}
         Err(e) => warn!("Failed to remove lock file: {}", e),
@@ -188,75 +938,403 @@ pub async fn process_osm_import(import_options: &ImportOptions) -> Result<()> {
     result
 }
 
-async fn process_full_import(date: &str, import_dir: &str) -> Result<()> {
+async fn process_full_import(
+    date: &str,
+    import_dir: &str,
+    provenance: header::BatchProvenance,
+    cancel: &CancellationToken,
+) -> Result<()> {
     let osm_pbf_file = format!("{}/{}.osm.pbf", import_dir, date);
     let osm_xml_file = format!("{}/{}.osm", import_dir, date);
+    let url = provider::provider().full_extract_url(date)?;
 
-    download_osm_pbf(date, &osm_pbf_file).await?;
+    download_osm_pbf(&url, &osm_pbf_file, cancel).await?;
 
     if !Path::new(&osm_xml_file).exists() {
-        utils::convert_pbf_to_xml(&osm_pbf_file, &osm_xml_file).await?;
+        utils::convert_pbf_to_xml(&osm_pbf_file, &osm_xml_file, cancel).await?;
     }
 
-    batch_osm_xml(&osm_xml_file, import_dir, "full", 500).await?;
+    let provenance = provenance
+        .with_source(Some(url))
+        .with_replication_timestamp(utils::pbf_replication_timestamp(&osm_pbf_file).await);
+    batch_osm_xml(
+        &osm_xml_file,
+        import_dir,
+        &BatchPlan::new("full", 500),
+        &provenance,
+        cancel,
+    )
+    .await?;
 
     Ok(())
 }
 
-async fn process_delta_import(abc: &str, import_dir: &str) -> Result<()> {
+async fn process_delta_import(
+    abc: &str,
+    import_dir: &str,
+    provenance: header::BatchProvenance,
+    cancel: &CancellationToken,
+) -> Result<()> {
     let a_b_c = abc.replace("/", "_");
     let osc_gz_file = format!("{}/{}.osc.gz", import_dir, a_b_c);
-    let osc_file = format!("{}/{}.osc", import_dir, a_b_c);
+    let state_file = format!("{}/{}.state.txt", import_dir, a_b_c);
+    let url = provider::provider().change_url(abc)?;
+
+    download_osc_gz(&url, &osc_gz_file, cancel).await?;
+    let replication_timestamp = match download_state(abc, &state_file, cancel).await {
+        Ok(()) => utils::state_replication_timestamp(&state_file).await?,
+        Err(e) if e.is::<jobs::ImportCancelled>() => return Err(e),
+        Err(e) => {
+            warn!("No replication state for delta {}: {}", abc, e);
+            None
+        }
+    };
 
-    download_osc_gz(abc, &osc_gz_file).await?;
+    let provenance = provenance
+        .with_source(Some(url))
+        .with_replication_timestamp(replication_timestamp);
+    // Batched straight from the download; the batches are still named after the `.osc`.
+    batch_osm_xml(
+        &osc_gz_file,
+        import_dir,
+        &BatchPlan::new("delta", 1000),
+        &provenance,
+        cancel,
+    )
+    .await?;
 
-    utils::decompress_gz(&osc_gz_file, &osc_file).await?;
+    Ok(())
+}
 
-    batch_osm_xml(&osc_file, import_dir, "delta", 1000).await?;
+/// Serializes downloading and converting history extracts, which every time window of
+/// a date shares.
+static HISTORY_SOURCES: LazyLock<tokio::sync::Mutex<()>> =
+    LazyLock::new(|| tokio::sync::Mutex::new(()));
+
+async fn process_history_import(
+    date: &str,
+    window: &TimeWindow,
+    import_dir: &str,
+    provenance: header::BatchProvenance,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    // Keyed by date alone: the window only filters versions while batching.
+    let osh_pbf_file = format!("{}/sources/{}.osh.pbf", DATA_DIR, date);
+    let osh_xml_file = format!("{}/sources/{}.osh", DATA_DIR, date);
+    let url = osh_pbf_url(date);
+
+    {
+        let _sources = HISTORY_SOURCES.lock().await;
+        fs::create_dir_all(format!("{}/sources", DATA_DIR)).await?;
+        let prepared = async {
+            download_osh_pbf(url.as_deref(), &osh_pbf_file, cancel).await?;
+            if !Path::new(&osh_xml_file).exists() {
+                utils::convert_pbf_to_xml(&osh_pbf_file, &osh_xml_file, cancel).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        // Outside the import directory, so cancelling the import does not clean these up.
+        if prepared.is_err() {
+            for file in [&osh_pbf_file, &osh_xml_file] {
+                let _ = fs::remove_file(format!("{}.temp", file)).await;
+            }
+        }
+        prepared?;
+    }
+
+    let provenance = provenance
+        .with_source(url)
+        .with_replication_timestamp(utils::pbf_replication_timestamp(&osh_pbf_file).await);
+    batch_osm_xml(
+        &osh_xml_file,
+        import_dir,
+        &BatchPlan {
+            time_window: window.clone(),
+            ..BatchPlan::new("history", 500)
+        },
+        &provenance,
+        cancel,
+    )
+    .await?;
 
     Ok(())
 }
 
-async fn download_osm_pbf(date: &str, output_path: &str) -> Result<()> {
-    if Path::new(output_path).exists() {
-        return Ok(());
+async fn process_diff_import(
+    from: &FullDate,
+    to: &FullDate,
+    scope: &str,
+    import_dir: &str,
+    provenance: header::BatchProvenance,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let osc_file = format!("{}/{}.osc", import_dir, scope);
+
+    if !Path::new(&osc_file).exists() {
+        let old_xml_file = prepare_full_import(from, cancel).await?;
+        let new_xml_file = prepare_full_import(to, cancel).await?;
+        let generator = format!("osm-import-rust diff {} {}", from.as_str(), to.as_str());
+        // The merge reads both extracts end to end, so it stays off the async workers.
+        let (merged_file, merge_cancel) = (osc_file.clone(), cancel.clone());
+        let summary = tokio::task::spawn_blocking(move || {
+            diff::write_osm_change(
+                &old_xml_file,
+                &new_xml_file,
+                &merged_file,
+                &generator,
+                &merge_cancel,
+            )
+        })
+        .await??;
+        info!(
+            "Diff {} → {}: {} created, {} modified, {} deleted",
+            from.as_str(),
+            to.as_str(),
+            summary.created,
+            summary.modified,
+            summary.deleted
+        );
     }
 
-    let url = format!(
-        "https://download.geofabrik.de/asia/bangladesh-{}.osm.pbf",
-        date
+    // The change ends where the newer extract does.
+    let new_pbf_file = format!(
+        "{}/{}.osm.pbf",
+        full_import_options(to).get_import_dir(),
+        to.as_str()
     );
-    utils::download_file(&url, output_path).await
+    let provenance = provenance
+        .with_replication_timestamp(utils::pbf_replication_timestamp(&new_pbf_file).await);
+
+    // Diffs are consumed like Geofabrik deltas, so they are batched exactly the same way.
+    batch_osm_xml(
+        &osc_file,
+        import_dir,
+        &BatchPlan::new("delta", 1000),
+        &provenance,
+        cancel,
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn process_custom_import(
+    source: &CustomSource,
+    xml_file: &str,
+    import_dir: &str,
+    provenance: header::BatchProvenance,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let input_file = source::prepare_input(source, xml_file, cancel).await?;
+
+    let replication_timestamp = match source.format() {
+        SourceFormat::OsmPbf => utils::pbf_replication_timestamp(source.path()).await,
+        _ => None,
+    };
+    let provenance = provenance
+        .with_source(source::provenance_source(source))
+        .with_replication_timestamp(replication_timestamp);
+
+    // Change files are batched like Geofabrik deltas, everything else like full extracts.
+    let (import_type, elements_per_batch) = if source.format().is_change() {
+        ("delta", 1000)
+    } else {
+        ("full", 500)
+    };
+    batch_osm_xml(
+        &input_file,
+        import_dir,
+        &BatchPlan::new(import_type, elements_per_batch),
+        &provenance,
+        cancel,
+    )
+    .await?;
+
+    Ok(())
+}
+
+fn full_import_options(date: &FullDate) -> ImportOptions {
+    ImportOptions {
+        osm_file_type: OsmFileType::Full(date.clone()),
+        base_path: "./data/".to_string(),
+    }
+}
+
+async fn prepare_full_import(date: &FullDate, cancel: &CancellationToken) -> Result<String> {
+    let full_import = full_import_options(date);
+    // Through the queue, so the extract counts against the import limit and an
+    // import of it that is already running is waited for instead of raced.
+    jobs::run_prerequisite(&full_import, cancel).await?;
+
+    Ok(format!(
+        "{}/{}.osm",
+        full_import.get_import_dir(),
+        date.as_str()
+    ))
+}
+
+/// Parses a full import date as `FullDate::new` does, resolving `latest` to the newest
+/// extract upstream has published.
+pub async fn resolve_full_date(date: String) -> Result<FullDate, ServiceError> {
+    if date != "latest" {
+        return FullDate::new(date).map_err(ServiceError::invalid_argument);
+    }
+    latest_full_date().await?.ok_or_else(|| {
+        ServiceError::new(
+            ErrorCode::UpstreamNotFound,
+            format!(
+                "No full extract was published in the last {} days",
+                provider::provider().full_extract_lookback_days()
+            ),
+        )
+    })
+}
+
+/// The newest full extract the upstream provider has published. `None` when nothing was
+/// published in the last few days.
+pub(crate) async fn latest_full_date() -> Result<Option<FullDate>, ServiceError> {
+    let client = reqwest::Client::new();
+    let today = Utc::now().date_naive();
+
+    for days_back in 0..=provider::provider().full_extract_lookback_days() {
+        let Some(date) = today.checked_sub_days(Days::new(days_back)) else {
+            break;
+        };
+        let Ok(date) = FullDate::from_date(date) else {
+            break;
+        };
+        let Ok(url) = provider::provider().full_extract_url(date.as_str()) else {
+            continue;
+        };
+        let response = client.head(&url).send().await.map_err(|e| {
+            ServiceError::new(
+                ErrorCode::UpstreamUnavailable,
+                format!("Looking up the latest extract failed: {}", e),
+            )
+            .with_detail("url", &url)
+        })?;
+        if response.status().is_success() {
+            return Ok(Some(date));
+        }
+    }
+
+    Ok(None)
 }
 
-async fn download_osc_gz(abc: &str, output_path: &str) -> Result<()> {
-    if Path::new(output_path).exists() {
+// Geofabrik only serves full-history extracts to logged-in users, so the source URL
+// is supplied by the operator, e.g. a mirror of the internal download server.
+fn osh_pbf_url(date: &str) -> Option<String> {
+    let url_template = std::env::var("HISTORY_PBF_URL_TEMPLATE").ok()?;
+    Some(url_template.replace("{date}", date))
+}
+
+/// Downloads a source file unless this or another replica already did, and shares it
+/// through the storage backend.
+/// Downloads are checked against `checksum_url` before they are shared.
+async fn fetch_source_file(
+    url: &str,
+    output_path: &str,
+    checksum_url: Option<&str>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let storage = storage::storage();
+    if storage.fetch(output_path).await? {
+        return check_source_file(output_path).await;
+    }
+
+    utils::download_file(url, output_path, cancel).await?;
+    if let Some(checksum_url) = checksum_url {
+        provider::verify_checksum(checksum_url, url, output_path).await?;
+    }
+    check_source_file(output_path).await?;
+    storage.upload(output_path).await
+}
+
+/// Validates a source file and removes it when it is invalid, so the next attempt
+/// downloads it again instead of failing on the same bad copy.
+async fn check_source_file(path: &str) -> Result<()> {
+    let result = validation::check_source(path).await;
+    if let Err(e) = &result {
+        if e.is::<InvalidInput>() {
+            warn!("Removing invalid source file {}: {}", path, e);
+            storage::storage().remove(path).await?;
+        }
+    }
+    result
+}
+
+async fn download_osm_pbf(url: &str, output_path: &str, cancel: &CancellationToken) -> Result<()> {
+    let checksum_url = provider::provider().checksum_url(url);
+    fetch_source_file(url, output_path, checksum_url.as_deref(), cancel).await
+}
+
+async fn download_osc_gz(url: &str, output_path: &str, cancel: &CancellationToken) -> Result<()> {
+    let checksum_url = provider::provider().checksum_url(url);
+    fetch_source_file(url, output_path, checksum_url.as_deref(), cancel).await
+}
+
+async fn download_state(abc: &str, output_path: &str, cancel: &CancellationToken) -> Result<()> {
+    let url = provider::provider().change_state_url(abc)?;
+    fetch_source_file(&url, output_path, None, cancel).await
+}
+
+async fn download_osh_pbf(
+    url: Option<&str>,
+    output_path: &str,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    if storage::storage().fetch(output_path).await? {
         return Ok(());
     }
 
-    let url = format!(
-        "https://download.geofabrik.de/asia/bangladesh-updates/{}.osc.gz",
-        abc
-    );
-    utils::download_file(&url, output_path).await
+    let Some(url) = url else {
+        anyhow::bail!(
+            "No history source configured. Set HISTORY_PBF_URL_TEMPLATE (with a {{date}} placeholder) or place the file at {}",
+            output_path
+        );
+    };
+    fetch_source_file(url, output_path, None, cancel).await
+}
+
+/// How `batch_osm_xml` cuts an input into batches.
+struct BatchPlan {
+    import_type: &'static str,
+    elements_per_batch: usize,
+    time_window: TimeWindow,
+    /// Tasks serializing and writing batches. The output does not depend on it.
+    writer_count: usize,
+}
+
+impl BatchPlan {
+    fn new(import_type: &'static str, elements_per_batch: usize) -> Self {
+        BatchPlan {
+            import_type,
+            elements_per_batch,
+            time_window: TimeWindow::default(),
+            writer_count: batch_writer_count(),
+        }
+    }
 }
 
 async fn batch_osm_xml(
     input_file: &str,
     import_dir: &str,
-    import_type: &str,
-    elements_per_batch: usize,
+    plan: &BatchPlan,
+    provenance: &header::BatchProvenance,
+    cancel: &CancellationToken,
 ) -> Result<()> {
+    let import_type = plan.import_type;
     let batches_dir = format!("{}/batches", import_dir);
-    let input_filename = Path::new(input_file).file_name().unwrap().to_str().unwrap();
+    let input_filename = &utils::uncompressed_filename(input_file)?;
 
     let mut all_complete = true;
-    for element_type in &["node", "way", "relation"] {
+    for element_type in ElementType::ALL {
         let complete_file = format!(
             "{}/{}/{}.batches_complete",
             batches_dir, element_type, input_filename
         );
-        if !Path::new(&complete_file).exists() {
+        if !storage::storage().exists(&complete_file).await? {
             all_complete = false;
             break;
         }
@@ -266,355 +1344,929 @@ async fn batch_osm_xml(
         return Ok(());
     }
 
-    if Path::new(&batches_dir).exists() {
-        fs::remove_dir_all(&batches_dir).await?;
+    let checkpoint_file = format!("{}/{}.checkpoint", batches_dir, input_filename);
+    let journal_file = format!("{}/{}.spatial_journal", batches_dir, input_filename);
+    let quality_journal_file = format!("{}/{}.quality_journal", batches_dir, input_filename);
+    let tag_journal_file = format!("{}/{}.tag_journal", batches_dir, input_filename);
+    let input_kind = quality::InputKind::from_import_type(import_type);
+    let input_size = fs::metadata(input_file).await?.len();
+
+    let element_output = reader::ElementOutput::from_env().map_err(anyhow::Error::msg)?;
+    let header_config = header::BatchHeaderConfig::from_env().map_err(anyhow::Error::msg)?;
+    let osm_reader =
+        reader::OsmReader::new(utils::open_input(input_file, 0)?)?.with_output(element_output);
+    let root_element_info = header_config.batch_root_element(osm_reader.root(), provenance);
+
+    let resumed = match checkpoint::BatchCheckpoint::read(&checkpoint_file).await {
+        Ok(Some(checkpoint)) if checkpoint.input_size == input_size => {
+            let resumed = resume_batching(
+                checkpoint,
+                osm_reader.root().clone(),
+                element_output,
+                input_file,
+                &batches_dir,
+                &journal_file,
+                input_kind,
+            )
+            .await;
+            match resumed {
+                Ok(state) => Some(state),
+                Err(e) => {
+                    warn!("Cannot resume batching of {}: {}", input_filename, e);
+                    None
+                }
+            }
+        }
+        Ok(Some(_)) => {
+            info!("{} changed since its checkpoint", input_filename);
+            None
+        }
+        Ok(None) => None,
+        Err(e) => {
+            warn!("Ignoring unreadable checkpoint {}: {}", checkpoint_file, e);
+            None
+        }
+    };
+
+    let BatchingState {
+        osm_reader,
+        type_batches,
+        index_builders,
+        spatial_index_builder,
+        quality_report_builder,
+        tag_statistics_builder,
+        total_elements_processed,
+    } = match resumed {
+        Some(state) => state,
+        None => {
+            if validation::full_validation_enabled() {
+                let id_order = validation::IdOrder::for_import_type(import_type);
+                let validated = validation::check_xml(input_file, id_order, cancel).await;
+                if let Err(e) = &validated {
+                    if e.is::<InvalidInput>() {
+                        warn!("Removing invalid input {}: {}", input_file, e);
+                        storage::storage().remove(input_file).await?;
+                    }
+                }
+                validated?;
+            }
+
+            if Path::new(&batches_dir).exists() {
+                fs::remove_dir_all(&batches_dir).await?;
+            }
+            fs::create_dir_all(&batches_dir).await?;
+
+            let mut type_batches = HashMap::new();
+            let mut index_builders = HashMap::new();
+            for element_type in ElementType::ALL {
+                let dir_path = format!("{}/{}", batches_dir, element_type);
+                fs::create_dir_all(&dir_path).await?;
+
+                let index_path = format!("{}/{}.index", dir_path, input_filename);
+                let ranges_path = format!("{}/{}.batch_ranges", dir_path, input_filename);
+                let index_builder = index::IndexBuilder::create(&index_path, &ranges_path).await?;
+                index_builders.insert(element_type, index_builder);
+                type_batches.insert(element_type, TypeBatches::default());
+            }
+
+            BatchingState {
+                osm_reader,
+                type_batches,
+                index_builders,
+                spatial_index_builder: spatial::SpatialIndexBuilder::with_journal(&journal_file)?,
+                quality_report_builder: quality::QualityReportBuilder::with_journal(
+                    input_kind,
+                    &quality_journal_file,
+                )?,
+                tag_statistics_builder: tag_stats::enabled()
+                    .then(|| tag_stats::TagStatisticsBuilder::with_journal(&tag_journal_file))
+                    .transpose()?,
+                total_elements_processed: 0,
+            }
+        }
+    };
+
+    // One thread parses and cuts batches, a pool of tasks serializes and writes them,
+    // and this task indexes the written batches in the order they were cut. Batch
+    // numbers are assigned while parsing, so the output matches a sequential run.
+    let writer_count = plan.writer_count;
+    let (job_sender, mut job_receiver) = tokio::sync::mpsc::channel(writer_count * 2);
+    let parser = BatchParser {
+        osm_reader,
+        type_batches,
+        spatial_index_builder: Some(spatial_index_builder),
+        quality_report_builder: Some(quality_report_builder),
+        tag_statistics_builder,
+        total_elements_processed,
+        import_type: import_type.to_string(),
+        elements_per_batch: plan.elements_per_batch,
+        time_window: plan.time_window.clone(),
+        id_order: validation::IdOrderCheck::new(
+            input_file,
+            validation::IdOrder::for_import_type(import_type),
+        ),
+        input_size: Some(input_size),
+        cancel: cancel.clone(),
+        jobs: job_sender,
+    };
+    let parser = tokio::task::spawn_blocking(move || parser.run());
+
+    let root_element_info = std::sync::Arc::new(root_element_info);
+    let sink = std::sync::Arc::new(storage::StorageSink {
+        batches_dir: batches_dir.clone(),
+        file_prefix: input_filename.to_string(),
+    });
+    let mut indexer = BatchIndexer {
+        index_builders,
+        written: std::collections::BTreeMap::new(),
+        next_sequence: 0,
+        checkpoint_file: checkpoint_file.clone(),
+    };
+    let mut writes = tokio::task::JoinSet::new();
+    let mut sequence = 0;
+    while let Some(job) = job_receiver.recv().await {
+        while writes.len() >= writer_count {
+            indexer.add(writes.join_next().await.unwrap()??).await?;
+        }
+
+        let job_sequence = sequence;
+        sequence += 1;
+        let root_element_info = root_element_info.clone();
+        let sink = sink.clone();
+        writes.spawn(async move {
+            let batch = batching::render_batch(
+                job.element_type,
+                &job.elements,
+                job.batch_number,
+                &root_element_info,
+            );
+            sink.write_batch(&batch).await?;
+            Ok::<_, anyhow::Error>(WrittenBatch {
+                sequence: job_sequence,
+                element_type: job.element_type,
+                batch_number: job.batch_number,
+                index_records: batch.index_records,
+                checkpoint: job.checkpoint,
+            })
+        });
+    }
+    while let Some(written) = writes.join_next().await {
+        indexer.add(written??).await?;
     }
 
-    fs::create_dir_all(&batches_dir).await?;
-    for element_type in &["node", "way", "relation"] {
-        let dir_path = format!("{}/{}", batches_dir, element_type);
-        fs::create_dir_all(&dir_path).await?;
+    let ParsedInput {
+        spatial_index_builder,
+        quality_report_builder,
+        tag_statistics_builder,
+        batch_counts,
+        total_elements_processed,
+    } = match parser.await? {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            // Checked while batching unless the full pass above already did.
+            if e.is::<InvalidInput>() {
+                warn!("Removing invalid input {}: {}", input_file, e);
+                storage::storage().remove(input_file).await?;
+                if Path::new(&checkpoint_file).exists() {
+                    fs::remove_file(&checkpoint_file).await?;
+                }
+            }
+            return Err(e);
+        }
+    };
+    let mut spatial_index_builder = spatial_index_builder.unwrap();
+
+    let quality_report = quality_report_builder.unwrap().finish();
+    let quality_report_file = format!("{}/{}.quality", batches_dir, input_filename);
+    quality_report.write(&quality_report_file).await?;
+    storage::storage().upload(&quality_report_file).await?;
+    let issues: Vec<String> = quality_report
+        .checks
+        .iter()
+        .filter(|result| result.count > 0)
+        .map(|result| format!("{} {}", result.count, result.check.as_str()))
+        .collect();
+    if !issues.is_empty() {
+        warn!(
+            "🩺 Quality issues in {}: {}",
+            input_filename,
+            issues.join(", ")
+        );
     }
 
-    let xml_content = fs::read_to_string(input_file).await?;
+    for element_type in ElementType::ALL {
+        let index_builder = indexer.index_builders.remove(&element_type).unwrap();
+        index_builder.finish().await?;
 
-    let mut reader = Reader::from_str(&xml_content);
-    reader.config_mut().trim_text(true);
+        let spatial_index_file = format!(
+            "{}/batches/{}/{}.spatial",
+            import_dir, element_type, input_filename
+        );
+        spatial_index_builder
+            .write(element_type, &spatial_index_file)
+            .await?;
 
-    let root_element_info = parse_root_element(&xml_content)?;
+        let mut extensions = vec!["index", "batch_ranges", "spatial"];
+        if let Some(tag_statistics_builder) = &tag_statistics_builder {
+            let tag_statistics_file = format!(
+                "{}/{}/{}.tag_stats",
+                batches_dir, element_type, input_filename
+            );
+            tag_statistics_builder
+                .write(element_type, &tag_statistics_file)
+                .await?;
+            extensions.push("tag_stats");
+        }
 
-    let mut batch_counts = std::collections::HashMap::new();
-    let mut current_batches: std::collections::HashMap<String, Vec<String>> =
-        std::collections::HashMap::new();
+        // Other replicas fetch the indexes once the completion marker is visible.
+        for extension in extensions {
+            let index_file = format!(
+                "{}/{}/{}.{}",
+                batches_dir, element_type, input_filename, extension
+            );
+            storage::storage().upload(&index_file).await?;
+        }
 
-    for element_type in &["node", "way", "relation"] {
-        batch_counts.insert(element_type.to_string(), 0);
-        current_batches.insert(element_type.to_string(), Vec::new());
+        sink.finish(element_type, batch_counts[&element_type])
+            .await?;
     }
 
-    let mut buf = Vec::new();
-    let mut current_element = String::new();
-    let mut element_type = String::new();
-    let mut in_element = false;
-    let mut element_depth = 0;
-    let mut delta_container = String::new();
-    let mut total_elements_processed = 0;
+    // Inputs smaller than one batch never write a checkpoint.
+    if Path::new(&checkpoint_file).exists() {
+        fs::remove_file(&checkpoint_file).await?;
+    }
+    fs::remove_file(&journal_file).await?;
+    fs::remove_file(&quality_journal_file).await?;
+    // Left behind when a run resumed after tag statistics were switched off.
+    if Path::new(&tag_journal_file).exists() {
+        fs::remove_file(&tag_journal_file).await?;
+    }
 
-    loop {
-        match reader.read_event_into(&mut buf) {
-            Ok(Event::Start(ref e)) => {
-                let tag_name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
-
-                match tag_name.as_str() {
-                    "node" | "way" | "relation" => {
-                        element_type = tag_name.to_string();
-                        in_element = true;
-                        element_depth = 1;
-                        current_element.clear();
-
-                        if import_type == "delta" && !delta_container.is_empty() {
-                            current_element.push_str(&format!("<{}>\n", delta_container));
-                        }
+    info!(
+        "Batched {} elements from {}",
+        total_elements_processed, input_filename
+    );
 
-                        current_element.push_str(&format!("<{}", tag_name));
-                        for attr in e.attributes() {
-                            let attr = attr?;
-                            let key = std::str::from_utf8(attr.key.as_ref())?;
-                            let value = std::str::from_utf8(&attr.value)?;
-                            let escaped_value = value
-                                .replace("&", "&amp;")
-                                .replace("\"", "&quot;")
-                                .replace("<", "&lt;")
-                                .replace(">", "&gt;");
-                            current_element.push_str(&format!(" {}=\"{}\"", key, escaped_value));
-                        }
+    Ok(())
+}
 
-                        current_element.push_str(">");
-                    }
-                    "create" | "modify" | "delete" if import_type == "delta" => {
-                        delta_container = tag_name.to_string();
-                    }
-                    _ => {
-                        if in_element {
-                            element_depth += 1;
-
-                            current_element.push_str(&format!("<{}", tag_name));
-                            for attr in e.attributes() {
-                                let attr = attr?;
-                                let key = std::str::from_utf8(attr.key.as_ref())?;
-                                let value = std::str::from_utf8(&attr.value)?;
-                                let escaped_value = value
-                                    .replace("&", "&amp;")
-                                    .replace("\"", "&quot;")
-                                    .replace("<", "&lt;")
-                                    .replace(">", "&gt;");
-                                current_element
-                                    .push_str(&format!(" {}=\"{}\"", key, escaped_value));
-                            }
-                            current_element.push_str(">");
-                        }
-                    }
-                }
-            }
-            Ok(Event::End(ref e)) => {
-                let tag_name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
-
-                match tag_name.as_str() {
-                    "node" | "way" | "relation" => {
-                        if in_element && element_depth == 1 {
-                            current_element.push_str(&format!("</{}>", tag_name));
-
-                            if import_type == "delta" && !delta_container.is_empty() {
-                                current_element.push_str(&format!("\n</{}>", delta_container));
-                            }
-
-                            current_batches
-                                .get_mut(&element_type)
-                                .unwrap()
-                                .push(current_element.clone());
-                            total_elements_processed += 1;
-
-                            if current_batches[&element_type].len() >= elements_per_batch {
-                                write_batch(
-                                    &element_type,
-                                    &current_batches[&element_type],
-                                    batch_counts[&element_type],
-                                    import_dir,
-                                    input_file,
-                                    import_type,
-                                    &root_element_info,
-                                )
-                                .await?;
-                                *batch_counts.get_mut(&element_type).unwrap() += 1;
-                                current_batches.get_mut(&element_type).unwrap().clear();
-                            }
-
-                            in_element = false;
-                            element_depth = 0;
-                        } else if in_element {
-                            current_element.push_str(&format!("</{}>", tag_name));
-                            element_depth -= 1;
-                        }
-                    }
-                    "create" | "modify" | "delete" if import_type == "delta" => {
-                        delta_container.clear();
-                    }
-                    _ => {
-                        if in_element && element_depth > 1 {
-                            current_element.push_str(&format!("</{}>", tag_name));
-                            element_depth -= 1;
-                        }
-                    }
-                }
-            }
-            Ok(Event::Empty(ref e)) => {
-                let tag_name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+fn batch_writer_count() -> usize {
+    std::env::var("BATCH_WRITER_THREADS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|&count| count > 0)
+        .unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|count| count.get())
+                .unwrap_or(1)
+        })
+}
 
-                match tag_name.as_str() {
-                    "node" | "way" | "relation" => {
-                        element_type = tag_name.to_string();
-                        current_element.clear();
+/// Batching state of one element type while parsing.
+#[derive(Default)]
+struct TypeBatches {
+    batch_count: usize,
+    current: Vec<BatchElement>,
+    last_batched_id: Option<i64>,
+}
 
-                        if import_type == "delta" && !delta_container.is_empty() {
-                            current_element.push_str(&format!("<{}>\n", delta_container));
-                        }
+impl TypeBatches {
+    fn cut(&mut self, element_type: ElementType) -> BatchJob {
+        let job = BatchJob {
+            element_type,
+            batch_number: self.batch_count,
+            elements: std::mem::take(&mut self.current),
+            checkpoint: None,
+        };
+        self.batch_count += 1;
+        job
+    }
+}
 
-                        current_element.push_str(&format!("<{}", tag_name));
-                        for attr in e.attributes() {
-                            let attr = attr?;
-                            let key = std::str::from_utf8(attr.key.as_ref())?;
-                            let value = std::str::from_utf8(&attr.value)?;
-                            let escaped_value = value
-                                .replace("&", "&amp;")
-                                .replace("\"", "&quot;")
-                                .replace("<", "&lt;")
-                                .replace(">", "&gt;");
-                            current_element.push_str(&format!(" {}=\"{}\"", key, escaped_value));
-                        }
-                        current_element.push_str("/>");
+struct BatchJob {
+    element_type: ElementType,
+    batch_number: usize,
+    elements: Vec<BatchElement>,
+    /// Progress right after this batch was cut, written once it and every batch cut
+    /// before it are indexed.
+    checkpoint: Option<checkpoint::BatchCheckpoint>,
+}
 
-                        if import_type == "delta" && !delta_container.is_empty() {
-                            current_element.push_str(&format!("\n</{}>", delta_container));
-                        }
+struct WrittenBatch {
+    sequence: u64,
+    element_type: ElementType,
+    batch_number: usize,
+    index_records: Vec<index::IndexRecord>,
+    checkpoint: Option<checkpoint::BatchCheckpoint>,
+}
+
+struct ParsedInput {
+    spatial_index_builder: Option<spatial::SpatialIndexBuilder>,
+    quality_report_builder: Option<quality::QualityReportBuilder>,
+    tag_statistics_builder: Option<tag_stats::TagStatisticsBuilder>,
+    batch_counts: HashMap<ElementType, usize>,
+    total_elements_processed: usize,
+}
+
+struct BatchParser<R: std::io::BufRead> {
+    osm_reader: reader::OsmReader<R>,
+    type_batches: HashMap<ElementType, TypeBatches>,
+    /// Only batching into the import directory builds a spatial index.
+    spatial_index_builder: Option<spatial::SpatialIndexBuilder>,
+    quality_report_builder: Option<quality::QualityReportBuilder>,
+    /// Only gathered with `TAG_STATISTICS=1`.
+    tag_statistics_builder: Option<tag_stats::TagStatisticsBuilder>,
+    total_elements_processed: usize,
+    import_type: String,
+    elements_per_batch: usize,
+    time_window: TimeWindow,
+    id_order: validation::IdOrderCheck,
+    /// Size of a seekable input, which can be checkpointed and resumed.
+    input_size: Option<u64>,
+    cancel: CancellationToken,
+    jobs: tokio::sync::mpsc::Sender<BatchJob>,
+}
+
+impl<R: std::io::BufRead> BatchParser<R> {
+    fn run(mut self) -> Result<ParsedInput> {
+        loop {
+            let position = self.osm_reader.position();
+            let Some(element) = self.osm_reader.next_element()? else {
+                break;
+            };
+            jobs::check_cancelled(&self.cancel)?;
+            self.id_order.check(element.element_type, element.id)?;
+
+            if self.import_type == "history" && !self.time_window.contains(&element.timestamp) {
+                continue;
+            }
+
+            let element_type = element.element_type;
+            let element_id = element.id;
+            let batches = self.type_batches.get_mut(&element_type).unwrap();
+
+            // History files list every version of an element back to back; a batch may
+            // only be cut between two different ids so consumers always see the full
+            // version chain.
+            let continues_previous =
+                self.import_type == "history" && batches.last_batched_id == Some(element_id);
+
+            if batches.current.len() >= self.elements_per_batch && !continues_previous {
+                let mut job = batches.cut(element_type);
+                // Taken before the current element, so a resumed run reads it again.
+                if let Some(input_size) = self.input_size {
+                    job.checkpoint = Some(self.checkpoint(input_size, position)?);
+                }
+                self.send(job)?;
+            }
 
-                        current_batches
-                            .get_mut(&element_type)
-                            .unwrap()
-                            .push(current_element.clone());
-                        total_elements_processed += 1;
-
-                        if current_batches[&element_type].len() >= elements_per_batch {
-                            write_batch(
-                                &element_type,
-                                &current_batches[&element_type],
-                                batch_counts[&element_type],
-                                import_dir,
-                                input_file,
-                                import_type,
-                                &root_element_info,
-                            )
-                            .await?;
-                            *batch_counts.get_mut(&element_type).unwrap() += 1;
-                            current_batches.get_mut(&element_type).unwrap().clear();
+            if let Some(spatial_index_builder) = self.spatial_index_builder.as_mut() {
+                match element_type {
+                    ElementType::Node => {
+                        if let Some((lat, lon)) = element.coordinates {
+                            spatial_index_builder.add_node(element_id, lat, lon)?;
                         }
                     }
-                    _ => {
-                        if in_element {
-                            current_element.push_str(&format!("<{}", tag_name));
-                            for attr in e.attributes() {
-                                let attr = attr?;
-                                let key = std::str::from_utf8(attr.key.as_ref())?;
-                                let value = std::str::from_utf8(&attr.value)?;
-                                let escaped_value = value
-                                    .replace("&", "&amp;")
-                                    .replace("\"", "&quot;")
-                                    .replace("<", "&lt;")
-                                    .replace(">", "&gt;");
-                                current_element
-                                    .push_str(&format!(" {}=\"{}\"", key, escaped_value));
-                            }
-                            current_element.push_str("/>");
-                        }
+                    ElementType::Way => {
+                        spatial_index_builder.add_way(element_id, &element.node_refs)?
+                    }
+                    ElementType::Relation => {
+                        spatial_index_builder.add_relation(element_id, &element.members)?
                     }
                 }
             }
-            Ok(Event::Text(e)) => {
-                if in_element {
-                    let text = std::str::from_utf8(&e)?;
-                    let escaped_text = text
-                        .replace("&", "&amp;")
-                        .replace("<", "&lt;")
-                        .replace(">", "&gt;");
-                    current_element.push_str(&escaped_text);
-                }
+            if let Some(quality_report_builder) = self.quality_report_builder.as_mut() {
+                quality_report_builder.add(&element)?;
             }
-            Ok(Event::CData(e)) => {
-                if in_element {
-                    current_element.push_str("<![CDATA[");
-                    current_element.push_str(std::str::from_utf8(&e)?);
-                    current_element.push_str("]]>");
+            if let Some(tag_statistics_builder) = self.tag_statistics_builder.as_mut() {
+                // Deletions carry no tags worth counting.
+                if element.action.as_deref() != Some("delete") {
+                    tag_statistics_builder.add(element_type, &element.tags)?;
                 }
             }
-            Ok(Event::Eof) => break,
-            Err(e) => anyhow::bail!("XML parsing error: {}", e),
-            _ => {}
+
+            let batches = self.type_batches.get_mut(&element_type).unwrap();
+            batches.current.push(BatchElement {
+                id: element_id,
+                xml: element.into_batch_xml(),
+            });
+            batches.last_batched_id = Some(element_id);
+            self.total_elements_processed += 1;
+        }
+
+        let mut batch_counts = HashMap::new();
+        for element_type in ElementType::ALL {
+            let batches = self.type_batches.get_mut(&element_type).unwrap();
+            if !batches.current.is_empty() {
+                let job = batches.cut(element_type);
+                self.send(job)?;
+            }
+            batch_counts.insert(element_type, self.type_batches[&element_type].batch_count);
         }
-        buf.clear();
+
+        Ok(ParsedInput {
+            spatial_index_builder: self.spatial_index_builder,
+            quality_report_builder: self.quality_report_builder,
+            tag_statistics_builder: self.tag_statistics_builder,
+            batch_counts,
+            total_elements_processed: self.total_elements_processed,
+        })
+    }
+
+    fn send(&self, job: BatchJob) -> Result<()> {
+        self.jobs
+            .blocking_send(job)
+            .map_err(|_| anyhow::anyhow!("Batch writers stopped"))
     }
 
-    for element_type in &["node", "way", "relation"] {
-        let element_key = element_type.to_string();
-        if !current_batches[&element_key].is_empty() {
-            write_batch(
+    // Index record counts are only known once the batches are indexed; the indexer
+    // fills them in.
+    fn checkpoint(
+        &mut self,
+        input_size: u64,
+        position: reader::ReaderPosition,
+    ) -> Result<checkpoint::BatchCheckpoint> {
+        let mut types = Vec::new();
+        for element_type in ElementType::ALL {
+            let batches = &self.type_batches[&element_type];
+            types.push(checkpoint::TypeCheckpoint {
                 element_type,
-                &current_batches[&element_key],
-                batch_counts[&element_key],
-                import_dir,
-                input_file,
-                import_type,
-                &root_element_info,
-            )
-            .await?;
-            *batch_counts.get_mut(&element_key).unwrap() += 1;
+                batch_count: batches.batch_count,
+                index_records: 0,
+                last_batched_id: batches.last_batched_id,
+                pending: batches.current.clone(),
+            });
         }
 
-        let input_filename = Path::new(input_file).file_name().unwrap().to_str().unwrap();
-        let completion_file = format!(
-            "{}/batches/{}/{}.batches_complete",
-            import_dir, element_type, input_filename
-        );
-        let completion_message = format!(
-            "wrote {} batches from {}\n",
-            batch_counts[&element_key], input_filename
-        );
-        fs::write(&completion_file, &completion_message).await?;
+        let spatial_journal_len = match self.spatial_index_builder.as_mut() {
+            Some(spatial_index_builder) => spatial_index_builder.checkpoint()?,
+            None => 0,
+        };
+        let quality_journal_len = match self.quality_report_builder.as_mut() {
+            Some(quality_report_builder) => quality_report_builder.checkpoint()?,
+            None => 0,
+        };
+        let tag_journal_len = self
+            .tag_statistics_builder
+            .as_mut()
+            .map(|tag_statistics_builder| tag_statistics_builder.checkpoint())
+            .transpose()?;
+        Ok(checkpoint::BatchCheckpoint {
+            input_size,
+            position,
+            elements_processed: self.total_elements_processed,
+            spatial_journal_len,
+            quality_journal_len,
+            tag_journal_len,
+            types,
+        })
     }
+}
 
-    Ok(())
+/// Indexes written batches in the order they were cut, whatever order the writers
+/// finish in.
+struct BatchIndexer {
+    index_builders: HashMap<ElementType, index::IndexBuilder>,
+    written: std::collections::BTreeMap<u64, WrittenBatch>,
+    next_sequence: u64,
+    checkpoint_file: String,
 }
 
-async fn write_batch(
-    element_type: &str,
-    elements: &[String],
-    batch_number: usize,
-    import_dir: &str,
+impl BatchIndexer {
+    async fn add(&mut self, written: WrittenBatch) -> Result<()> {
+        self.written.insert(written.sequence, written);
+
+        while let Some(written) = self.written.remove(&self.next_sequence) {
+            self.index_builders
+                .get_mut(&written.element_type)
+                .unwrap()
+                .add_batch(written.batch_number, &written.index_records)
+                .await?;
+            self.next_sequence += 1;
+
+            if let Some(mut checkpoint) = written.checkpoint {
+                for progress in &mut checkpoint.types {
+                    progress.index_records = self
+                        .index_builders
+                        .get_mut(&progress.element_type)
+                        .unwrap()
+                        .checkpoint()
+                        .await?;
+                }
+                checkpoint.write(&self.checkpoint_file).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct BatchingState {
+    osm_reader: reader::OsmReader<utils::InputStream>,
+    type_batches: HashMap<ElementType, TypeBatches>,
+    index_builders: HashMap<ElementType, index::IndexBuilder>,
+    spatial_index_builder: spatial::SpatialIndexBuilder,
+    quality_report_builder: quality::QualityReportBuilder,
+    tag_statistics_builder: Option<tag_stats::TagStatisticsBuilder>,
+    total_elements_processed: usize,
+}
+
+async fn resume_batching(
+    checkpoint: checkpoint::BatchCheckpoint,
+    root: reader::RootElementInfo,
+    element_output: reader::ElementOutput,
     input_file: &str,
-    _import_type: &str,
-    root_info: &RootElementInfo,
-) -> Result<()> {
-    let input_filename = Path::new(input_file).file_name().unwrap().to_str().unwrap();
-    let extension = ".xml";
-    let batch_filename = format!("{}.batch_{:06}{}", input_filename, batch_number, extension);
-    let batch_path = format!("{}/batches/{}/{}", import_dir, element_type, batch_filename);
-    let temp_path = format!("{}.temp", batch_path);
+    batches_dir: &str,
+    journal_file: &str,
+    input_kind: quality::InputKind,
+) -> Result<BatchingState> {
+    let input_filename = &utils::uncompressed_filename(input_file)?;
+    let mut type_batches = HashMap::new();
+    let mut index_builders = HashMap::new();
+    for progress in checkpoint.types {
+        let dir_path = format!("{}/{}", batches_dir, progress.element_type);
+        let index_path = format!("{}/{}.index", dir_path, input_filename);
+        let ranges_path = format!("{}/{}.batch_ranges", dir_path, input_filename);
+        let index_builder =
+            index::IndexBuilder::resume(&index_path, &ranges_path, progress.index_records).await?;
+        index_builders.insert(progress.element_type, index_builder);
+        type_batches.insert(
+            progress.element_type,
+            TypeBatches {
+                batch_count: progress.batch_count,
+                current: progress.pending,
+                last_batched_id: progress.last_batched_id,
+            },
+        );
+    }
+    for element_type in ElementType::ALL {
+        if !type_batches.contains_key(&element_type) {
+            anyhow::bail!("Checkpoint has no {} progress", element_type);
+        }
+    }
 
-    let mut content = String::new();
-    content.push_str("<?xml version='1.0' encoding='UTF-8'?>\n");
+    let spatial_index_builder =
+        spatial::SpatialIndexBuilder::resume(journal_file, checkpoint.spatial_journal_len)?;
+    let quality_journal_file = format!("{}/{}.quality_journal", batches_dir, input_filename);
+    let quality_report_builder = quality::QualityReportBuilder::resume(
+        input_kind,
+        &quality_journal_file,
+        checkpoint.quality_journal_len,
+    )?;
+    let tag_statistics_builder = match (tag_stats::enabled(), checkpoint.tag_journal_len) {
+        (true, Some(tag_journal_len)) => Some(tag_stats::TagStatisticsBuilder::resume(
+            &format!("{}/{}.tag_journal", batches_dir, input_filename),
+            tag_journal_len,
+        )?),
+        (true, None) => anyhow::bail!("Checkpoint was written without tag statistics"),
+        (false, _) => None,
+    };
+
+    // Compressed input is decompressed again up to the checkpoint.
+    let input_path = input_file.to_string();
+    let offset = checkpoint.position.offset;
+    let input =
+        tokio::task::spawn_blocking(move || utils::open_input(&input_path, offset)).await??;
+    let osm_reader =
+        reader::OsmReader::resume(input, root, &checkpoint.position).with_output(element_output);
+
+    info!(
+        "♻️ Resuming batching of {} after {} elements",
+        input_filename, checkpoint.elements_processed
+    );
+    Ok(BatchingState {
+        osm_reader,
+        type_batches,
+        index_builders,
+        spatial_index_builder,
+        quality_report_builder,
+        tag_statistics_builder,
+        total_elements_processed: checkpoint.elements_processed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("osm-import-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn write_test_input(path: &str) {
+        let mut xml =
+            String::from("<?xml version='1.0' encoding='UTF-8'?>\n<osm version=\"0.6\">\n");
+        for id in 1..=500 {
+            xml.push_str(&format!(
+                "<node id=\"{}\" version=\"1\" timestamp=\"2024-01-01T00:00:00Z\" lat=\"23.{:04}\" lon=\"90.{:04}\"><tag k=\"name\" v=\"n{}\"/></node>\n",
+                id, id, id, id
+            ));
+        }
+        for id in 1..=120 {
+            xml.push_str(&format!(
+                "<way id=\"{}\" version=\"1\" timestamp=\"2024-01-01T00:00:00Z\"><nd ref=\"{}\"/><nd ref=\"{}\"/></way>\n",
+                id,
+                id,
+                id + 1
+            ));
+        }
+        for id in 1..=30 {
+            xml.push_str(&format!(
+                "<relation id=\"{}\" version=\"1\" timestamp=\"2024-01-01T00:00:00Z\"><member type=\"way\" ref=\"{}\" role=\"\"/></relation>\n",
+                id, id
+            ));
+        }
+        xml.push_str("</osm>\n");
+        std::fs::write(path, xml).unwrap();
+    }
+
+    /// Every file below `dir` with its content, by relative path.
+    fn files(dir: &str) -> Vec<(String, Vec<u8>)> {
+        let mut files = Vec::new();
+        let mut pending = vec![std::path::PathBuf::from(dir)];
+        while let Some(current) = pending.pop() {
+            for entry in std::fs::read_dir(&current).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    let relative = path
+                        .strip_prefix(dir)
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned();
+                    files.push((relative, std::fs::read(&path).unwrap()));
+                }
+            }
+        }
+        files.sort();
+        files
+    }
 
-    content.push_str(&format!("<{}", root_info.tag));
-    for (key, value) in &root_info.attributes {
-        let escaped_value = value
-            .replace("&", "&amp;")
-            .replace("\"", "&quot;")
-            .replace("<", "&lt;")
-            .replace(">", "&gt;");
-        content.push_str(&format!(" {}=\"{}\"", key, escaped_value));
+    #[test]
+    fn full_dates_map_to_extract_names() {
+        let date = FullDate::new("2024-02-29".to_string()).unwrap();
+        assert_eq!(date.as_str(), "240229");
+        assert_eq!(date.date(), NaiveDate::from_ymd_opt(2024, 2, 29).unwrap());
+        assert_eq!(
+            FullDate::new("1999-12-31".to_string()).unwrap().as_str(),
+            "991231"
+        );
     }
-    content.push_str(">\n");
 
-    for element in elements {
-        content.push_str(element);
-        content.push('\n');
+    #[test]
+    fn rejects_invalid_full_dates() {
+        let error = |date: &str| FullDate::new(date.to_string()).unwrap_err();
+        assert!(error("250901").contains("expected YYYY-MM-DD"));
+        assert!(error("2025-02-30").contains("not a calendar date"));
+        assert!(error("2023-02-29").contains("not a calendar date"));
+        assert!(error("latest").contains("only supported for full imports"));
+
+        let tomorrow = Utc::now().date_naive() + Days::new(1);
+        assert!(error(&tomorrow.to_string()).contains("in the future"));
+        let century_ago = Utc::now().date_naive() - Months::new(100 * 12);
+        assert!(error(&century_ago.to_string()).contains("last 100 years"));
+        assert!(FullDate::new((century_ago + Days::new(1)).to_string()).is_ok());
     }
 
-    content.push_str(&format!("</{}>\n", root_info.tag));
+    #[test]
+    fn delta_sequences_map_to_abc_paths() {
+        let abc = |sequence| DeltaAbc::from_sequence(sequence).unwrap();
+        assert_eq!(abc(0).as_str(), "000/000/000");
+        assert_eq!(abc(4_321).as_str(), "000/004/321");
+        assert_eq!(abc(6_543_210).as_underscore(), "006_543_210");
+        assert_eq!(abc(999_999_999).as_str(), "999/999/999");
+        assert!(DeltaAbc::from_sequence(1_000_000_000)
+            .unwrap_err()
+            .contains("out of range"));
+        assert_eq!(
+            DeltaAbc::new(abc(6_543_210).as_str().to_string())
+                .unwrap()
+                .as_str(),
+            "006/543/210"
+        );
+    }
 
-    fs::write(&temp_path, content).await?;
+    #[test]
+    fn providers_without_an_archive_only_accept_recent_dates() {
+        let today = FullDate::from_date(Utc::now().date_naive()).unwrap();
+        let yesterday = FullDate::from_date(Utc::now().date_naive() - Days::new(1)).unwrap();
+        let geofabrik = provider::Geofabrik::new("asia/bangladesh").unwrap();
+        let bbbike = provider::BBBike::new("Dhaka").unwrap();
+
+        assert!(yesterday.check_published_by(&geofabrik).is_ok());
+        assert!(today.check_published_by(&bbbike).is_ok());
+        let error = yesterday.check_published_by(&bbbike).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidArgument);
+        assert!(error
+            .message
+            .contains("only publishes today's full extract"));
+    }
 
-    fs::rename(&temp_path, &batch_path).await?;
+    #[test]
+    fn time_windows_filter_by_timestamp() {
+        let window = |since: Option<&str>, until: Option<&str>| {
+            TimeWindow::new(since.map(str::to_string), until.map(str::to_string))
+        };
+        assert!(window(Some("2024-01-01"), None)
+            .unwrap_err()
+            .contains("expected YYYY-MM-DDThh:mm:ssZ"));
+        assert!(
+            window(Some("2024-02-01T00:00:00Z"), Some("2024-01-01T00:00:00Z"))
+                .unwrap_err()
+                .contains("is after")
+        );
 
-    Ok(())
-}
+        let unbounded = window(None, None).unwrap();
+        assert!(unbounded.is_unbounded());
+        assert!(unbounded.contains("2007-10-01T12:00:00Z"));
+
+        let since = window(Some("2024-01-01T00:00:00Z"), None).unwrap();
+        assert!(since.contains("2024-01-01T00:00:00Z"));
+        assert!(!since.contains("2023-12-31T23:59:59Z"));
+        assert_eq!(since.as_scope_suffix(), "20240101T000000Z_end");
+
+        let bounded = window(Some("2024-01-01T00:00:00Z"), Some("2024-12-31T23:59:59Z")).unwrap();
+        assert!(bounded.contains("2024-12-31T23:59:59Z"));
+        assert!(!bounded.contains("2025-01-01T00:00:00Z"));
+
+        // Every window of one date batches the same date-keyed file into its own directory.
+        let date = FullDate::new("2025-09-01".to_string()).unwrap();
+        let options = ImportOptions {
+            osm_file_type: OsmFileType::History(date, since),
+            base_path: String::new(),
+        };
+        assert_eq!(options.get_import_scope(), "250901_20240101T000000Z_end");
+        assert_eq!(options.get_filename_base(), "250901.osh");
+    }
 
-fn parse_root_element(xml_content: &str) -> Result<RootElementInfo> {
-    let mut reader = Reader::from_str(xml_content);
-    reader.config_mut().trim_text(true);
-    let mut buf = Vec::new();
+    #[tokio::test]
+    async fn history_versions_stay_in_one_batch() {
+        let input = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+<node id="1" version="1" timestamp="2023-06-01T00:00:00Z" lat="23.1" lon="90.1"/>
+<node id="1" version="2" timestamp="2024-02-01T00:00:00Z" lat="23.1" lon="90.2"/>
+<node id="1" version="3" timestamp="2024-03-01T00:00:00Z" visible="false"/>
+<node id="2" version="1" timestamp="2024-04-01T00:00:00Z" lat="23.2" lon="90.2"/>
+<node id="2" version="2" timestamp="2024-05-01T00:00:00Z" lat="23.2" lon="90.3"/>
+<node id="3" version="1" timestamp="2024-06-01T00:00:00Z" lat="23.3" lon="90.3"/>
+<node id="4" version="1" timestamp="2025-01-01T00:00:00Z" lat="23.4" lon="90.4"/>
+</osm>
+"#;
+        let batch_ids = |time_window: TimeWindow| async move {
+            let sink = MemorySink::default();
+            let options = BatchOptions {
+                elements_per_batch: 1,
+                history: true,
+                time_window,
+                ..Default::default()
+            };
+            batch_reader(
+                input.as_bytes(),
+                InputFormat::Xml,
+                &options,
+                &sink,
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+            sink.into_batches()
+                .iter()
+                .map(|batch| {
+                    let id_regex = Regex::new(r#"<node id="(\d+)" version="(\d+)""#).unwrap();
+                    id_regex
+                        .captures_iter(&batch.content)
+                        .map(|captures| format!("{}v{}", &captures[1], &captures[2]))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            batch_ids(TimeWindow::default()).await,
+            [
+                vec!["1v1", "1v2", "1v3"],
+                vec!["2v1", "2v2"],
+                vec!["3v1"],
+                vec!["4v1"]
+            ]
+        );
+        let window = TimeWindow::new(
+            Some("2024-01-01T00:00:00Z".to_string()),
+            Some("2024-12-31T23:59:59Z".to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            batch_ids(window).await,
+            [vec!["1v2", "1v3"], vec!["2v1", "2v2"], vec!["3v1"]]
+        );
+    }
 
-    loop {
-        match reader.read_event_into(&mut buf) {
-            Ok(Event::Start(ref e)) => {
-                let tag_name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
-
-                if tag_name == "osm" || tag_name == "osmChange" {
-                    let mut attributes = std::collections::HashMap::new();
-
-                    for attr in e.attributes() {
-                        let attr = attr?;
-                        let key = std::str::from_utf8(attr.key.as_ref())?.to_string();
-                        let value = std::str::from_utf8(&attr.value)?.to_string();
-                        attributes.insert(key, value);
-                    }
+    #[test]
+    fn only_provider_extracts_are_checked_for_publication() {
+        let bbbike = provider::BBBike::new("Dhaka").unwrap();
+        let today = FullDate::from_date(Utc::now().date_naive()).unwrap();
+        let old = FullDate::new("2012-09-12".to_string()).unwrap();
+        let options = |osm_file_type| ImportOptions {
+            osm_file_type,
+            base_path: String::new(),
+        };
+
+        // History files come from HISTORY_PBF_URL_TEMPLATE, not the provider.
+        let history = options(OsmFileType::History(old.clone(), TimeWindow::default()));
+        assert!(history.check_published_by(&bbbike).is_ok());
+        assert!(options(OsmFileType::Full(today.clone()))
+            .check_published_by(&bbbike)
+            .is_ok());
+        assert!(options(OsmFileType::Full(old.clone()))
+            .check_published_by(&bbbike)
+            .is_err());
+        assert!(options(OsmFileType::Diff(old, today))
+            .check_published_by(&bbbike)
+            .is_err());
+    }
 
-                    let current_generator =
-                        attributes.get("generator").cloned().unwrap_or_default();
-                    attributes.insert(
-                        "generator".to_string(),
-                        format!("Chaldal osm-import-rust; {}", current_generator),
-                    );
-
-                    return Ok(RootElementInfo {
-                        tag: tag_name,
-                        attributes,
-                    });
-                }
-            }
-            Ok(Event::Eof) => break,
-            Err(e) => anyhow::bail!("XML parsing error while finding root element: {}", e),
-            _ => {}
+    #[tokio::test]
+    async fn batching_output_does_not_depend_on_the_writer_count() {
+        let dir = test_dir("writers");
+        let input_file = format!("{}/input.osm", dir);
+        write_test_input(&input_file);
+
+        let mut outputs = Vec::new();
+        for writers in [1, 8] {
+            let import_dir = format!("{}/import-{}", dir, writers);
+            batch_osm_xml(
+                &input_file,
+                &import_dir,
+                &BatchPlan {
+                    writer_count: writers,
+                    ..BatchPlan::new("full", 7)
+                },
+                &header::BatchProvenance::new("test"),
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+            outputs.push(files(&import_dir));
         }
-        buf.clear();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(outputs[0].len() > 100);
+        assert_eq!(outputs[0], outputs[1]);
     }
 
-    anyhow::bail!("Could not find root element (osm or osmChange)")
+    #[tokio::test]
+    async fn bbox_queries_stop_at_the_limit() {
+        let dir = test_dir("bbox");
+        let source_file = format!("{}/source.osm", dir);
+        write_test_input(&source_file);
+        let source = source::prepare_custom_source(&source_file, &CancellationToken::new())
+            .await
+            .unwrap();
+        let import_options = ImportOptions {
+            osm_file_type: OsmFileType::Custom(source),
+            base_path: dir.clone(),
+        };
+        let import_dir = import_options.get_import_dir();
+        std::fs::create_dir_all(&import_dir).unwrap();
+        let input_file = format!("{}/{}", import_dir, import_options.get_filename_base());
+        std::fs::copy(&source_file, &input_file).unwrap();
+        batch_osm_xml(
+            &input_file,
+            &import_dir,
+            &BatchPlan::new("full", 7),
+            &header::BatchProvenance::new("test"),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        let bbox = BoundingBox::new(90.0, 23.0, 90.1, 23.1).unwrap();
+        let types = [ElementType::Node, ElementType::Way];
+        let mut results = Vec::new();
+        for max_results in [0, 3, 500, 620, 1000] {
+            match query_bbox(&import_options, &bbox, &types, max_results).await {
+                BboxQueryStatus::ElementsFound {
+                    elements,
+                    truncated,
+                } => results.push((elements.len(), truncated)),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // 500 nodes and 120 ways lie in the box.
+        assert_eq!(
+            results,
+            [
+                (0, true),
+                (3, true),
+                (500, true),
+                (620, false),
+                (620, false)
+            ]
+        );
+    }
 }
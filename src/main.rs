@@ -1,9 +1,25 @@
+mod cli;
+
+use cli::{BatchArgs, CleanTarget, Command, ImportTarget};
 use osm_import_rust::{
-    self, check_batch_file_status, BatchFileStatus, DeltaAbc, FullDate, ImportOptions, OsmFileType,
+    self, apply_retention_policy, batch_reader, cancel_import, check_batch_file_status,
+    delete_import, find_element, get_quality_report, get_tag_statistics, import_status,
+    list_imports, prepare_custom_source, prewarm, process_osm_import, provider_from_env,
+    query_bbox, resolve_custom_source, resolve_full_date, start_import, BatchFileStatus,
+    BatchHeaderConfig, BatchOptions, BatchProvenance, BboxQueryStatus, BoundingBox,
+    CancelImportStatus, CustomSourceStatus, DeleteImportStatus, DeltaAbc, DirectorySink,
+    ElementLookupStatus, ElementOutput, ElementType, ErrorCode, FullDate, ImportOptions,
+    ImportPriority, ImportQueueStatus, InputErrorCode, OsmFileType, PrewarmTarget, QualityReport,
+    QualityReportStatus, RetentionPolicy, Schedule, ServiceError, StartImportStatus, Storage,
+    TagCount, TagStatistics, TagStatisticsStatus, TimeWindow, TypeCounts,
 };
+use prost::Message;
 use std::env;
-use tonic::{transport::Server, Request, Response, Status};
-use tracing::info;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+use tonic::{transport::Server, Code, Request, Response, Status};
+use tracing::{error, info};
 
 pub mod osm_import {
     tonic::include_proto!("osm_import");
@@ -11,27 +27,254 @@ pub mod osm_import {
 
 use osm_import::osm_import_server::{OsmImport, OsmImportServer};
 use osm_import::{
-    fetch_import_batch_request::ImportType, fetch_import_batch_response::Response as BatchResponse,
-    FetchImportBatchRequest, FetchImportBatchResponse, PingRequest, PingResponse,
+    cancel_import_response::Response as CancelResponse,
+    delete_import_response::Response as DeleteResponse, fetch_import_batch_request::ImportType,
+    fetch_import_batch_response::Response as BatchResponse,
+    get_element_response::Response as ElementResponse,
+    get_import_status_response::Response as StatusResponse,
+    get_quality_report_response::Response as QualityResponse,
+    get_tag_statistics_response::Response as TagResponse, import_reference,
+    query_bbox_response::Response as BboxResponse,
+    start_import_response::Response as StartResponse, BboxElements, CancelImportRequest,
+    CancelImportResponse, DeleteImportRequest, DeleteImportResponse, Element,
+    FetchImportBatchRequest, FetchImportBatchResponse, GetElementRequest, GetElementResponse,
+    GetImportStatusRequest, GetImportStatusResponse, GetQualityReportRequest,
+    GetQualityReportResponse, GetTagStatisticsRequest, GetTagStatisticsResponse, ImportInfo,
+    ImportReference, ListImportsRequest, ListImportsResponse, PingRequest, PingResponse,
+    QueryBboxRequest, QueryBboxResponse, StartImportRequest, StartImportResponse,
 };
 
-fn get_import_options(import_type: Option<ImportType>) -> Result<ImportOptions, String> {
-    match import_type {
+const DEFAULT_BBOX_MAX_RESULTS: usize = 10_000;
+const DEFAULT_TAG_STATISTICS_LIMIT: usize = 50;
+
+/// `None` while a custom source is still being downloaded or hashed. Only StartImport
+/// resolves `latest`, once; every other request addresses an import by its date.
+async fn get_import_options(
+    import_type: Option<ImportType>,
+    resolve_latest: bool,
+) -> Result<Option<ImportOptions>, ServiceError> {
+    let options = match import_type {
         Some(ImportType::FullDate(date)) => {
-            let validated_date = FullDate::new(date)?;
-            Ok(ImportOptions {
+            if date == "latest" && !resolve_latest {
+                return Err(ServiceError::invalid_argument(
+                    "full_date latest is only accepted by StartImport, which returns the date it resolved to",
+                ));
+            }
+            let validated_date = resolve_full_date(date).await?;
+            ImportOptions {
                 osm_file_type: OsmFileType::Full(validated_date),
                 base_path: "./data/".to_string(),
-            })
+            }
         }
         Some(ImportType::DeltaAbc(abc)) => {
-            let validated_abc = DeltaAbc::new(abc)?;
-            Ok(ImportOptions {
+            let validated_abc = DeltaAbc::new(abc).map_err(ServiceError::invalid_argument)?;
+            ImportOptions {
                 osm_file_type: OsmFileType::Delta(validated_abc),
                 base_path: "./data/".to_string(),
-            })
+            }
+        }
+        Some(ImportType::History(history)) => {
+            let validated_date =
+                FullDate::new(history.date).map_err(ServiceError::invalid_argument)?;
+            let non_empty = |value: String| (!value.is_empty()).then_some(value);
+            let window = TimeWindow::new(non_empty(history.since), non_empty(history.until))
+                .map_err(ServiceError::invalid_argument)?;
+            ImportOptions {
+                osm_file_type: OsmFileType::History(validated_date, window),
+                base_path: "./data/".to_string(),
+            }
+        }
+        Some(ImportType::Diff(diff)) => {
+            let from_date =
+                FullDate::new(diff.from_date).map_err(ServiceError::invalid_argument)?;
+            let to_date = FullDate::new(diff.to_date).map_err(ServiceError::invalid_argument)?;
+            if from_date.as_str() == to_date.as_str() {
+                return Err(ServiceError::invalid_argument("diff dates must differ"));
+            }
+            ImportOptions {
+                osm_file_type: OsmFileType::Diff(from_date, to_date),
+                base_path: "./data/".to_string(),
+            }
         }
-        None => Err("import type is unknown".to_string()),
+        Some(ImportType::Source(location)) => match resolve_custom_source(&location).await {
+            CustomSourceStatus::SourceReady(source) => ImportOptions {
+                osm_file_type: OsmFileType::Custom(source),
+                base_path: "./data/".to_string(),
+            },
+            CustomSourceStatus::SourcePreparing => return Ok(None),
+            CustomSourceStatus::SourceError(e) => return Err(e),
+        },
+        None => return Err(ServiceError::invalid_argument("import type is unknown")),
+    };
+    Ok(Some(options))
+}
+
+fn get_priority(backfill: bool) -> ImportPriority {
+    if backfill {
+        ImportPriority::Backfill
+    } else {
+        ImportPriority::Interactive
+    }
+}
+
+async fn get_referenced_import_options(
+    import_reference: Option<ImportReference>,
+    resolve_latest: bool,
+) -> Result<Option<ImportOptions>, ServiceError> {
+    let import_type = import_reference
+        .and_then(|reference| reference.import_type)
+        .map(|import_type| match import_type {
+            import_reference::ImportType::FullDate(date) => ImportType::FullDate(date),
+            import_reference::ImportType::DeltaAbc(abc) => ImportType::DeltaAbc(abc),
+            import_reference::ImportType::History(history) => ImportType::History(history),
+            import_reference::ImportType::Diff(diff) => ImportType::Diff(diff),
+            import_reference::ImportType::Source(location) => ImportType::Source(location),
+        });
+    get_import_options(import_type, resolve_latest).await
+}
+
+fn get_element_type(value: i32) -> Result<ElementType, Status> {
+    match osm_import::ElementType::try_from(value) {
+        Ok(osm_import::ElementType::Node) => Ok(ElementType::Node),
+        Ok(osm_import::ElementType::Way) => Ok(ElementType::Way),
+        Ok(osm_import::ElementType::Relation) => Ok(ElementType::Relation),
+        Ok(osm_import::ElementType::Unspecified) => {
+            Err(invalid_argument("element_type is required"))
+        }
+        Err(_) => Err(invalid_argument(format!("Invalid element type: {}", value))),
+    }
+}
+
+/// Every element type when none are given.
+fn get_element_types(values: &[i32]) -> Result<Vec<ElementType>, Status> {
+    if values.is_empty() {
+        return Ok(ElementType::ALL.to_vec());
+    }
+    values
+        .iter()
+        .map(|&value| get_element_type(value))
+        .collect()
+}
+
+fn to_proto_element_type(element_type: ElementType) -> i32 {
+    let element_type = match element_type {
+        ElementType::Node => osm_import::ElementType::Node,
+        ElementType::Way => osm_import::ElementType::Way,
+        ElementType::Relation => osm_import::ElementType::Relation,
+    };
+    element_type as i32
+}
+
+fn to_proto_invalid_input(invalid: osm_import_rust::InvalidInput) -> osm_import::InvalidInput {
+    let code = match invalid.code {
+        InputErrorCode::EmptyFile => osm_import::InputErrorCode::EmptyFile,
+        InputErrorCode::HtmlPage => osm_import::InputErrorCode::HtmlPage,
+        InputErrorCode::UnknownFormat => osm_import::InputErrorCode::UnknownFormat,
+        InputErrorCode::InvalidPbfHeader => osm_import::InputErrorCode::InvalidPbfHeader,
+        InputErrorCode::TruncatedFile => osm_import::InputErrorCode::TruncatedFile,
+        InputErrorCode::CorruptCompression => osm_import::InputErrorCode::CorruptCompression,
+        InputErrorCode::MalformedXml => osm_import::InputErrorCode::MalformedXml,
+        InputErrorCode::UnexpectedRoot => osm_import::InputErrorCode::UnexpectedRoot,
+        InputErrorCode::UnsortedIds => osm_import::InputErrorCode::UnsortedIds,
+    };
+    osm_import::InvalidInput {
+        code: code as i32,
+        message: invalid.message,
+    }
+}
+
+fn to_proto_error(error: ServiceError) -> osm_import::Error {
+    let code = match error.code {
+        ErrorCode::InvalidArgument => osm_import::ErrorCode::InvalidArgument,
+        ErrorCode::UpstreamNotFound => osm_import::ErrorCode::UpstreamNotFound,
+        ErrorCode::UpstreamUnavailable => osm_import::ErrorCode::UpstreamUnavailable,
+        ErrorCode::ConversionFailed => osm_import::ErrorCode::ConversionFailed,
+        ErrorCode::ParseFailed => osm_import::ErrorCode::ParseFailed,
+        ErrorCode::StorageError => osm_import::ErrorCode::StorageError,
+        ErrorCode::ImportFailed => osm_import::ErrorCode::ImportFailed,
+        ErrorCode::ChecksumMismatch => osm_import::ErrorCode::ChecksumMismatch,
+    };
+    osm_import::Error {
+        code: code as i32,
+        message: error.message,
+        details: error.details.into_iter().collect(),
+    }
+}
+
+/// Bad requests and storage failures fail the call, with the `Error` as status details;
+/// failures of the import itself are answered in the response.
+fn report_error(error: ServiceError) -> Result<osm_import::Error, Status> {
+    let code = match error.code {
+        ErrorCode::InvalidArgument => Code::InvalidArgument,
+        ErrorCode::StorageError => Code::Internal,
+        _ => return Ok(to_proto_error(error)),
+    };
+    Err(error_status(code, error))
+}
+
+fn error_status(code: Code, error: ServiceError) -> Status {
+    let message = error.message.clone();
+    Status::with_details(code, message, to_proto_error(error).encode_to_vec().into())
+}
+
+fn invalid_argument(message: impl Into<String>) -> Status {
+    error_status(
+        Code::InvalidArgument,
+        ServiceError::invalid_argument(message),
+    )
+}
+
+fn to_proto_quality_report(report: QualityReport) -> osm_import::QualityReport {
+    osm_import::QualityReport {
+        nodes: report.nodes,
+        ways: report.ways,
+        relations: report.relations,
+        checks: report
+            .checks
+            .into_iter()
+            .map(|result| osm_import::QualityCheckResult {
+                check: result.check.as_str().to_string(),
+                count: result.count,
+                samples: result
+                    .samples
+                    .into_iter()
+                    .map(|issue| osm_import::QualityIssue {
+                        element_type: to_proto_element_type(issue.element_type),
+                        id: issue.id,
+                        detail: issue.detail,
+                    })
+                    .collect(),
+            })
+            .collect(),
+    }
+}
+
+fn to_proto_tag_statistics(statistics: TagStatistics) -> osm_import::TagStatistics {
+    let to_proto_counts = |counts: TypeCounts| osm_import::TypeCounts {
+        nodes: counts.nodes,
+        ways: counts.ways,
+        relations: counts.relations,
+    };
+    let to_proto_tag_count = |tag_count: TagCount| osm_import::TagCount {
+        text: tag_count.text,
+        count: tag_count.counts.total(),
+        counts: Some(to_proto_counts(tag_count.counts)),
+        distinct_values: tag_count.distinct_values,
+        values_capped: tag_count.values_capped,
+    };
+    osm_import::TagStatistics {
+        elements: Some(to_proto_counts(statistics.elements)),
+        tagged_elements: Some(to_proto_counts(statistics.tagged_elements)),
+        keys: statistics
+            .keys
+            .into_iter()
+            .map(to_proto_tag_count)
+            .collect(),
+        values: statistics
+            .values
+            .into_iter()
+            .map(to_proto_tag_count)
+            .collect(),
     }
 }
 
@@ -51,31 +294,57 @@ impl OsmImport for OSMImportService {
         request: Request<FetchImportBatchRequest>,
     ) -> Result<Response<FetchImportBatchResponse>, Status> {
         let req: FetchImportBatchRequest = request.into_inner();
+        let element_type = get_element_type(req.element_type)?;
 
-        match get_import_options(req.import_type) {
+        match get_import_options(req.import_type, false).await {
             Err(e) => Ok(Response::new(FetchImportBatchResponse {
-                response: Some(BatchResponse::Error(e)),
+                response: Some(BatchResponse::Error(report_error(e)?)),
             })),
-            Ok(options) => {
+            Ok(None) => Ok(Response::new(FetchImportBatchResponse {
+                response: Some(BatchResponse::BatchesPending(
+                    "Preparing source".to_string(),
+                )),
+            })),
+            Ok(Some(options)) => {
                 let batch_status =
-                    check_batch_file_status(&options, &req.element_type, req.batch_number as usize)
+                    check_batch_file_status(&options, element_type, req.batch_number as usize)
                         .await;
 
-                let (should_attempt_import, response) = match batch_status {
+                let (should_attempt_import, mut response) = match batch_status {
                     BatchFileStatus::FileReadSuccessfully(content) => {
                         (false, BatchResponse::BatchContent(content))
                     }
-                    BatchFileStatus::FileReadError(error) => (false, BatchResponse::Error(error)),
+                    BatchFileStatus::FileReadError(error) => {
+                        (false, BatchResponse::Error(report_error(error)?))
+                    }
                     BatchFileStatus::FileWillNeverExist => {
                         (false, BatchResponse::BatchesComplete("".to_string()))
                     }
                     BatchFileStatus::FileDoesNotExistYet => {
                         (true, BatchResponse::BatchesPending("".to_string()))
                     }
+                    // Retried only through StartImport, so clients keep seeing the cause.
+                    BatchFileStatus::InputInvalid(invalid) => (
+                        false,
+                        BatchResponse::InvalidInput(to_proto_invalid_input(invalid)),
+                    ),
+                    // Other failures may be transient, so the import is attempted again.
+                    BatchFileStatus::ImportFailed(error) => {
+                        (true, BatchResponse::Error(report_error(error)?))
+                    }
                 };
 
                 if should_attempt_import {
-                    osm_import_rust::maybe_start_background_processing(options).await;
+                    match options.check_published() {
+                        Ok(()) => {
+                            osm_import_rust::maybe_start_background_processing(
+                                options,
+                                get_priority(req.backfill),
+                            )
+                            .await
+                        }
+                        Err(e) => response = BatchResponse::Error(report_error(e)?),
+                    }
                 }
 
                 Ok(Response::new(FetchImportBatchResponse {
@@ -84,23 +353,371 @@ impl OsmImport for OSMImportService {
             }
         }
     }
+
+    async fn get_element(
+        &self,
+        request: Request<GetElementRequest>,
+    ) -> Result<Response<GetElementResponse>, Status> {
+        let req: GetElementRequest = request.into_inner();
+        let element_type = get_element_type(req.element_type)?;
+
+        let response = match get_referenced_import_options(req.import, false).await {
+            Err(e) => ElementResponse::Error(report_error(e)?),
+            Ok(None) => ElementResponse::IndexPending("Preparing source".to_string()),
+            Ok(Some(options)) => match find_element(&options, element_type, req.id).await {
+                ElementLookupStatus::ElementFound {
+                    batch_number,
+                    content,
+                } => ElementResponse::Element(Element {
+                    batch_number: batch_number as i64,
+                    content,
+                    element_type: req.element_type,
+                    id: req.id,
+                }),
+                ElementLookupStatus::ElementNotFound => {
+                    ElementResponse::ElementNotFound("".to_string())
+                }
+                ElementLookupStatus::IndexNotReady => ElementResponse::IndexPending("".to_string()),
+                ElementLookupStatus::ElementReadError(error) => {
+                    ElementResponse::Error(report_error(error)?)
+                }
+            },
+        };
+
+        Ok(Response::new(GetElementResponse {
+            response: Some(response),
+        }))
+    }
+
+    async fn query_bbox(
+        &self,
+        request: Request<QueryBboxRequest>,
+    ) -> Result<Response<QueryBboxResponse>, Status> {
+        let req: QueryBboxRequest = request.into_inner();
+
+        let bbox = req
+            .bbox
+            .ok_or_else(|| "bbox is required".to_string())
+            .and_then(|b| BoundingBox::new(b.min_lon, b.min_lat, b.max_lon, b.max_lat))
+            .map_err(ServiceError::invalid_argument);
+        let element_types = get_element_types(&req.element_types)?;
+        let max_results = match req.max_results {
+            n if n > 0 => n as usize,
+            _ => DEFAULT_BBOX_MAX_RESULTS,
+        };
+
+        let response = match (get_referenced_import_options(req.import, false).await, bbox) {
+            (Err(e), _) | (_, Err(e)) => BboxResponse::Error(report_error(e)?),
+            (Ok(None), Ok(_)) => BboxResponse::IndexPending("Preparing source".to_string()),
+            (Ok(Some(options)), Ok(bbox)) => {
+                match query_bbox(&options, &bbox, &element_types, max_results).await {
+                    BboxQueryStatus::ElementsFound {
+                        elements,
+                        truncated,
+                    } => BboxResponse::Elements(BboxElements {
+                        elements: elements
+                            .into_iter()
+                            .map(|element| Element {
+                                batch_number: element.batch_number as i64,
+                                content: element.content,
+                                element_type: to_proto_element_type(element.element_type),
+                                id: element.id,
+                            })
+                            .collect(),
+                        truncated,
+                    }),
+                    BboxQueryStatus::IndexNotReady => BboxResponse::IndexPending("".to_string()),
+                    BboxQueryStatus::QueryError(error) => BboxResponse::Error(report_error(error)?),
+                }
+            }
+        };
+
+        Ok(Response::new(QueryBboxResponse {
+            response: Some(response),
+        }))
+    }
+
+    async fn list_imports(
+        &self,
+        _request: Request<ListImportsRequest>,
+    ) -> Result<Response<ListImportsResponse>, Status> {
+        let imports = list_imports().await.map_err(|e| {
+            error!("Failed to list imports: {}", e);
+            error_status(
+                Code::Internal,
+                ServiceError::storage("Failed to list imports"),
+            )
+        })?;
+
+        Ok(Response::new(ListImportsResponse {
+            imports: imports
+                .into_iter()
+                .map(|import| ImportInfo {
+                    import_type: import.import_type,
+                    scope: import.scope,
+                    locked: import.locked,
+                    batches_complete: import.batches_complete,
+                    size_bytes: import.size_bytes,
+                    last_modified_unix: import
+                        .last_modified
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs() as i64)
+                        .unwrap_or_default(),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn delete_import(
+        &self,
+        request: Request<DeleteImportRequest>,
+    ) -> Result<Response<DeleteImportResponse>, Status> {
+        let req: DeleteImportRequest = request.into_inner();
+
+        let response = match get_referenced_import_options(req.import, false).await {
+            Err(e) => DeleteResponse::Error(report_error(e)?),
+            Ok(None) => return Err(Status::unavailable("Source is still being prepared")),
+            Ok(Some(options)) => match delete_import(&options).await {
+                DeleteImportStatus::ImportDeleted => DeleteResponse::Deleted("".to_string()),
+                DeleteImportStatus::ImportLocked => DeleteResponse::ImportLocked("".to_string()),
+                DeleteImportStatus::ImportNotFound => {
+                    DeleteResponse::ImportNotFound("".to_string())
+                }
+                DeleteImportStatus::DeleteError(error) => {
+                    DeleteResponse::Error(report_error(error)?)
+                }
+            },
+        };
+
+        Ok(Response::new(DeleteImportResponse {
+            response: Some(response),
+        }))
+    }
+
+    async fn start_import(
+        &self,
+        request: Request<StartImportRequest>,
+    ) -> Result<Response<StartImportResponse>, Status> {
+        let req: StartImportRequest = request.into_inner();
+
+        let options = match get_referenced_import_options(req.import, true).await {
+            Ok(Some(options)) => options.check_published().map(|()| options),
+            Ok(None) => {
+                return Err(Status::unavailable(
+                    "Source is still being prepared; retry shortly",
+                ))
+            }
+            Err(e) => Err(e),
+        };
+        let (response, full_date) = match options {
+            Err(e) => (StartResponse::Error(report_error(e)?), String::new()),
+            Ok(options) => {
+                let full_date = match &options.osm_file_type {
+                    OsmFileType::Full(date) => date.date().to_string(),
+                    _ => String::new(),
+                };
+                let response = match start_import(options, get_priority(req.backfill)) {
+                    StartImportStatus::ImportStarted(handle) => StartResponse::ImportHandle(handle),
+                    StartImportStatus::ImportAlreadyRunning(handle) => {
+                        StartResponse::AlreadyRunning(handle)
+                    }
+                };
+                (response, full_date)
+            }
+        };
+
+        Ok(Response::new(StartImportResponse {
+            response: Some(response),
+            full_date,
+        }))
+    }
+
+    async fn cancel_import(
+        &self,
+        request: Request<CancelImportRequest>,
+    ) -> Result<Response<CancelImportResponse>, Status> {
+        let req: CancelImportRequest = request.into_inner();
+
+        if req.import_handle.is_empty() {
+            return Err(invalid_argument("import_handle is required"));
+        }
+
+        let response = match cancel_import(&req.import_handle) {
+            CancelImportStatus::CancelRequested => CancelResponse::CancelRequested("".to_string()),
+            CancelImportStatus::ImportNotRunning => {
+                CancelResponse::ImportNotRunning("".to_string())
+            }
+        };
+
+        Ok(Response::new(CancelImportResponse {
+            response: Some(response),
+        }))
+    }
+
+    async fn get_import_status(
+        &self,
+        request: Request<GetImportStatusRequest>,
+    ) -> Result<Response<GetImportStatusResponse>, Status> {
+        let req: GetImportStatusRequest = request.into_inner();
+
+        if req.import_handle.is_empty() {
+            return Err(invalid_argument("import_handle is required"));
+        }
+
+        let response = match import_status(&req.import_handle).await {
+            ImportQueueStatus::ImportRunning => StatusResponse::Running("".to_string()),
+            ImportQueueStatus::ImportQueued(position) => {
+                StatusResponse::QueuePosition(position as u32)
+            }
+            ImportQueueStatus::ImportNotQueued => StatusResponse::NotQueued("".to_string()),
+            ImportQueueStatus::ImportInvalid(invalid) => {
+                StatusResponse::InvalidInput(to_proto_invalid_input(invalid))
+            }
+            ImportQueueStatus::ImportFailed(error) => StatusResponse::Error(to_proto_error(error)),
+        };
+
+        Ok(Response::new(GetImportStatusResponse {
+            response: Some(response),
+        }))
+    }
+
+    async fn get_quality_report(
+        &self,
+        request: Request<GetQualityReportRequest>,
+    ) -> Result<Response<GetQualityReportResponse>, Status> {
+        let req: GetQualityReportRequest = request.into_inner();
+
+        let response = match get_referenced_import_options(req.import, false).await {
+            Err(e) => QualityResponse::Error(report_error(e)?),
+            Ok(None) => QualityResponse::ReportPending("Preparing source".to_string()),
+            Ok(Some(options)) => match get_quality_report(&options).await {
+                QualityReportStatus::ReportFound(report) => {
+                    QualityResponse::Report(to_proto_quality_report(report))
+                }
+                QualityReportStatus::ReportNotReady => {
+                    QualityResponse::ReportPending("".to_string())
+                }
+                QualityReportStatus::ReportNotFound => {
+                    return Err(Status::failed_precondition(
+                        "Import was batched without a quality report",
+                    ))
+                }
+                QualityReportStatus::ReportReadError(error) => {
+                    QualityResponse::Error(report_error(error)?)
+                }
+            },
+        };
+
+        Ok(Response::new(GetQualityReportResponse {
+            response: Some(response),
+        }))
+    }
+
+    async fn get_tag_statistics(
+        &self,
+        request: Request<GetTagStatisticsRequest>,
+    ) -> Result<Response<GetTagStatisticsResponse>, Status> {
+        let req: GetTagStatisticsRequest = request.into_inner();
+
+        let element_types = get_element_types(&req.element_types)?;
+        let key = Some(req.key.as_str()).filter(|key| !key.is_empty());
+        let limit = match req.limit {
+            n if n > 0 => n as usize,
+            _ => DEFAULT_TAG_STATISTICS_LIMIT,
+        };
+
+        let response = match get_referenced_import_options(req.import, false).await {
+            Err(e) => TagResponse::Error(report_error(e)?),
+            Ok(None) => TagResponse::StatisticsPending("Preparing source".to_string()),
+            Ok(Some(options)) => {
+                match get_tag_statistics(&options, &element_types, key, limit).await {
+                    TagStatisticsStatus::StatisticsFound(statistics) => {
+                        TagResponse::Statistics(to_proto_tag_statistics(statistics))
+                    }
+                    TagStatisticsStatus::StatisticsNotReady => {
+                        TagResponse::StatisticsPending("".to_string())
+                    }
+                    TagStatisticsStatus::StatisticsNotGathered => {
+                        return Err(Status::failed_precondition(
+                            "Import was batched without TAG_STATISTICS=1",
+                        ))
+                    }
+                    TagStatisticsStatus::StatisticsReadError(error) => {
+                        TagResponse::Error(report_error(error)?)
+                    }
+                }
+            }
+        };
+
+        Ok(Response::new(GetTagStatisticsResponse {
+            response: Some(response),
+        }))
+    }
+}
+
+async fn run_retention(policy: RetentionPolicy, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match apply_retention_policy(&policy).await {
+            Ok(report) => info!(
+                "🧹 Retention removed {} imports and {} intermediate files ({} bytes)",
+                report.imports_deleted, report.intermediates_removed, report.bytes_freed
+            ),
+            Err(e) => error!("💥 Retention run failed: {e}"),
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
-        )
-        .init();
+async fn run_prewarm(target: PrewarmTarget, schedule: Schedule) {
+    loop {
+        match schedule.until_next() {
+            Ok(delay) => tokio::time::sleep(delay).await,
+            Err(e) => {
+                error!("💥 No next run for {:?} pre-warming: {e}", target);
+                return;
+            }
+        }
+        match prewarm(target).await {
+            Ok(processed) => info!("⏰ Pre-warmed {} {:?} imports", processed, target),
+            Err(e) => error!("💥 {:?} pre-warming failed: {e}", target),
+        }
+    }
+}
 
+async fn serve() -> Result<(), Box<dyn std::error::Error>> {
     let grpc_port = env::var("SERVER_PORT").unwrap_or_else(|_| "8080".to_string());
     let grpc_addr = format!("[::]:{}", grpc_port).parse()?;
 
     info!("Starting OSM Import Rust gRPC service on {}", grpc_addr);
 
-    let osm_service = OSMImportService::default();
+    let retention_policy = RetentionPolicy::from_env()?;
+    if !retention_policy.is_empty() {
+        let retention_interval_secs = env::var("RETENTION_INTERVAL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(3600);
+        tokio::spawn(run_retention(
+            retention_policy,
+            Duration::from_secs(retention_interval_secs),
+        ));
+    }
+
+    let osm_service = OSMImportService;
+
+    for (variable, target) in [
+        ("SCHEDULER_FULL_CRON", PrewarmTarget::Full),
+        ("SCHEDULER_DELTA_CRON", PrewarmTarget::Delta),
+    ] {
+        if let Ok(expression) = env::var(variable) {
+            let schedule = Schedule::parse(&expression)?;
+            info!(
+                "Pre-warming {:?} imports on schedule {}",
+                target, expression
+            );
+            tokio::spawn(run_prewarm(target, schedule));
+        }
+    }
 
     Server::builder()
         .add_service(OsmImportServer::new(osm_service))
@@ -109,3 +726,292 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// Cancelled on Ctrl-C, so an interrupted command leaves no lock or temp files behind.
+fn interrupt_token() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let interrupted = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("🛑 Interrupted, stopping");
+            interrupted.cancel();
+        }
+    });
+    cancel
+}
+
+async fn resolve_import_target(
+    target: ImportTarget,
+    cancel: &CancellationToken,
+) -> Result<ImportOptions, Box<dyn std::error::Error>> {
+    Ok(match target {
+        ImportTarget::Options(options) => options,
+        ImportTarget::LatestFull => ImportOptions {
+            osm_file_type: OsmFileType::Full(
+                resolve_full_date("latest".to_string())
+                    .await
+                    .map_err(anyhow::Error::new)?,
+            ),
+            base_path: "./data/".to_string(),
+        },
+        ImportTarget::Source(location) => ImportOptions {
+            osm_file_type: OsmFileType::Custom(prepare_custom_source(&location, cancel).await?),
+            base_path: "./data/".to_string(),
+        },
+    })
+}
+
+async fn run_import_command(target: ImportTarget) -> Result<(), Box<dyn std::error::Error>> {
+    let cancel = interrupt_token();
+    let options = resolve_import_target(target, &cancel).await?;
+    options.check_published().map_err(anyhow::Error::new)?;
+    let handle = options.get_import_handle();
+    if Path::new(&options.get_lock_file()).exists() {
+        return Err(format!("Import {} is already running", handle).into());
+    }
+
+    info!("🚀 Importing {}", handle);
+    process_osm_import(&options, &cancel).await?;
+    info!("🎉 Import {} complete", handle);
+    Ok(())
+}
+
+async fn run_batch_command(args: BatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let input_filename = Path::new(&args.input)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format!("Invalid input path {}", args.input))?
+        .to_string();
+
+    let options = BatchOptions {
+        elements_per_batch: args.elements_per_batch,
+        history: args.history,
+        output: ElementOutput::from_env()?,
+        header: BatchHeaderConfig::from_env()?,
+        provenance: BatchProvenance::new(&input_filename).with_source(Some(args.input.clone())),
+        ..Default::default()
+    };
+    let input = std::io::BufReader::new(std::fs::File::open(&args.input)?);
+    let sink = DirectorySink::new(&args.out, &input_filename);
+
+    let summary = batch_reader(input, args.format, &options, &sink, &interrupt_token()).await?;
+    info!(
+        "🎉 Batched {} elements from {} into {}: {} node, {} way and {} relation batches",
+        summary.elements,
+        args.input,
+        args.out,
+        summary.batches[&ElementType::Node],
+        summary.batches[&ElementType::Way],
+        summary.batches[&ElementType::Relation]
+    );
+    Ok(())
+}
+
+async fn run_status_command() -> Result<(), Box<dyn std::error::Error>> {
+    let imports = list_imports().await?;
+    if imports.is_empty() {
+        println!("No imports under ./data");
+        return Ok(());
+    }
+
+    println!(
+        "{:<8} {:<32} {:<10} {:>12}  LAST MODIFIED",
+        "TYPE", "SCOPE", "STATE", "SIZE"
+    );
+    for import in &imports {
+        let state = if import.locked {
+            "running"
+        } else if import.batches_complete {
+            "complete"
+        } else if import.invalid_input.is_some() {
+            "invalid"
+        } else if import.last_error.is_some() {
+            "failed"
+        } else {
+            "incomplete"
+        };
+        let last_modified: chrono::DateTime<chrono::Utc> = import.last_modified.into();
+        println!(
+            "{:<8} {:<32} {:<10} {:>12}  {}",
+            import.import_type,
+            import.scope,
+            state,
+            import.size_bytes,
+            last_modified.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+    }
+    for import in &imports {
+        if let Some(invalid) = &import.invalid_input {
+            println!("⚠️ {}/{}: {}", import.import_type, import.scope, invalid);
+        }
+        if let Some(error) = &import.last_error {
+            println!(
+                "💥 {}/{}: {} ({})",
+                import.import_type,
+                import.scope,
+                error,
+                error.code.as_str()
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn run_clean_command(target: CleanTarget) -> Result<(), Box<dyn std::error::Error>> {
+    let policy = match target {
+        CleanTarget::Import(target) => {
+            let options = resolve_import_target(target, &interrupt_token()).await?;
+            let handle = options.get_import_handle();
+            return match delete_import(&options).await {
+                DeleteImportStatus::ImportDeleted => {
+                    info!("🧹 Deleted import {}", handle);
+                    Ok(())
+                }
+                DeleteImportStatus::ImportLocked => {
+                    Err(format!("Import {} is still running", handle).into())
+                }
+                DeleteImportStatus::ImportNotFound => {
+                    Err(format!("Import {} not found", handle).into())
+                }
+                DeleteImportStatus::DeleteError(error) => Err(error.into()),
+            };
+        }
+        CleanTarget::Intermediates => RetentionPolicy {
+            keep_full_imports: None,
+            keep_delta_days: None,
+            remove_intermediates: true,
+        },
+        CleanTarget::RetentionPolicy => {
+            let policy = RetentionPolicy::from_env()?;
+            if policy.is_empty() {
+                return Err(
+                    "No retention policy configured; set RETENTION_* or name an import to delete"
+                        .into(),
+                );
+            }
+            policy
+        }
+    };
+
+    let report = apply_retention_policy(&policy).await?;
+    info!(
+        "🧹 Removed {} imports and {} intermediate files ({} bytes)",
+        report.imports_deleted, report.intermediates_removed, report.bytes_freed
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let command = match cli::parse_args(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Command::Help = command {
+        print!("{}", cli::USAGE);
+        return Ok(());
+    }
+
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
+
+    let storage = Storage::from_env()?;
+    info!("📦 Storing imports on {}", storage.describe());
+    osm_import_rust::set_storage(storage)?;
+    let provider = provider_from_env()?;
+    info!("🌍 Downloading from {}", provider.describe());
+    osm_import_rust::set_provider(provider)?;
+
+    match command {
+        Command::Serve => serve().await,
+        Command::Import(options) => run_import_command(options).await,
+        Command::Batch(args) => run_batch_command(args).await,
+        Command::Status => run_status_command().await,
+        Command::Clean(target) => run_clean_command(target).await,
+        Command::Help => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_every_error_code_to_proto() {
+        let codes = [
+            (
+                ErrorCode::InvalidArgument,
+                osm_import::ErrorCode::InvalidArgument,
+            ),
+            (
+                ErrorCode::UpstreamNotFound,
+                osm_import::ErrorCode::UpstreamNotFound,
+            ),
+            (
+                ErrorCode::UpstreamUnavailable,
+                osm_import::ErrorCode::UpstreamUnavailable,
+            ),
+            (
+                ErrorCode::ConversionFailed,
+                osm_import::ErrorCode::ConversionFailed,
+            ),
+            (ErrorCode::ParseFailed, osm_import::ErrorCode::ParseFailed),
+            (ErrorCode::StorageError, osm_import::ErrorCode::StorageError),
+            (ErrorCode::ImportFailed, osm_import::ErrorCode::ImportFailed),
+            (
+                ErrorCode::ChecksumMismatch,
+                osm_import::ErrorCode::ChecksumMismatch,
+            ),
+        ];
+        for (code, proto) in codes {
+            let error = ServiceError::new(code, "failed").with_detail("url", "https://example.org");
+            let error = to_proto_error(error);
+            assert_eq!(error.code, proto as i32, "{:?}", code);
+            assert_eq!(error.message, "failed");
+            assert_eq!(error.details["url"], "https://example.org");
+        }
+    }
+
+    #[test]
+    fn reports_import_failures_in_the_response() {
+        for code in [
+            ErrorCode::UpstreamNotFound,
+            ErrorCode::UpstreamUnavailable,
+            ErrorCode::ConversionFailed,
+            ErrorCode::ParseFailed,
+            ErrorCode::ImportFailed,
+            ErrorCode::ChecksumMismatch,
+        ] {
+            let error = report_error(ServiceError::new(code, "failed")).unwrap();
+            assert_eq!(error.message, "failed", "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn fails_the_call_for_bad_requests_and_storage_errors() {
+        for (code, status_code) in [
+            (ErrorCode::InvalidArgument, Code::InvalidArgument),
+            (ErrorCode::StorageError, Code::Internal),
+        ] {
+            let error = ServiceError::new(code, "failed").with_detail("key", "value");
+            let status = report_error(error).unwrap_err();
+            assert_eq!(status.code(), status_code);
+            assert_eq!(status.message(), "failed");
+
+            let details = osm_import::Error::decode(status.details()).unwrap();
+            assert_eq!(
+                details.code,
+                to_proto_error(ServiceError::new(code, "")).code
+            );
+            assert_eq!(details.message, "failed");
+            assert_eq!(details.details["key"], "value");
+        }
+    }
+}
@@ -0,0 +1,600 @@
+use anyhow::Result;
+use chrono::Utc;
+use md5::{Digest, Md5};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::sync::OnceLock;
+use tokio::fs;
+use tracing::warn;
+
+use crate::error::{ErrorCode, ServiceError};
+
+static PROVIDER: OnceLock<Box<dyn Provider>> = OnceLock::new();
+
+/// Where full extracts and change files are downloaded from. Full extracts are named
+/// by their `yymmdd` date and change files by their `AAA/BBB/CCC` sequence.
+pub trait Provider: Send + Sync {
+    fn describe(&self) -> String;
+
+    /// The full extract published on `date`.
+    fn full_extract_url(&self, date: &str) -> Result<String, ServiceError>;
+
+    /// The change file `abc`.
+    fn change_url(&self, abc: &str) -> Result<String, ServiceError>;
+
+    /// The replication state published next to the change file `abc`.
+    fn change_state_url(&self, abc: &str) -> Result<String, ServiceError>;
+
+    /// The replication state naming the newest change file.
+    fn latest_state_url(&self) -> Result<String, ServiceError>;
+
+    /// The sidecar holding the checksum of the file at `url`, if one is published.
+    fn checksum_url(&self, url: &str) -> Option<String>;
+
+    /// How many days `latest` looks back for a full extract.
+    fn full_extract_lookback_days(&self) -> u64 {
+        // A dated extract is published once a day; a few days cover late publication
+        // and time zone differences.
+        3
+    }
+
+    /// Whether full extracts stay published after the `latest` lookback window.
+    fn keeps_full_extract_archive(&self) -> bool {
+        true
+    }
+}
+
+type NewProvider = fn(&str) -> Result<Box<dyn Provider>, String>;
+
+/// The region imported when `UPSTREAM_REGION` is not set.
+const DEFAULT_REGION: &str = "bangladesh";
+
+/// Region names mapped to the provider serving them, one key per provider. Each key
+/// holds comma-separated `region=value` entries, e.g.
+/// `GEOFABRIK_REGIONS=nepal=asia/nepal,india=asia/india`.
+const REGION_KEYS: [(&str, NewProvider); 4] = [
+    ("GEOFABRIK_REGIONS", |path| {
+        Ok(Box::new(Geofabrik::new(path)?))
+    }),
+    ("PLANET_REGIONS", |interval| {
+        Ok(Box::new(Planet::new(interval)?))
+    }),
+    ("BBBIKE_REGIONS", |city| Ok(Box::new(BBBike::new(city)?))),
+    ("HTTP_REGIONS", |base_url| {
+        Ok(Box::new(StaticDirectory::new(base_url)?))
+    }),
+];
+
+/// Selects the provider of the region named by `UPSTREAM_REGION`, default
+/// `bangladesh`, from the built-in regions and the `*_REGIONS` keys.
+pub fn provider_from_env() -> Result<Box<dyn Provider>, String> {
+    provider_for_region(|key| std::env::var(key).ok().filter(|value| !value.is_empty()))
+}
+
+fn provider_for_region(env: impl Fn(&str) -> Option<String>) -> Result<Box<dyn Provider>, String> {
+    let mut regions = regions(&env)?;
+    let region = env("UPSTREAM_REGION").unwrap_or_else(|| DEFAULT_REGION.to_string());
+    regions.remove(&region).ok_or_else(|| {
+        format!(
+            "UPSTREAM_REGION {:?} is not configured; known regions: {}",
+            region,
+            regions.keys().cloned().collect::<Vec<_>>().join(", ")
+        )
+    })
+}
+
+/// The built-in regions, overridden and extended by the `*_REGIONS` keys.
+fn regions(
+    env: &impl Fn(&str) -> Option<String>,
+) -> Result<BTreeMap<String, Box<dyn Provider>>, String> {
+    let mut regions: BTreeMap<String, Box<dyn Provider>> = BTreeMap::new();
+    regions.insert(
+        DEFAULT_REGION.to_string(),
+        Box::new(Geofabrik::new("asia/bangladesh")?),
+    );
+    regions.insert("planet".to_string(), Box::new(Planet::new("day")?));
+
+    let mut configured = HashMap::new();
+    for (key, provider) in REGION_KEYS {
+        let Some(entries) = env(key) else {
+            continue;
+        };
+        for entry in entries
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (region, value) = entry
+                .split_once('=')
+                .map(|(region, value)| (region.trim(), value.trim()))
+                .filter(|(region, value)| !region.is_empty() && !value.is_empty())
+                .ok_or_else(|| format!("{} entries must be region=value, not {:?}", key, entry))?;
+            if let Some(other_key) = configured.insert(region.to_string(), key) {
+                return Err(format!(
+                    "Region {:?} is configured in both {} and {}",
+                    region, other_key, key
+                ));
+            }
+            let provider =
+                provider(value).map_err(|e| format!("{} region {:?}: {}", key, region, e))?;
+            regions.insert(region.to_string(), provider);
+        }
+    }
+    Ok(regions)
+}
+
+pub fn set_provider(provider: Box<dyn Provider>) -> Result<(), String> {
+    PROVIDER
+        .set(provider)
+        .map_err(|_| "Upstream provider is already configured".to_string())
+}
+
+pub(crate) fn provider() -> &'static dyn Provider {
+    PROVIDER
+        .get_or_init(|| {
+            Box::new(Geofabrik {
+                region: "asia/bangladesh".to_string(),
+            })
+        })
+        .as_ref()
+}
+
+fn not_published(provider: &dyn Provider, what: &str) -> ServiceError {
+    ServiceError::new(
+        ErrorCode::UpstreamNotFound,
+        format!("{} does not publish {}", provider.describe(), what),
+    )
+}
+
+/// An osmosis-style replication tree: `AAA/BBB/CCC.osc.gz` change files, each with a
+/// `.state.txt`, and a `state.txt` naming the newest one.
+struct ReplicationTree<'a>(&'a str);
+
+impl ReplicationTree<'_> {
+    fn change_url(&self, abc: &str) -> String {
+        format!("{}/{}.osc.gz", self.0, abc)
+    }
+
+    fn change_state_url(&self, abc: &str) -> String {
+        format!("{}/{}.state.txt", self.0, abc)
+    }
+
+    fn latest_state_url(&self) -> String {
+        format!("{}/state.txt", self.0)
+    }
+}
+
+/// Regional extracts from download.geofabrik.de, e.g. `asia/bangladesh`, with daily
+/// change files. Every `.osm.pbf` has an `.md5` sidecar.
+pub struct Geofabrik {
+    region: String,
+}
+
+impl Geofabrik {
+    /// `path` is the region's path on the download server, e.g. `asia/bangladesh`.
+    pub fn new(path: &str) -> Result<Self, String> {
+        let region = path.trim_matches('/');
+        if region.is_empty() {
+            return Err("Geofabrik regions need a path such as asia/bangladesh".to_string());
+        }
+        Ok(Geofabrik {
+            region: region.to_string(),
+        })
+    }
+
+    fn base_url(&self) -> String {
+        format!("https://download.geofabrik.de/{}", self.region)
+    }
+
+    fn updates_url(&self) -> String {
+        format!("{}-updates", self.base_url())
+    }
+}
+
+impl Provider for Geofabrik {
+    fn describe(&self) -> String {
+        format!("Geofabrik ({})", self.region)
+    }
+
+    fn full_extract_url(&self, date: &str) -> Result<String, ServiceError> {
+        Ok(format!("{}-{}.osm.pbf", self.base_url(), date))
+    }
+
+    fn change_url(&self, abc: &str) -> Result<String, ServiceError> {
+        Ok(ReplicationTree(&self.updates_url()).change_url(abc))
+    }
+
+    fn change_state_url(&self, abc: &str) -> Result<String, ServiceError> {
+        Ok(ReplicationTree(&self.updates_url()).change_state_url(abc))
+    }
+
+    fn latest_state_url(&self) -> Result<String, ServiceError> {
+        Ok(ReplicationTree(&self.updates_url()).latest_state_url())
+    }
+
+    fn checksum_url(&self, url: &str) -> Option<String> {
+        url.ends_with(".osm.pbf").then(|| format!("{}.md5", url))
+    }
+}
+
+/// The whole planet from planet.openstreetmap.org: the weekly `planet-yymmdd.osm.pbf`
+/// dumps with their `.md5` sidecars, and the minutely, hourly or daily replication tree.
+pub struct Planet {
+    interval: String,
+}
+
+const PLANET_URL: &str = "https://planet.openstreetmap.org";
+
+impl Planet {
+    pub fn new(interval: &str) -> Result<Self, String> {
+        if !["minute", "hour", "day"].contains(&interval) {
+            return Err(format!(
+                "the planet replication interval must be minute, hour or day, not {:?}",
+                interval
+            ));
+        }
+        Ok(Planet {
+            interval: interval.to_string(),
+        })
+    }
+
+    fn replication_url(&self) -> String {
+        format!("{}/replication/{}", PLANET_URL, self.interval)
+    }
+}
+
+impl Provider for Planet {
+    fn describe(&self) -> String {
+        format!("planet.openstreetmap.org ({} replication)", self.interval)
+    }
+
+    fn full_extract_url(&self, date: &str) -> Result<String, ServiceError> {
+        Ok(format!("{}/pbf/planet-{}.osm.pbf", PLANET_URL, date))
+    }
+
+    fn change_url(&self, abc: &str) -> Result<String, ServiceError> {
+        Ok(ReplicationTree(&self.replication_url()).change_url(abc))
+    }
+
+    fn change_state_url(&self, abc: &str) -> Result<String, ServiceError> {
+        Ok(ReplicationTree(&self.replication_url()).change_state_url(abc))
+    }
+
+    fn latest_state_url(&self) -> Result<String, ServiceError> {
+        Ok(ReplicationTree(&self.replication_url()).latest_state_url())
+    }
+
+    fn checksum_url(&self, url: &str) -> Option<String> {
+        url.ends_with(".osm.pbf").then(|| format!("{}.md5", url))
+    }
+
+    // Dumps are dated on the day they start and take a few days to appear.
+    fn full_extract_lookback_days(&self) -> u64 {
+        10
+    }
+}
+
+/// City extracts from download.bbbike.org, e.g. `Dhaka`. BBBike keeps no archive and
+/// no change files, so only today's extract can be imported. Checksums of all files
+/// in a city's directory are listed in its `CHECKSUM.txt`.
+pub struct BBBike {
+    city: String,
+}
+
+impl BBBike {
+    pub fn new(city: &str) -> Result<Self, String> {
+        if city.is_empty() || city.contains('/') {
+            return Err(format!("{:?} is not a BBBike city name", city));
+        }
+        Ok(BBBike {
+            city: city.to_string(),
+        })
+    }
+
+    fn city_url(&self) -> String {
+        format!("https://download.bbbike.org/osm/bbbike/{}", self.city)
+    }
+}
+
+impl Provider for BBBike {
+    fn describe(&self) -> String {
+        format!("BBBike ({})", self.city)
+    }
+
+    fn full_extract_url(&self, date: &str) -> Result<String, ServiceError> {
+        if date != Utc::now().format("%y%m%d").to_string() {
+            return Err(not_published(self, "extracts from past days"));
+        }
+        Ok(format!("{}/{}.osm.pbf", self.city_url(), self.city))
+    }
+
+    fn change_url(&self, _abc: &str) -> Result<String, ServiceError> {
+        Err(not_published(self, "change files"))
+    }
+
+    fn change_state_url(&self, _abc: &str) -> Result<String, ServiceError> {
+        Err(not_published(self, "change files"))
+    }
+
+    fn latest_state_url(&self) -> Result<String, ServiceError> {
+        Err(not_published(self, "change files"))
+    }
+
+    fn checksum_url(&self, _url: &str) -> Option<String> {
+        Some(format!("{}/CHECKSUM.txt", self.city_url()))
+    }
+
+    fn full_extract_lookback_days(&self) -> u64 {
+        0
+    }
+
+    fn keeps_full_extract_archive(&self) -> bool {
+        false
+    }
+}
+
+/// Any static HTTP directory laid out like a Geofabrik mirror: `yymmdd.osm.pbf`
+/// extracts with optional `.md5` sidecars and a replication tree under `updates/`.
+pub struct StaticDirectory {
+    base_url: String,
+}
+
+impl StaticDirectory {
+    pub fn new(base_url: &str) -> Result<Self, String> {
+        reqwest::Url::parse(base_url)
+            .map_err(|e| format!("invalid base URL {:?}: {}", base_url, e))?;
+        Ok(StaticDirectory {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    fn updates_url(&self) -> String {
+        format!("{}/updates", self.base_url)
+    }
+}
+
+impl Provider for StaticDirectory {
+    fn describe(&self) -> String {
+        self.base_url.clone()
+    }
+
+    fn full_extract_url(&self, date: &str) -> Result<String, ServiceError> {
+        Ok(format!("{}/{}.osm.pbf", self.base_url, date))
+    }
+
+    fn change_url(&self, abc: &str) -> Result<String, ServiceError> {
+        Ok(ReplicationTree(&self.updates_url()).change_url(abc))
+    }
+
+    fn change_state_url(&self, abc: &str) -> Result<String, ServiceError> {
+        Ok(ReplicationTree(&self.updates_url()).change_state_url(abc))
+    }
+
+    fn latest_state_url(&self) -> Result<String, ServiceError> {
+        Ok(ReplicationTree(&self.updates_url()).latest_state_url())
+    }
+
+    fn checksum_url(&self, url: &str) -> Option<String> {
+        Some(format!("{}.md5", url))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Checksum {
+    Md5(String),
+    Sha256(String),
+}
+
+impl Checksum {
+    fn parse(hash: &str) -> Option<Self> {
+        if !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return None;
+        }
+        let hash = hash.to_ascii_lowercase();
+        match hash.len() {
+            32 => Some(Checksum::Md5(hash)),
+            64 => Some(Checksum::Sha256(hash)),
+            _ => None,
+        }
+    }
+
+    fn expected(&self) -> &str {
+        match self {
+            Checksum::Md5(hash) | Checksum::Sha256(hash) => hash,
+        }
+    }
+
+    fn compute(&self, path: &str) -> Result<String> {
+        let mut file = std::fs::File::open(path)?;
+        let mut buffer = vec![0; 1 << 16];
+        match self {
+            Checksum::Md5(_) => {
+                let mut hasher = Md5::new();
+                loop {
+                    let read = file.read(&mut buffer)?;
+                    if read == 0 {
+                        break;
+                    }
+                    hasher.update(&buffer[..read]);
+                }
+                Ok(crate::utils::hex(&hasher.finalize()))
+            }
+            Checksum::Sha256(_) => {
+                let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+                loop {
+                    let read = file.read(&mut buffer)?;
+                    if read == 0 {
+                        break;
+                    }
+                    context.update(&buffer[..read]);
+                }
+                Ok(crate::utils::hex(context.finish().as_ref()))
+            }
+        }
+    }
+}
+
+/// Finds the checksum of `file_name` in a sidecar in `md5sum` (`<hash>  <name>`) or BSD
+/// (`MD5 (<name>) = <hash>`) format. A sidecar with a single bare hash applies to any file.
+fn find_checksum(sidecar: &str, file_name: &str) -> Option<Checksum> {
+    sidecar.lines().find_map(|line| {
+        let line = line.trim();
+        if let Some((name, hash)) = line
+            .split_once(" (")
+            .and_then(|(_, rest)| rest.split_once(") = "))
+        {
+            return (name == file_name).then(|| Checksum::parse(hash)).flatten();
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((hash, name)) => {
+                let name = name.trim_start().trim_start_matches('*');
+                (name == file_name).then(|| Checksum::parse(hash)).flatten()
+            }
+            None => Checksum::parse(line),
+        }
+    })
+}
+
+/// Checks a downloaded file against the checksum in its sidecar and removes it when they
+/// differ. A missing sidecar, or one that does not list the file, is only logged.
+pub(crate) async fn verify_checksum(checksum_url: &str, url: &str, path: &str) -> Result<()> {
+    let unavailable = |e: reqwest::Error| {
+        ServiceError::new(
+            ErrorCode::UpstreamUnavailable,
+            format!("Fetching checksum failed: {}", e),
+        )
+        .with_detail("url", checksum_url)
+    };
+
+    let response = reqwest::get(checksum_url).await.map_err(unavailable)?;
+    if !response.status().is_success() {
+        warn!(
+            "No checksum for {} at {} ({}), skipping verification",
+            url,
+            checksum_url,
+            response.status()
+        );
+        return Ok(());
+    }
+    let sidecar = response.text().await.map_err(unavailable)?;
+
+    let file_name = url.rsplit('/').next().unwrap_or(url);
+    let Some(checksum) = find_checksum(&sidecar, file_name) else {
+        warn!(
+            "{} lists no checksum for {}, skipping verification",
+            checksum_url, file_name
+        );
+        return Ok(());
+    };
+
+    let expected = checksum.expected().to_string();
+    let hashed_path = path.to_string();
+    let actual = tokio::task::spawn_blocking(move || checksum.compute(&hashed_path)).await??;
+    if actual != expected {
+        fs::remove_file(path).await?;
+        return Err(ServiceError::new(
+            ErrorCode::ChecksumMismatch,
+            format!("Checksum of {} does not match {}", file_name, checksum_url),
+        )
+        .with_detail("url", url)
+        .with_detail("expected", expected)
+        .with_detail("actual", actual)
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider_with(env: &[(&str, &str)]) -> Result<Box<dyn Provider>, String> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        provider_for_region(|key| env.get(key).cloned())
+    }
+
+    #[test]
+    fn selects_the_provider_of_the_region() {
+        assert_eq!(
+            provider_with(&[]).unwrap().describe(),
+            "Geofabrik (asia/bangladesh)"
+        );
+        assert_eq!(
+            provider_with(&[("UPSTREAM_REGION", "planet")])
+                .unwrap()
+                .describe(),
+            "planet.openstreetmap.org (day replication)"
+        );
+
+        let env = [
+            ("GEOFABRIK_REGIONS", "nepal=asia/nepal, india=/asia/india/"),
+            ("BBBIKE_REGIONS", "dhaka=Dhaka"),
+            ("HTTP_REGIONS", "mirror=https://mirror.example.org/osm/"),
+            ("PLANET_REGIONS", "planet=minute"),
+        ];
+        let selected = |region: &str| {
+            let mut env = env.to_vec();
+            env.push(("UPSTREAM_REGION", region));
+            provider_with(&env).unwrap().describe()
+        };
+        assert_eq!(selected("india"), "Geofabrik (asia/india)");
+        assert_eq!(selected("dhaka"), "BBBike (Dhaka)");
+        assert_eq!(selected("mirror"), "https://mirror.example.org/osm");
+        assert_eq!(
+            selected("planet"),
+            "planet.openstreetmap.org (minute replication)"
+        );
+        assert_eq!(selected("bangladesh"), "Geofabrik (asia/bangladesh)");
+    }
+
+    #[test]
+    fn rejects_unknown_and_conflicting_regions() {
+        let error = provider_with(&[("UPSTREAM_REGION", "atlantis")])
+            .err()
+            .unwrap();
+        assert!(error.contains("bangladesh, planet"), "{}", error);
+
+        let error = provider_with(&[
+            ("GEOFABRIK_REGIONS", "dhaka=asia/bangladesh"),
+            ("BBBIKE_REGIONS", "dhaka=Dhaka"),
+        ])
+        .err()
+        .unwrap();
+        assert!(
+            error.contains("both GEOFABRIK_REGIONS and BBBIKE_REGIONS"),
+            "{}",
+            error
+        );
+
+        assert!(provider_with(&[("PLANET_REGIONS", "planet=weekly")]).is_err());
+        assert!(provider_with(&[("HTTP_REGIONS", "mirror=not a url")]).is_err());
+        assert!(provider_with(&[("BBBIKE_REGIONS", "Dhaka")]).is_err());
+    }
+
+    #[test]
+    fn finds_checksums_in_every_sidecar_format() {
+        let md5 = "0123456789abcdef0123456789abcdef";
+        let sha256 = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let expected = |checksum: Option<Checksum>| checksum.map(|c| c.expected().to_string());
+
+        let md5sum = format!("{}  other.osm.pbf\n{} *Dhaka.osm.pbf\n", sha256, md5);
+        assert_eq!(
+            expected(find_checksum(&md5sum, "Dhaka.osm.pbf")).as_deref(),
+            Some(md5)
+        );
+        let bsd = format!("SHA256 (Dhaka.osm.pbf) = {}\n", sha256);
+        assert_eq!(
+            expected(find_checksum(&bsd, "Dhaka.osm.pbf")).as_deref(),
+            Some(sha256)
+        );
+        assert_eq!(
+            expected(find_checksum(md5, "any.osm.pbf")).as_deref(),
+            Some(md5)
+        );
+        assert!(find_checksum(&bsd, "other.osm.pbf").is_none());
+        assert!(find_checksum("not a checksum", "any.osm.pbf").is_none());
+    }
+}
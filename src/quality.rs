@@ -0,0 +1,668 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use tokio::fs;
+
+use crate::reader::{MemberRef, OsmElement};
+use crate::ElementType;
+
+/// Issues kept per check as examples; the rest are only counted.
+const MAX_SAMPLES: usize = 20;
+
+/// The API limit on tag keys and values, in characters.
+const MAX_TAG_LENGTH: usize = 255;
+
+/// Tag values quoted in an issue are cut to this many characters.
+const MAX_QUOTED_LENGTH: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QualityCheck {
+    /// The same element listed twice; the same version for history and change files.
+    DuplicateIds,
+    MissingWayNodes,
+    MissingRelationMembers,
+    /// Ways with fewer than two nodes.
+    EmptyWays,
+    /// Nodes without coordinates or outside the valid range.
+    InvalidCoordinates,
+    /// Empty, overlong, padded or control-character values and repeated keys.
+    TagAnomalies,
+}
+
+const QUALITY_CHECKS: [QualityCheck; 6] = [
+    QualityCheck::DuplicateIds,
+    QualityCheck::MissingWayNodes,
+    QualityCheck::MissingRelationMembers,
+    QualityCheck::EmptyWays,
+    QualityCheck::InvalidCoordinates,
+    QualityCheck::TagAnomalies,
+];
+
+impl QualityCheck {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QualityCheck::DuplicateIds => "duplicate_ids",
+            QualityCheck::MissingWayNodes => "missing_way_nodes",
+            QualityCheck::MissingRelationMembers => "missing_relation_members",
+            QualityCheck::EmptyWays => "empty_ways",
+            QualityCheck::InvalidCoordinates => "invalid_coordinates",
+            QualityCheck::TagAnomalies => "tag_anomalies",
+        }
+    }
+
+    fn from_str(name: &str) -> Option<Self> {
+        QUALITY_CHECKS
+            .into_iter()
+            .find(|candidate| candidate.as_str() == name)
+    }
+
+    fn is_reference_check(self) -> bool {
+        matches!(
+            self,
+            QualityCheck::MissingWayNodes | QualityCheck::MissingRelationMembers
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QualityIssue {
+    pub element_type: ElementType,
+    pub id: i64,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct QualityCheckResult {
+    pub check: QualityCheck,
+    pub count: u64,
+    /// The first issues found, at most `MAX_SAMPLES`.
+    pub samples: Vec<QualityIssue>,
+}
+
+/// What batching found wrong with an input, written next to its batches.
+#[derive(Debug, Clone, Default)]
+pub struct QualityReport {
+    pub nodes: u64,
+    pub ways: u64,
+    pub relations: u64,
+    /// Only the checks that apply to the input: history and change files are not
+    /// checked for missing references since they only hold part of the data.
+    pub checks: Vec<QualityCheckResult>,
+}
+
+// `nodes`, `ways` and `relations` lines, then a `check name count` line per check
+// followed by a `sample type id detail` line per sample. Details never contain newlines.
+impl QualityReport {
+    pub(crate) async fn write(&self, report_path: &str) -> Result<()> {
+        let mut content = format!(
+            "nodes {}\nways {}\nrelations {}\n",
+            self.nodes, self.ways, self.relations
+        );
+        for result in &self.checks {
+            content.push_str(&format!(
+                "check {} {}\n",
+                result.check.as_str(),
+                result.count
+            ));
+            for sample in &result.samples {
+                content.push_str(&format!(
+                    "sample {} {} {}\n",
+                    sample.element_type,
+                    sample.id,
+                    sample.detail.replace('\n', " ")
+                ));
+            }
+        }
+
+        let temp_path = format!("{}.temp", report_path);
+        fs::write(&temp_path, content).await?;
+        fs::rename(&temp_path, report_path).await?;
+        Ok(())
+    }
+
+    pub(crate) fn parse(content: &str) -> Result<Self> {
+        let mut report = QualityReport::default();
+        for line in content.lines() {
+            let (key, value) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow::anyhow!("Invalid quality report line {:?}", line))?;
+            match key {
+                "nodes" => report.nodes = value.parse()?,
+                "ways" => report.ways = value.parse()?,
+                "relations" => report.relations = value.parse()?,
+                "check" => {
+                    let (name, count) = value
+                        .split_once(' ')
+                        .ok_or_else(|| anyhow::anyhow!("Invalid quality check {:?}", line))?;
+                    report.checks.push(QualityCheckResult {
+                        check: QualityCheck::from_str(name)
+                            .ok_or_else(|| anyhow::anyhow!("Unknown quality check {}", name))?,
+                        count: count.parse()?,
+                        samples: Vec::new(),
+                    });
+                }
+                "sample" => {
+                    let mut fields = value.splitn(3, ' ');
+                    let (Some(element_type), Some(id), Some(detail)) =
+                        (fields.next(), fields.next(), fields.next())
+                    else {
+                        anyhow::bail!("Invalid quality sample {:?}", line);
+                    };
+                    report
+                        .checks
+                        .last_mut()
+                        .ok_or_else(|| anyhow::anyhow!("Quality sample before any check"))?
+                        .samples
+                        .push(QualityIssue {
+                            element_type: element_type.parse().map_err(anyhow::Error::msg)?,
+                            id: id.parse()?,
+                            detail: detail.to_string(),
+                        });
+                }
+                _ => anyhow::bail!("Invalid quality report line {:?}", line),
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// What an input holds, which decides how its elements are checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InputKind {
+    Extract,
+    /// Every version of each element; deleted versions have no coordinates.
+    History,
+    /// Only the changed elements, each possibly in several versions.
+    Change,
+}
+
+impl InputKind {
+    pub(crate) fn from_import_type(import_type: &str) -> Self {
+        match import_type {
+            "history" => InputKind::History,
+            "delta" => InputKind::Change,
+            _ => InputKind::Extract,
+        }
+    }
+}
+
+/// Checks elements while they are batched. References are checked against the elements
+/// read before them, as files list nodes, then ways, then relations; only relations
+/// that are members of other relations may appear anywhere in the file.
+///
+/// Like the spatial index, it journals everything it records so an interrupted run can
+/// rebuild its state instead of re-reading the input from the start.
+pub(crate) struct QualityReportBuilder {
+    kind: InputKind,
+    /// Ids per element type, with the version for history and change files.
+    seen: [HashSet<(i64, u64)>; 3],
+    counts: [u64; 3],
+    checks: Vec<QualityCheckResult>,
+    /// Relations with relation members not read yet: the relation, the node and way
+    /// members it already misses and the unresolved relation members.
+    deferred: Vec<(i64, u32, Vec<i64>)>,
+    journal: Option<std::io::BufWriter<std::fs::File>>,
+    journal_len: u64,
+}
+
+impl QualityReportBuilder {
+    fn new(kind: InputKind) -> Self {
+        QualityReportBuilder {
+            kind,
+            seen: Default::default(),
+            counts: [0; 3],
+            checks: QUALITY_CHECKS
+                .into_iter()
+                .filter(|check| kind == InputKind::Extract || !check.is_reference_check())
+                .map(|check| QualityCheckResult {
+                    check,
+                    count: 0,
+                    samples: Vec::new(),
+                })
+                .collect(),
+            deferred: Vec::new(),
+            journal: None,
+            journal_len: 0,
+        }
+    }
+
+    pub fn with_journal(kind: InputKind, journal_path: &str) -> Result<Self> {
+        let mut builder = QualityReportBuilder::new(kind);
+        builder.journal = Some(std::io::BufWriter::new(std::fs::File::create(
+            journal_path,
+        )?));
+        Ok(builder)
+    }
+
+    /// Replays the first `journal_len` bytes of a journal, as reported by `checkpoint`,
+    /// and keeps appending after them.
+    pub fn resume(kind: InputKind, journal_path: &str, journal_len: u64) -> Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(journal_path)?;
+        if file.metadata()?.len() < journal_len {
+            anyhow::bail!("{} is shorter than its checkpoint", journal_path);
+        }
+        file.set_len(journal_len)?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut builder = QualityReportBuilder::new(kind);
+        let mut journal = JournalReader { rest: &bytes };
+        while !journal.rest.is_empty() {
+            match journal.take(1)?[0] {
+                0 => {
+                    let type_index = journal.type_index()?;
+                    let id = journal.i64()?;
+                    let version = journal.i64()? as u64;
+                    builder.add_seen(type_index, id, version);
+                }
+                1 => {
+                    let check = *QUALITY_CHECKS
+                        .get(journal.take(1)?[0] as usize)
+                        .ok_or_else(|| anyhow::anyhow!("Invalid quality journal record"))?;
+                    let type_index = journal.type_index()?;
+                    let id = journal.i64()?;
+                    let length = journal.u32()? as usize;
+                    let detail = String::from_utf8(journal.take(length)?.to_vec())?;
+                    builder.add_issue(check, type_index, id, detail);
+                }
+                2 => {
+                    let id = journal.i64()?;
+                    let missing = journal.u32()?;
+                    let mut unresolved = Vec::new();
+                    for _ in 0..journal.u32()? {
+                        unresolved.push(journal.i64()?);
+                    }
+                    builder.deferred.push((id, missing, unresolved));
+                }
+                _ => anyhow::bail!("Invalid quality journal record"),
+            }
+        }
+
+        builder.journal = Some(std::io::BufWriter::new(file));
+        builder.journal_len = journal_len;
+        Ok(builder)
+    }
+
+    /// Flushes the journal and returns its length.
+    pub fn checkpoint(&mut self) -> Result<u64> {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.flush()?;
+        }
+        Ok(self.journal_len)
+    }
+
+    pub fn add(&mut self, element: &OsmElement) -> Result<()> {
+        let type_index = element.element_type.index();
+        let id = element.id;
+        let version = match self.kind {
+            InputKind::Extract => 0,
+            InputKind::History | InputKind::Change => element.version.unwrap_or(0),
+        };
+
+        let duplicate = self.seen[type_index].contains(&(id, version));
+        self.write_journal(|journal| {
+            journal.push(0);
+            journal.push(type_index as u8);
+            journal.extend_from_slice(&id.to_le_bytes());
+            journal.extend_from_slice(&version.to_le_bytes());
+        })?;
+        self.add_seen(type_index, id, version);
+        if duplicate {
+            self.record_issue(QualityCheck::DuplicateIds, type_index, id, || {
+                "is listed more than once".to_string()
+            })?;
+        }
+
+        // Deletions only name the element.
+        if element.action.as_deref() == Some("delete") {
+            return Ok(());
+        }
+
+        match type_index {
+            0 => match element.coordinates {
+                None if self.kind != InputKind::History => {
+                    self.record_issue(QualityCheck::InvalidCoordinates, 0, id, || {
+                        "has no coordinates".to_string()
+                    })?;
+                }
+                Some((lat, lon))
+                    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) =>
+                {
+                    self.record_issue(QualityCheck::InvalidCoordinates, 0, id, || {
+                        format!("has coordinates {},{} outside the valid range", lat, lon)
+                    })?;
+                }
+                _ => {}
+            },
+            1 => {
+                let node_count = element.node_refs.len();
+                if node_count < 2 {
+                    self.record_issue(QualityCheck::EmptyWays, 1, id, || {
+                        format!("has {}", counted(node_count, "node"))
+                    })?;
+                }
+                if self.kind == InputKind::Extract {
+                    let missing = element
+                        .node_refs
+                        .iter()
+                        .filter(|&&node_id| !self.seen[0].contains(&(node_id, 0)))
+                        .count();
+                    if missing > 0 {
+                        self.record_issue(QualityCheck::MissingWayNodes, 1, id, || {
+                            format!("references {}", counted(missing, "missing node"))
+                        })?;
+                    }
+                }
+            }
+            _ => {
+                if self.kind == InputKind::Extract {
+                    self.check_members(id, &element.members)?;
+                }
+            }
+        }
+
+        self.check_tags(type_index, id, &element.tags)
+    }
+
+    fn check_members(&mut self, id: i64, members: &[MemberRef]) -> Result<()> {
+        let mut missing: u32 = 0;
+        let mut unresolved = Vec::new();
+        for member in members {
+            match *member {
+                MemberRef::Node(node_id) if !self.seen[0].contains(&(node_id, 0)) => missing += 1,
+                MemberRef::Way(way_id) if !self.seen[1].contains(&(way_id, 0)) => missing += 1,
+                MemberRef::Relation(relation_id) if !self.seen[2].contains(&(relation_id, 0)) => {
+                    unresolved.push(relation_id)
+                }
+                _ => {}
+            }
+        }
+
+        if unresolved.is_empty() {
+            if missing > 0 {
+                self.record_issue(QualityCheck::MissingRelationMembers, 2, id, || {
+                    format!("references {}", counted(missing as usize, "missing member"))
+                })?;
+            }
+            return Ok(());
+        }
+
+        self.write_journal(|journal| {
+            journal.push(2);
+            journal.extend_from_slice(&id.to_le_bytes());
+            journal.extend_from_slice(&missing.to_le_bytes());
+            journal.extend_from_slice(&(unresolved.len() as u32).to_le_bytes());
+            for relation_id in &unresolved {
+                journal.extend_from_slice(&relation_id.to_le_bytes());
+            }
+        })?;
+        self.deferred.push((id, missing, unresolved));
+        Ok(())
+    }
+
+    fn check_tags(&mut self, type_index: usize, id: i64, tags: &[(String, String)]) -> Result<()> {
+        let mut keys = HashSet::new();
+        for (key, value) in tags {
+            let problem = if key.is_empty() {
+                "has a tag with an empty key".to_string()
+            } else if !keys.insert(key.as_str()) {
+                format!("sets {:?} more than once", key)
+            } else if value.is_empty() {
+                format!("has an empty {:?} value", key)
+            } else if key.chars().count() > MAX_TAG_LENGTH || value.chars().count() > MAX_TAG_LENGTH
+            {
+                format!(
+                    "has a {:?} tag longer than {} characters",
+                    key, MAX_TAG_LENGTH
+                )
+            } else if value.trim() != value {
+                format!("has {}={} with surrounding whitespace", key, quoted(value))
+            } else if value.chars().any(char::is_control) {
+                format!("has {}={} with control characters", key, quoted(value))
+            } else {
+                continue;
+            };
+            self.record_issue(QualityCheck::TagAnomalies, type_index, id, || problem)?;
+        }
+        Ok(())
+    }
+
+    /// Resolves the relation members that were still outstanding and returns the report.
+    pub fn finish(mut self) -> QualityReport {
+        for (id, missing, unresolved) in std::mem::take(&mut self.deferred) {
+            let missing = missing as usize
+                + unresolved
+                    .iter()
+                    .filter(|&&relation_id| !self.seen[2].contains(&(relation_id, 0)))
+                    .count();
+            if missing > 0 {
+                self.add_issue(
+                    QualityCheck::MissingRelationMembers,
+                    2,
+                    id,
+                    format!("references {}", counted(missing, "missing member")),
+                );
+            }
+        }
+
+        QualityReport {
+            nodes: self.counts[0],
+            ways: self.counts[1],
+            relations: self.counts[2],
+            checks: self.checks,
+        }
+    }
+
+    fn record_issue(
+        &mut self,
+        check: QualityCheck,
+        type_index: usize,
+        id: i64,
+        detail: impl FnOnce() -> String,
+    ) -> Result<()> {
+        // Details are only kept, and journaled, while there is room for samples.
+        let detail = match self.result(check) {
+            Some(result) if result.samples.len() < MAX_SAMPLES => detail(),
+            _ => String::new(),
+        };
+        self.write_journal(|journal| {
+            journal.push(1);
+            journal.push(QUALITY_CHECKS.iter().position(|&c| c == check).unwrap() as u8);
+            journal.push(type_index as u8);
+            journal.extend_from_slice(&id.to_le_bytes());
+            journal.extend_from_slice(&(detail.len() as u32).to_le_bytes());
+            journal.extend_from_slice(detail.as_bytes());
+        })?;
+        self.add_issue(check, type_index, id, detail);
+        Ok(())
+    }
+
+    fn add_seen(&mut self, type_index: usize, id: i64, version: u64) {
+        self.seen[type_index].insert((id, version));
+        self.counts[type_index] += 1;
+    }
+
+    fn add_issue(&mut self, check: QualityCheck, type_index: usize, id: i64, detail: String) {
+        let Some(result) = self.result(check) else {
+            return;
+        };
+        result.count += 1;
+        if !detail.is_empty() && result.samples.len() < MAX_SAMPLES {
+            result.samples.push(QualityIssue {
+                element_type: ElementType::ALL[type_index],
+                id,
+                detail,
+            });
+        }
+    }
+
+    fn result(&mut self, check: QualityCheck) -> Option<&mut QualityCheckResult> {
+        self.checks.iter_mut().find(|result| result.check == check)
+    }
+
+    fn write_journal(&mut self, encode: impl FnOnce(&mut Vec<u8>)) -> Result<()> {
+        if let Some(journal) = self.journal.as_mut() {
+            let mut record = Vec::new();
+            encode(&mut record);
+            journal.write_all(&record)?;
+            self.journal_len += record.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+fn counted(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("1 {}", noun),
+        _ => format!("{} {}s", count, noun),
+    }
+}
+
+/// A tag value for an issue detail, escaped and shortened.
+fn quoted(value: &str) -> String {
+    if value.chars().count() <= MAX_QUOTED_LENGTH {
+        return format!("{:?}", value);
+    }
+    let prefix: String = value.chars().take(MAX_QUOTED_LENGTH).collect();
+    format!("{:?}…", prefix)
+}
+
+struct JournalReader<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> JournalReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        let (taken, rest) = self
+            .rest
+            .split_at_checked(length)
+            .ok_or_else(|| anyhow::anyhow!("Truncated quality journal record"))?;
+        self.rest = rest;
+        Ok(taken)
+    }
+
+    fn type_index(&mut self) -> Result<usize> {
+        let type_index = self.take(1)?[0] as usize;
+        if type_index >= ElementType::ALL.len() {
+            anyhow::bail!("Invalid quality journal record");
+        }
+        Ok(type_index)
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::OsmReader;
+
+    const INPUT: &str = r#"<osm version="0.6">
+<node id="1" version="1" lat="23.7" lon="90.4"><tag k="name" v=" Dhaka"/></node>
+<node id="2" version="1" lat="95.0" lon="90.4"/>
+<node id="1" version="2" lat="23.7" lon="90.4"/>
+<way id="1" version="1"><nd ref="1"/><nd ref="9"/></way>
+<way id="2" version="1"><nd ref="2"/><tag k="a" v="x"/><tag k="a" v="y"/></way>
+<relation id="1" version="1"><member type="relation" ref="2" role=""/><member type="node" ref="9" role=""/></relation>
+<relation id="2" version="1"><member type="way" ref="1" role=""/><tag k="name" v=""/></relation>
+<relation id="3" version="1"><member type="relation" ref="4" role=""/></relation>
+</osm>"#;
+
+    fn elements() -> Vec<OsmElement> {
+        let mut reader = OsmReader::new(INPUT.as_bytes()).unwrap();
+        let mut elements = Vec::new();
+        while let Some(element) = reader.next_element().unwrap() {
+            elements.push(element);
+        }
+        elements
+    }
+
+    async fn written(report: &QualityReport, path: &str) -> String {
+        report.write(path).await.unwrap();
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reports_issues_and_round_trips() {
+        let dir = std::env::temp_dir().join(format!("quality-report-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("input.osm.quality");
+        let path = path.to_str().unwrap();
+
+        let mut builder = QualityReportBuilder::new(InputKind::Extract);
+        for element in elements() {
+            builder.add(&element).unwrap();
+        }
+        let report = builder.finish();
+        let content = written(&report, path).await;
+        assert_eq!(
+            content,
+            "nodes 3\nways 2\nrelations 3\n\
+             check duplicate_ids 1\nsample node 1 is listed more than once\n\
+             check missing_way_nodes 1\nsample way 1 references 1 missing node\n\
+             check missing_relation_members 2\n\
+             sample relation 1 references 1 missing member\n\
+             sample relation 3 references 1 missing member\n\
+             check empty_ways 1\nsample way 2 has 1 node\n\
+             check invalid_coordinates 1\n\
+             sample node 2 has coordinates 95,90.4 outside the valid range\n\
+             check tag_anomalies 3\n\
+             sample node 1 has name=\" Dhaka\" with surrounding whitespace\n\
+             sample way 2 sets \"a\" more than once\n\
+             sample relation 2 has an empty \"name\" value\n"
+        );
+
+        let parsed = QualityReport::parse(&content).unwrap();
+        assert_eq!(written(&parsed, path).await, content);
+        assert!(QualityReport::parse("check unknown_check 1\n").is_err());
+        assert!(QualityReport::parse("sample node 1 orphan\n").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn journal_resumes_to_the_same_report() {
+        let dir = std::env::temp_dir().join(format!("quality-journal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let elements = elements();
+
+        let mut builder = QualityReportBuilder::new(InputKind::Extract);
+        for element in &elements {
+            builder.add(element).unwrap();
+        }
+        let expected = written(&builder.finish(), &path("expected")).await;
+
+        // Checkpointed after the first relation, which is still deferred then.
+        let resume_at = 6;
+        let mut builder =
+            QualityReportBuilder::with_journal(InputKind::Extract, &path("journal")).unwrap();
+        for element in &elements[..resume_at] {
+            builder.add(element).unwrap();
+        }
+        let journal_len = builder.checkpoint().unwrap();
+        for element in &elements[resume_at..] {
+            builder.add(element).unwrap();
+        }
+        builder.checkpoint().unwrap();
+        drop(builder);
+
+        let mut builder =
+            QualityReportBuilder::resume(InputKind::Extract, &path("journal"), journal_len)
+                .unwrap();
+        for element in &elements[resume_at..] {
+            builder.add(element).unwrap();
+        }
+        assert_eq!(written(&builder.finish(), &path("resumed")).await, expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
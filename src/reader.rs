@@ -0,0 +1,674 @@
+use anyhow::Result;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::io::{BufRead, Read};
+
+use crate::validation::{self, InputErrorCode};
+use crate::ElementType;
+
+#[derive(Debug, Clone)]
+pub struct RootElementInfo {
+    pub tag: String,
+    /// Unescaped attribute values in document order, namespace declarations included.
+    pub attributes: Vec<(String, String)>,
+    /// Elements such as `<bounds>` that sit between the root start tag and the first
+    /// element, exactly as they appear in the input.
+    pub header: Vec<String>,
+}
+
+impl RootElementInfo {
+    pub fn attribute(&self, key: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Replaces the value of `key` in place, or appends it when missing.
+    pub fn set_attribute(&mut self, key: &str, value: String) {
+        match self.attributes.iter_mut().find(|(name, _)| name == key) {
+            Some((_, existing)) => *existing = value,
+            None => self.attributes.push((key.to_string(), value)),
+        }
+    }
+}
+
+pub enum MemberRef {
+    Node(i64),
+    Way(i64),
+    Relation(i64),
+    Other,
+}
+
+/// One top-level `node`, `way` or `relation`, re-serialized from the parse events,
+/// together with the attributes the batcher and its indexes need.
+pub struct OsmElement {
+    pub element_type: ElementType,
+    pub id: i64,
+    pub version: Option<u64>,
+    pub timestamp: String,
+    pub coordinates: Option<(f64, f64)>,
+    pub node_refs: Vec<i64>,
+    pub members: Vec<MemberRef>,
+    /// Unescaped `k`/`v` pairs in document order.
+    pub tags: Vec<(String, String)>,
+    /// The enclosing `create`/`modify`/`delete` block of an osmChange file.
+    pub action: Option<String>,
+    pub xml: String,
+}
+
+impl OsmElement {
+    /// The element as it is written into a batch: osmChange elements keep their action
+    /// block so every batch stays a valid change file on its own.
+    pub fn into_batch_xml(self) -> String {
+        match self.action {
+            Some(action) => format!("<{}>\n{}\n</{}>", action, self.xml, action),
+            None => self.xml,
+        }
+    }
+}
+
+/// How `OsmElement::xml` is produced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElementOutput {
+    /// The element's bytes exactly as they appear in the input.
+    Passthrough,
+    /// Re-serialized from the parse events, which normalizes quoting and whitespace.
+    Rebuild,
+}
+
+impl ElementOutput {
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("BATCH_ELEMENT_OUTPUT").as_deref() {
+            Err(_) | Ok("passthrough") => Ok(ElementOutput::Passthrough),
+            Ok("rebuild") => Ok(ElementOutput::Rebuild),
+            Ok(other) => Err(format!(
+                "BATCH_ELEMENT_OUTPUT must be passthrough or rebuild, not {:?}",
+                other
+            )),
+        }
+    }
+}
+
+/// Passes reads through to `inner` and, while recording, keeps a copy of every byte
+/// the XML reader consumes, which is exactly the source text of the events read.
+///
+/// The bytes are copied rather than sliced out of the input buffer because an element
+/// can span several refills of `inner`'s buffer, which overwrite what was consumed
+/// before. Only the element being read is copied, straight into the `Vec` that becomes
+/// `OsmElement::xml`, so each byte is copied once.
+struct ByteRecorder<R> {
+    inner: R,
+    recording: bool,
+    recorded: Vec<u8>,
+}
+
+impl<R: BufRead> Read for ByteRecorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.inner.fill_buf()?;
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.consume(length);
+        Ok(length)
+    }
+}
+
+impl<R: BufRead> BufRead for ByteRecorder<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        if self.recording {
+            // The bytes being consumed are still in the inner buffer.
+            if let Ok(available) = self.inner.fill_buf() {
+                let mut consumed = &available[..amount];
+                // Whitespace between top-level elements is consumed along with the next tag.
+                if self.recorded.is_empty() {
+                    let start = consumed
+                        .iter()
+                        .position(|b| !b.is_ascii_whitespace())
+                        .unwrap_or(consumed.len());
+                    consumed = &consumed[start..];
+                }
+                self.recorded.extend_from_slice(consumed);
+            }
+        }
+        self.inner.consume(amount);
+    }
+}
+
+impl<R> ByteRecorder<R> {
+    fn new(inner: R) -> Self {
+        ByteRecorder {
+            inner,
+            recording: false,
+            recorded: Vec::new(),
+        }
+    }
+
+    fn start_recording(&mut self) {
+        self.recording = true;
+        self.recorded.clear();
+    }
+
+    fn stop_recording(&mut self) {
+        self.recording = false;
+        self.recorded.clear();
+    }
+
+    fn take_recorded(&mut self) -> Result<String> {
+        self.recording = false;
+        Ok(String::from_utf8(std::mem::take(&mut self.recorded))?)
+    }
+}
+
+/// Where a reader stood between two elements, enough to continue from there with a
+/// fresh reader.
+#[derive(Debug, Clone)]
+pub struct ReaderPosition {
+    pub offset: u64,
+    pub action: Option<String>,
+}
+
+/// The start tag of the first element, read while looking for the end of the header.
+struct PendingStart {
+    start: BytesStart<'static>,
+    empty: bool,
+    offset: u64,
+}
+
+/// Streams top-level elements out of an `osm` or `osmChange` document without
+/// holding the whole file in memory.
+pub struct OsmReader<R: BufRead> {
+    reader: Reader<ByteRecorder<R>>,
+    buf: Vec<u8>,
+    root: RootElementInfo,
+    action: Option<String>,
+    finished: bool,
+    base_offset: u64,
+    output: ElementOutput,
+    pending: Option<PendingStart>,
+}
+
+impl<R: BufRead> OsmReader<R> {
+    pub fn new(source: R) -> Result<Self> {
+        let mut reader = Reader::from_reader(ByteRecorder::new(source));
+        reader.config_mut().trim_text(true);
+        let mut buf = Vec::new();
+
+        loop {
+            let (e, finished) = match reader.read_event_into(&mut buf) {
+                Ok(Event::Start(e)) => (e, false),
+                Ok(Event::Empty(e)) => (e, true),
+                Ok(Event::Eof) => break,
+                Err(e) => return Err(validation::xml_error(e, reader.error_position())),
+                _ => {
+                    buf.clear();
+                    continue;
+                }
+            };
+
+            let tag_name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+            if tag_name == "osm" || tag_name == "osmChange" {
+                let mut attributes = Vec::new();
+                for attr in e.attributes() {
+                    let attr = attr?;
+                    let key = std::str::from_utf8(attr.key.as_ref())?.to_string();
+                    let value = attr.unescape_value()?.to_string();
+                    attributes.push((key, value));
+                }
+
+                let root = RootElementInfo {
+                    tag: tag_name,
+                    attributes,
+                    header: Vec::new(),
+                };
+                buf.clear();
+                let mut osm_reader = OsmReader {
+                    reader,
+                    buf,
+                    root,
+                    action: None,
+                    finished,
+                    base_offset: 0,
+                    output: ElementOutput::Passthrough,
+                    pending: None,
+                };
+                osm_reader.read_header()?;
+                return Ok(osm_reader);
+            }
+            buf.clear();
+        }
+
+        Err(validation::invalid_input(
+            InputErrorCode::UnexpectedRoot,
+            "The input has no <osm> or <osmChange> root element".to_string(),
+        ))
+    }
+
+    /// Continues a document at `position`, taken from an earlier reader of the same
+    /// document. `source` must already be positioned at `position.offset`.
+    pub fn resume(source: R, root: RootElementInfo, position: &ReaderPosition) -> Self {
+        let mut reader = Reader::from_reader(ByteRecorder::new(source));
+        reader.config_mut().trim_text(true);
+        // The start tags of the root and the open action block were read before the
+        // checkpoint, so their end tags arrive unmatched.
+        reader.config_mut().allow_unmatched_ends = true;
+
+        OsmReader {
+            reader,
+            buf: Vec::new(),
+            root,
+            action: position.action.clone(),
+            finished: false,
+            base_offset: position.offset,
+            output: ElementOutput::Passthrough,
+            pending: None,
+        }
+    }
+
+    /// Collects the children of the root that come before the first element. The
+    /// first element's start tag is kept for `next_element`.
+    fn read_header(&mut self) -> Result<()> {
+        while !self.finished {
+            let offset = self.reader.buffer_position();
+            self.buf.clear();
+            self.reader.get_mut().start_recording();
+
+            let (e, empty) = match self.reader.read_event_into(&mut self.buf) {
+                Ok(Event::Start(e)) => (e.into_owned(), false),
+                Ok(Event::Empty(e)) => (e.into_owned(), true),
+                Ok(Event::End(_)) => {
+                    self.finished = true;
+                    break;
+                }
+                Ok(Event::Eof) => return Err(validation::truncated_xml()),
+                Err(e) => return Err(self.xml_error(e)),
+                _ => continue,
+            };
+
+            let tag_name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+            match tag_name.as_str() {
+                "node" | "way" | "relation" => {
+                    self.pending = Some(PendingStart {
+                        start: e,
+                        empty,
+                        offset,
+                    });
+                    return Ok(());
+                }
+                "create" | "modify" | "delete" if !empty => {
+                    self.action = Some(tag_name);
+                    break;
+                }
+                _ => {
+                    if !empty {
+                        let mut skipped = Vec::new();
+                        self.reader
+                            .read_to_end_into(e.name(), &mut skipped)
+                            .map_err(|error| self.xml_error(error))?;
+                    }
+                    let element = self.reader.get_mut().take_recorded()?;
+                    self.root.header.push(element);
+                }
+            }
+        }
+
+        self.reader.get_mut().stop_recording();
+        Ok(())
+    }
+
+    fn xml_error(&self, error: quick_xml::Error) -> anyhow::Error {
+        validation::xml_error(error, self.base_offset + self.reader.error_position())
+    }
+
+    pub fn with_output(mut self, output: ElementOutput) -> Self {
+        self.output = output;
+        self
+    }
+
+    pub fn root(&self) -> &RootElementInfo {
+        &self.root
+    }
+
+    /// The position after the last element returned by `next_element`.
+    pub fn position(&self) -> ReaderPosition {
+        let offset = match &self.pending {
+            Some(pending) => pending.offset,
+            None => self.reader.buffer_position(),
+        };
+        ReaderPosition {
+            offset: self.base_offset + offset,
+            action: self.action.clone(),
+        }
+    }
+
+    pub fn next_element(&mut self) -> Result<Option<OsmElement>> {
+        let rebuild = self.output == ElementOutput::Rebuild;
+        let mut current: Option<OsmElement> = None;
+        let mut depth = 0;
+
+        if let Some(pending) = self.pending.take() {
+            let tag_name = String::from_utf8_lossy(pending.start.local_name().as_ref()).to_string();
+            let mut element = start_element(&tag_name, &pending.start, &self.action)?;
+            if rebuild {
+                self.reader.get_mut().stop_recording();
+                push_start_tag(&mut element.xml, &tag_name, &pending.start)?;
+                element.xml.push_str(if pending.empty { "/>" } else { ">" });
+            }
+            if pending.empty {
+                if !rebuild {
+                    element.xml = self.reader.get_mut().take_recorded()?;
+                }
+                return Ok(Some(element));
+            }
+            current = Some(element);
+            depth = 1;
+        }
+
+        while !self.finished {
+            self.buf.clear();
+            if !rebuild && current.is_none() {
+                self.reader.get_mut().start_recording();
+            }
+
+            match self.reader.read_event_into(&mut self.buf) {
+                Ok(Event::Start(ref e)) => {
+                    let tag_name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+
+                    if let Some(element) = current.as_mut() {
+                        depth += 1;
+                        if rebuild {
+                            push_start_tag(&mut element.xml, &tag_name, e)?;
+                            element.xml.push('>');
+                        }
+                        // `<tag ...></tag>` is as valid as `<tag .../>`.
+                        add_child(element, &tag_name, e)?;
+                        continue;
+                    }
+
+                    match tag_name.as_str() {
+                        "node" | "way" | "relation" => {
+                            let mut element = start_element(&tag_name, e, &self.action)?;
+                            if rebuild {
+                                push_start_tag(&mut element.xml, &tag_name, e)?;
+                                element.xml.push('>');
+                            }
+                            current = Some(element);
+                            depth = 1;
+                        }
+                        "create" | "modify" | "delete" => {
+                            self.action = Some(tag_name);
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(Event::End(ref e)) => {
+                    let tag_name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+
+                    match current.as_mut() {
+                        Some(element) => {
+                            if rebuild {
+                                element.xml.push_str(&format!("</{}>", tag_name));
+                            }
+                            depth -= 1;
+                            if depth == 0 {
+                                if !rebuild {
+                                    element.xml = self.reader.get_mut().take_recorded()?;
+                                }
+                                return Ok(current);
+                            }
+                        }
+                        None if tag_name == self.root.tag => self.finished = true,
+                        None => {
+                            if self.action.as_deref() == Some(tag_name.as_str()) {
+                                self.action = None;
+                            }
+                        }
+                    }
+                }
+                Ok(Event::Empty(ref e)) => {
+                    let tag_name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+
+                    match current.as_mut() {
+                        Some(element) => {
+                            if rebuild {
+                                push_start_tag(&mut element.xml, &tag_name, e)?;
+                                element.xml.push_str("/>");
+                            }
+                            add_child(element, &tag_name, e)?;
+                        }
+                        None => {
+                            if let "node" | "way" | "relation" = tag_name.as_str() {
+                                let mut element = start_element(&tag_name, e, &self.action)?;
+                                if rebuild {
+                                    push_start_tag(&mut element.xml, &tag_name, e)?;
+                                    element.xml.push_str("/>");
+                                } else {
+                                    element.xml = self.reader.get_mut().take_recorded()?;
+                                }
+                                return Ok(Some(element));
+                            }
+                        }
+                    }
+                }
+                Ok(Event::Text(ref e)) => {
+                    if let Some(element) = current.as_mut().filter(|_| rebuild) {
+                        // Entities arrive as separate `GeneralRef` events, so text
+                        // holds no escapes of its own.
+                        element.xml.push_str(&escape_xml(std::str::from_utf8(e)?));
+                    }
+                }
+                Ok(Event::GeneralRef(ref e)) => {
+                    if let Some(element) = current.as_mut().filter(|_| rebuild) {
+                        element.xml.push('&');
+                        element.xml.push_str(std::str::from_utf8(e)?);
+                        element.xml.push(';');
+                    }
+                }
+                Ok(Event::CData(ref e)) => {
+                    if let Some(element) = current.as_mut().filter(|_| rebuild) {
+                        element.xml.push_str("<![CDATA[");
+                        element.xml.push_str(std::str::from_utf8(e)?);
+                        element.xml.push_str("]]>");
+                    }
+                }
+                Ok(Event::Eof) => return Err(validation::truncated_xml()),
+                Err(e) => return Err(self.xml_error(e)),
+                _ => {}
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+fn start_element(tag_name: &str, e: &BytesStart, action: &Option<String>) -> Result<OsmElement> {
+    let id_attribute = get_attribute(e, "id")?.unwrap_or_default();
+    let id = id_attribute
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid {} id: {:?}", tag_name, id_attribute))?;
+    let version = match get_attribute(e, "version")? {
+        Some(version) => Some(
+            version
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid {} version: {:?}", tag_name, version))?,
+        ),
+        None => None,
+    };
+
+    Ok(OsmElement {
+        element_type: tag_name.parse().map_err(anyhow::Error::msg)?,
+        id,
+        version,
+        timestamp: get_attribute(e, "timestamp")?.unwrap_or_default(),
+        coordinates: get_coordinates(e)?,
+        node_refs: Vec::new(),
+        members: Vec::new(),
+        tags: Vec::new(),
+        action: action.clone(),
+        xml: String::new(),
+    })
+}
+
+/// Records a node reference, member or tag of the element being read.
+fn add_child(element: &mut OsmElement, tag_name: &str, e: &BytesStart) -> Result<()> {
+    match tag_name {
+        "nd" => element.node_refs.push(get_ref(e)?),
+        "member" => element.members.push(get_member_ref(e)?),
+        "tag" => element.tags.push(get_tag(e)?),
+        _ => {}
+    }
+    Ok(())
+}
+
+fn push_start_tag(buffer: &mut String, tag_name: &str, e: &BytesStart) -> Result<()> {
+    buffer.push_str(&format!("<{}", tag_name));
+    for attr in e.attributes() {
+        let attr = attr?;
+        let key = std::str::from_utf8(attr.key.as_ref())?;
+        // Values arrive still escaped; unescape first so entities are not escaped twice.
+        let value = attr.unescape_value()?;
+        buffer.push_str(&format!(" {}=\"{}\"", key, escape_xml(&value)));
+    }
+    Ok(())
+}
+
+pub(crate) fn escape_xml(value: &str) -> String {
+    value
+        .replace("&", "&amp;")
+        .replace("\"", "&quot;")
+        .replace("<", "&lt;")
+        .replace(">", "&gt;")
+}
+
+pub fn get_coordinates(e: &BytesStart) -> Result<Option<(f64, f64)>> {
+    match (get_attribute(e, "lat")?, get_attribute(e, "lon")?) {
+        (Some(lat), Some(lon)) => Ok(Some((lat.parse()?, lon.parse()?))),
+        _ => Ok(None),
+    }
+}
+
+fn get_ref(e: &BytesStart) -> Result<i64> {
+    let reference = get_attribute(e, "ref")?.unwrap_or_default();
+    reference
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid ref: {:?}", reference))
+}
+
+fn get_member_ref(e: &BytesStart) -> Result<MemberRef> {
+    let reference = get_ref(e)?;
+    Ok(match get_attribute(e, "type")?.as_deref() {
+        Some("node") => MemberRef::Node(reference),
+        Some("way") => MemberRef::Way(reference),
+        Some("relation") => MemberRef::Relation(reference),
+        _ => MemberRef::Other,
+    })
+}
+
+fn get_tag(e: &BytesStart) -> Result<(String, String)> {
+    let mut key = String::new();
+    let mut value = String::new();
+    for attr in e.attributes() {
+        let attr = attr?;
+        match attr.key.as_ref() {
+            b"k" => key = attr.unescape_value()?.to_string(),
+            b"v" => value = attr.unescape_value()?.to_string(),
+            _ => {}
+        }
+    }
+    Ok((key, value))
+}
+
+pub fn get_attribute(e: &BytesStart, key: &str) -> Result<Option<String>> {
+    for attr in e.attributes() {
+        let attr = attr?;
+        if attr.key.as_ref() == key.as_bytes() {
+            return Ok(Some(std::str::from_utf8(&attr.value)?.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = "<?xml version='1.0' encoding='UTF-8'?>
+<osm version=\"0.6\" generator='test'>
+  <node lon='90.1' id=\"1\"   lat = \"23.1\" version='1'/>
+  <way id='2' version='1'>
+\t<!-- first <nd> & only -->
+    <nd ref='1' />
+    <tag k='name' v='A &amp; B &#x41; &lt;&quot;&gt;'/>
+  </way>
+  <relation id='3'><member type='way' ref='2' role='outer'/><![CDATA[ <x> ]]></relation>
+</osm>
+";
+
+    fn read_all(output: ElementOutput) -> Vec<OsmElement> {
+        let mut reader = OsmReader::new(INPUT.as_bytes())
+            .unwrap()
+            .with_output(output);
+        let mut elements = Vec::new();
+        while let Some(element) = reader.next_element().unwrap() {
+            elements.push(element);
+        }
+        elements
+    }
+
+    #[test]
+    fn passthrough_keeps_elements_byte_identical() {
+        let elements = read_all(ElementOutput::Passthrough);
+        let xml: Vec<&str> = elements
+            .iter()
+            .map(|element| element.xml.as_str())
+            .collect();
+        assert_eq!(
+            xml,
+            [
+                "<node lon='90.1' id=\"1\"   lat = \"23.1\" version='1'/>",
+                "<way id='2' version='1'>\n\t<!-- first <nd> & only -->\n    <nd ref='1' />\n    <tag k='name' v='A &amp; B &#x41; &lt;&quot;&gt;'/>\n  </way>",
+                "<relation id='3'><member type='way' ref='2' role='outer'/><![CDATA[ <x> ]]></relation>",
+            ]
+        );
+        // Every element is a verbatim slice of the input.
+        assert!(xml.iter().all(|xml| INPUT.contains(xml)));
+
+        assert_eq!(elements[0].coordinates, Some((23.1, 90.1)));
+        assert_eq!(elements[1].node_refs, [1]);
+        assert_eq!(
+            elements[1].tags,
+            [("name".to_string(), "A & B A <\">".to_string())]
+        );
+        assert!(matches!(elements[2].members[..], [MemberRef::Way(2)]));
+    }
+
+    #[test]
+    fn rebuild_normalizes_quoting_and_whitespace() {
+        let elements = read_all(ElementOutput::Rebuild);
+        assert_eq!(
+            elements[0].xml,
+            "<node lon=\"90.1\" id=\"1\" lat=\"23.1\" version=\"1\"/>"
+        );
+        assert_eq!(
+            elements[1].xml,
+            "<way id=\"2\" version=\"1\"><nd ref=\"1\"/><tag k=\"name\" v=\"A &amp; B A &lt;&quot;&gt;\"/></way>"
+        );
+    }
+
+    #[test]
+    fn collects_children_written_with_end_tags() {
+        let input = "<osm><way id='1'><nd ref='7'></nd><tag k='a' v='b'>\n</tag></way>\
+            <relation id='2'><member type='node' ref='7' role=''></member></relation></osm>";
+        let mut reader = OsmReader::new(input.as_bytes()).unwrap();
+
+        let way = reader.next_element().unwrap().unwrap();
+        assert_eq!(way.node_refs, [7]);
+        assert_eq!(way.tags, [("a".to_string(), "b".to_string())]);
+        let relation = reader.next_element().unwrap().unwrap();
+        assert!(matches!(relation.members[..], [MemberRef::Node(7)]));
+        assert!(reader.next_element().unwrap().is_none());
+    }
+}
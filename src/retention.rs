@@ -0,0 +1,433 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::error::{self, ServiceError};
+use crate::validation::{self, InvalidInput};
+use crate::{storage, utils};
+use crate::{ElementType, ImportOptions, DATA_DIR};
+
+const IMPORT_TYPES: [&str; 5] = ["full", "delta", "history", "diff", "custom"];
+
+/// Downloaded and converted inputs, left-over partial files, and the checkpoints and
+/// journals of an interrupted batching run.
+const INTERMEDIATE_SUFFIXES: [&str; 10] = [
+    ".osm",
+    ".osh",
+    ".osc",
+    ".pbf",
+    ".gz",
+    ".bz2",
+    ".zst",
+    ".temp",
+    ".checkpoint",
+    "_journal",
+];
+
+#[derive(Debug)]
+pub struct ImportSummary {
+    pub import_type: String,
+    pub scope: String,
+    pub locked: bool,
+    pub batches_complete: bool,
+    /// Why the last attempt rejected the input, until it is retried.
+    pub invalid_input: Option<InvalidInput>,
+    /// Why the last attempt failed otherwise, until it is retried.
+    pub last_error: Option<ServiceError>,
+    pub size_bytes: u64,
+    pub last_modified: SystemTime,
+}
+
+#[derive(Debug)]
+pub enum DeleteImportStatus {
+    ImportDeleted,
+    ImportLocked,
+    ImportNotFound,
+    DeleteError(ServiceError),
+}
+
+/// Which imports to keep. Every rule is optional; an import whose lock is held is
+/// never touched.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    pub keep_full_imports: Option<usize>,
+    pub keep_delta_days: Option<u64>,
+    pub remove_intermediates: bool,
+}
+
+impl RetentionPolicy {
+    pub fn from_env() -> Result<Self, String> {
+        let parse = |name: &str| -> Result<Option<u64>, String> {
+            match std::env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| format!("{} must be a non-negative integer", name)),
+                Err(_) => Ok(None),
+            }
+        };
+        Ok(RetentionPolicy {
+            keep_full_imports: parse("RETENTION_KEEP_FULL_IMPORTS")?.map(|n| n as usize),
+            keep_delta_days: parse("RETENTION_KEEP_DELTA_DAYS")?,
+            remove_intermediates: parse("RETENTION_REMOVE_INTERMEDIATES")?.unwrap_or(0) != 0,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keep_full_imports.is_none()
+            && self.keep_delta_days.is_none()
+            && !self.remove_intermediates
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RetentionReport {
+    pub imports_deleted: usize,
+    pub intermediates_removed: usize,
+    pub bytes_freed: u64,
+}
+
+pub async fn list_imports() -> Result<Vec<ImportSummary>> {
+    let mut imports = Vec::new();
+    for import_type in IMPORT_TYPES {
+        let type_dir = format!("{}/{}", DATA_DIR, import_type);
+        let mut entries = match fs::read_dir(&type_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            let scope = entry.file_name().to_string_lossy().to_string();
+            if scope.ends_with(".deleting") {
+                continue;
+            }
+            imports.push(summarize_import(import_type, &scope, &entry.path()).await?);
+        }
+    }
+
+    imports.sort_by(|a, b| (&a.import_type, &a.scope).cmp(&(&b.import_type, &b.scope)));
+    Ok(imports)
+}
+
+async fn summarize_import(
+    import_type: &str,
+    scope: &str,
+    import_dir: &Path,
+) -> Result<ImportSummary> {
+    // Completion markers are read through storage, which is where finished batches
+    // live when they are shared through a bucket.
+    let stored = storage::storage()
+        .list(&import_dir.join("batches").to_string_lossy())
+        .await?;
+    let batches_complete = ElementType::ALL.iter().all(|element_type| {
+        let batches_dir = import_dir.join("batches").join(element_type.as_str());
+        stored.iter().any(|path| {
+            let path = Path::new(path);
+            path.parent() == Some(batches_dir.as_path())
+                && path.to_string_lossy().ends_with(".batches_complete")
+        })
+    });
+
+    Ok(ImportSummary {
+        import_type: import_type.to_string(),
+        scope: scope.to_string(),
+        locked: import_dir.join("lock").exists(),
+        batches_complete,
+        invalid_input: validation::recorded(&import_dir.to_string_lossy()).await,
+        last_error: error::recorded(&import_dir.to_string_lossy()).await,
+        size_bytes: directory_size(import_dir.to_path_buf()).await?,
+        last_modified: fs::metadata(import_dir).await?.modified()?,
+    })
+}
+
+async fn directory_size(dir: PathBuf) -> Result<u64> {
+    let mut size = 0;
+    let mut pending = vec![dir];
+    while let Some(dir) = pending.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else {
+                size += metadata.len();
+            }
+        }
+    }
+    Ok(size)
+}
+
+pub async fn delete_import(import_options: &ImportOptions) -> DeleteImportStatus {
+    let import_dir = import_options.get_import_dir();
+    // Another replica may have written the import to the bucket without it ever being
+    // on this disk.
+    let stored = match storage::storage().list(&import_dir).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            warn!("Failed to list import {}: {}", import_dir, e);
+            return DeleteImportStatus::DeleteError(ServiceError::storage(
+                "Failed to delete import",
+            ));
+        }
+    };
+    if !Path::new(&import_dir).exists() && stored.is_empty() {
+        return DeleteImportStatus::ImportNotFound;
+    }
+
+    match remove_import_dir(Path::new(&import_dir)).await {
+        Ok(true) => DeleteImportStatus::ImportDeleted,
+        Ok(false) => DeleteImportStatus::ImportLocked,
+        Err(e) => {
+            warn!("Failed to delete import {}: {}", import_dir, e);
+            DeleteImportStatus::DeleteError(ServiceError::storage("Failed to delete import"))
+        }
+    }
+}
+
+// The directory is renamed out of the way first so a request arriving mid-delete
+// starts a fresh import instead of finding half-removed batches. What the import
+// shared through the bucket is removed too, or other replicas would keep serving it.
+async fn remove_import_dir(import_dir: &Path) -> Result<bool> {
+    if import_dir.join("lock").exists() {
+        return Ok(false);
+    }
+
+    if import_dir.exists() {
+        let deleting_dir = import_dir.with_extension("deleting");
+        fs::rename(import_dir, &deleting_dir).await?;
+        if deleting_dir.join("lock").exists() {
+            fs::rename(&deleting_dir, import_dir).await?;
+            return Ok(false);
+        }
+        fs::remove_dir_all(&deleting_dir).await?;
+    }
+
+    storage::storage()
+        .remove_dir(&import_dir.to_string_lossy())
+        .await?;
+    Ok(true)
+}
+
+pub async fn apply_retention_policy(policy: &RetentionPolicy) -> Result<RetentionReport> {
+    let mut report = RetentionReport::default();
+    let imports = list_imports().await?;
+
+    let mut full_imports: Vec<&ImportSummary> =
+        imports.iter().filter(|i| i.import_type == "full").collect();
+    full_imports.sort_by(|a, b| b.scope.cmp(&a.scope));
+    let expired_full = policy
+        .keep_full_imports
+        .map(|keep| full_imports.split_off(keep.min(full_imports.len())))
+        .unwrap_or_default();
+
+    let mut expired_delta = Vec::new();
+    if let Some(days) = policy.keep_delta_days {
+        let cutoff = SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60);
+        for import in imports.iter().filter(|i| i.import_type == "delta") {
+            let import_dir = Path::new(DATA_DIR).join("delta").join(&import.scope);
+            match delta_timestamp(&import_dir, &import.scope).await {
+                Some(timestamp) if timestamp < cutoff => expired_delta.push(import),
+                Some(_) => {}
+                None => info!("Retention keeps delta {}, its age is unknown", import.scope),
+            }
+        }
+    }
+
+    for import in expired_full.into_iter().chain(expired_delta) {
+        let import_dir = Path::new(DATA_DIR)
+            .join(&import.import_type)
+            .join(&import.scope);
+        match remove_import_dir(&import_dir).await {
+            Ok(true) => {
+                info!(
+                    "Retention removed {} import {}",
+                    import.import_type, import.scope
+                );
+                report.imports_deleted += 1;
+                report.bytes_freed += import.size_bytes;
+            }
+            Ok(false) => info!("Retention skipped locked import {}", import_dir.display()),
+            Err(e) => warn!("Retention failed to remove {}: {}", import_dir.display(), e),
+        }
+    }
+
+    if policy.remove_intermediates {
+        for import in imports.iter().filter(|i| i.batches_complete && !i.locked) {
+            let import_dir = Path::new(DATA_DIR)
+                .join(&import.import_type)
+                .join(&import.scope);
+            if !import_dir.exists() {
+                continue;
+            }
+            let (removed, freed) = remove_intermediate_files(&import_dir).await?;
+            report.intermediates_removed += removed;
+            report.bytes_freed += freed;
+        }
+    }
+
+    Ok(report)
+}
+
+/// How old a delta is: the replication timestamp of its `state.txt`, or else when its
+/// batches were completed. Touching the import directory does not make it younger.
+async fn delta_timestamp(import_dir: &Path, scope: &str) -> Option<SystemTime> {
+    let state_file = import_dir.join(format!("{}.state.txt", scope));
+    if let Ok(Some(timestamp)) =
+        utils::state_replication_timestamp(&state_file.to_string_lossy()).await
+    {
+        match chrono::DateTime::parse_from_rfc3339(&timestamp) {
+            Ok(timestamp) => return Some(timestamp.into()),
+            Err(e) => warn!(
+                "Ignoring replication timestamp {:?} of {}: {}",
+                timestamp, scope, e
+            ),
+        }
+    }
+
+    let mut completed = None;
+    for element_type in ElementType::ALL {
+        let batches_dir = import_dir.join("batches").join(element_type.as_str());
+        let Ok(mut entries) = fs::read_dir(&batches_dir).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if !entry
+                .file_name()
+                .to_string_lossy()
+                .ends_with(".batches_complete")
+            {
+                continue;
+            }
+            if let Ok(modified) = entry.metadata().await.and_then(|m| m.modified()) {
+                completed = completed.max(Some(modified));
+            }
+        }
+    }
+    completed
+}
+
+// Inputs sit at the top of the import directory and checkpoints and journals under
+// `batches/`. Failure markers, replication state and everything clients read stay.
+async fn remove_intermediate_files(import_dir: &Path) -> Result<(usize, u64)> {
+    let mut removed = 0;
+    let mut freed = 0;
+    for dir in [import_dir.to_path_buf(), import_dir.join("batches")] {
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if metadata.is_dir() || !is_intermediate(&name) {
+                continue;
+            }
+            if import_dir.join("lock").exists() {
+                return Ok((removed, freed));
+            }
+            fs::remove_file(entry.path()).await?;
+            removed += 1;
+            freed += metadata.len();
+        }
+    }
+    Ok((removed, freed))
+}
+
+fn is_intermediate(file_name: &str) -> bool {
+    INTERMEDIATE_SUFFIXES
+        .iter()
+        .any(|suffix| file_name.ends_with(suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("retention-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("batches/node")).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn delta_age_comes_from_its_replication_state() {
+        let dir = test_dir("state");
+        std::fs::write(
+            dir.join("000_004_000.state.txt"),
+            "sequenceNumber=4000\ntimestamp=2024-01-01T20\\:21\\:02Z\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("batches/node/000_004_000.osc.batches_complete"),
+            "",
+        )
+        .unwrap();
+
+        let timestamp = delta_timestamp(&dir, "000_004_000").await.unwrap();
+        let expected = chrono::DateTime::parse_from_rfc3339("2024-01-01T20:21:02Z").unwrap();
+        assert_eq!(timestamp, SystemTime::from(expected));
+
+        // Without a state file the completion markers date the delta.
+        std::fs::remove_file(dir.join("000_004_000.state.txt")).unwrap();
+        let timestamp = delta_timestamp(&dir, "000_004_000").await.unwrap();
+        assert!(timestamp > SystemTime::from(expected));
+
+        std::fs::remove_file(dir.join("batches/node/000_004_000.osc.batches_complete")).unwrap();
+        assert!(delta_timestamp(&dir, "000_004_000").await.is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn removes_only_inputs_checkpoints_and_journals() {
+        let dir = test_dir("intermediates");
+        let intermediates = [
+            "000_004_000.osc.gz",
+            "250901.osm.pbf",
+            "250901.osm",
+            "250901.osm.temp",
+            "batches/250901.osm.checkpoint",
+            "batches/250901.osm.spatial_journal",
+        ];
+        let kept = [
+            "000_004_000.state.txt",
+            "invalid_input",
+            "import_error",
+            "batches/250901.osm.quality",
+            "batches/node/250901.osm.batch_000000.xml",
+        ];
+        for file in intermediates.iter().chain(&kept) {
+            std::fs::write(dir.join(file), "12345").unwrap();
+        }
+
+        let (removed, freed) = remove_intermediate_files(&dir).await.unwrap();
+        assert_eq!(
+            (removed, freed),
+            (intermediates.len(), 5 * intermediates.len() as u64)
+        );
+        for file in intermediates {
+            assert!(!dir.join(file).exists(), "{} was kept", file);
+        }
+        for file in kept {
+            assert!(dir.join(file).exists(), "{} was removed", file);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn leaves_locked_imports_alone() {
+        let dir = test_dir("locked");
+        std::fs::write(dir.join("lock"), "").unwrap();
+        std::fs::write(dir.join("250901.osm.pbf"), "12345").unwrap();
+        assert_eq!(remove_intermediate_files(&dir).await.unwrap(), (0, 0));
+        assert!(dir.join("250901.osm.pbf").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
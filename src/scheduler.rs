@@ -0,0 +1,158 @@
+use anyhow::Result;
+use chrono::Utc;
+use croner::Cron;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::fs;
+use tracing::info;
+
+use crate::jobs;
+use crate::provider::provider;
+use crate::{latest_full_date, DeltaAbc, ImportOptions, OsmFileType, DATA_DIR};
+
+#[derive(Debug, Clone, Copy)]
+pub enum PrewarmTarget {
+    Full,
+    Delta,
+}
+
+impl PrewarmTarget {
+    fn state_file(&self) -> String {
+        match self {
+            PrewarmTarget::Full => format!("{}/scheduler/last_full_date", DATA_DIR),
+            PrewarmTarget::Delta => format!("{}/scheduler/last_delta_sequence", DATA_DIR),
+        }
+    }
+}
+
+pub struct Schedule(Cron);
+
+impl Schedule {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        Cron::from_str(expression)
+            .map(Schedule)
+            .map_err(|e| format!("Invalid schedule {:?}: {}", expression, e))
+    }
+
+    pub fn until_next(&self) -> Result<Duration> {
+        let now = Utc::now();
+        let next = self.0.find_next_occurrence(&now, false)?;
+        Ok((next - now).to_std().unwrap_or_default())
+    }
+}
+
+/// Processes whatever upstream has published since the last run, through the same
+/// `process_osm_import` path a client request would take. Returns how many imports
+/// were processed.
+pub async fn prewarm(target: PrewarmTarget) -> Result<usize> {
+    match target {
+        PrewarmTarget::Full => prewarm_full_import().await,
+        PrewarmTarget::Delta => prewarm_delta_imports().await,
+    }
+}
+
+async fn prewarm_full_import() -> Result<usize> {
+    let Some(latest_date) = latest_full_date().await? else {
+        info!(
+            "No full extract published in the last {} days",
+            provider().full_extract_lookback_days()
+        );
+        return Ok(0);
+    };
+
+    let state_file = PrewarmTarget::Full.state_file();
+    if read_state(&state_file).await?.as_deref() == Some(latest_date.as_str()) {
+        return Ok(0);
+    }
+
+    let import_options = ImportOptions {
+        osm_file_type: OsmFileType::Full(latest_date.clone()),
+        base_path: "./data/".to_string(),
+    };
+    if !run_import(&import_options).await? {
+        return Ok(0);
+    }
+
+    write_state(&state_file, latest_date.as_str()).await?;
+    Ok(1)
+}
+
+async fn prewarm_delta_imports() -> Result<usize> {
+    let latest_sequence = fetch_latest_delta_sequence().await?;
+
+    let state_file = PrewarmTarget::Delta.state_file();
+    let first_sequence = match read_state(&state_file).await? {
+        Some(last_sequence) => last_sequence.parse::<u64>()? + 1,
+        // Without history only the newest delta is warmed; older ones stay lazy.
+        None => latest_sequence,
+    };
+
+    let mut processed = 0;
+    for sequence in first_sequence..=latest_sequence {
+        let import_options = ImportOptions {
+            osm_file_type: OsmFileType::Delta(
+                DeltaAbc::from_sequence(sequence).map_err(anyhow::Error::msg)?,
+            ),
+            base_path: "./data/".to_string(),
+        };
+        if !run_import(&import_options).await? {
+            break;
+        }
+        write_state(&state_file, &sequence.to_string()).await?;
+        processed += 1;
+    }
+
+    Ok(processed)
+}
+
+// An import that is already running is left to whoever runs it and retried on the
+// next run.
+async fn run_import(import_options: &ImportOptions) -> Result<bool> {
+    info!("⏰ Pre-warming {}", import_options.get_import_handle());
+    let processed = jobs::run_import(import_options).await?;
+    if !processed {
+        info!(
+            "Skipping {}, it is already being processed",
+            import_options.get_import_handle()
+        );
+    }
+    Ok(processed)
+}
+
+async fn fetch_latest_delta_sequence() -> Result<u64> {
+    let response = reqwest::get(provider().latest_state_url()?).await?;
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "Fetching delta state failed with status: {}",
+            response.status()
+        );
+    }
+
+    let state = response.text().await?;
+    state
+        .lines()
+        .find_map(|line| line.strip_prefix("sequenceNumber="))
+        .ok_or_else(|| anyhow::anyhow!("Delta state has no sequenceNumber"))?
+        .trim()
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid sequenceNumber in delta state: {}", e))
+}
+
+async fn read_state(state_file: &str) -> Result<Option<String>> {
+    match fs::read_to_string(state_file).await {
+        Ok(state) => Ok(Some(state.trim().to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn write_state(state_file: &str, state: &str) -> Result<()> {
+    if let Some(parent) = Path::new(state_file).parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let temp_file = format!("{}.temp", state_file);
+    fs::write(&temp_file, format!("{}\n", state)).await?;
+    fs::rename(&temp_file, state_file).await?;
+    Ok(())
+}
@@ -0,0 +1,685 @@
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use crate::error::ServiceError;
+use crate::utils::{hex, Compression};
+use crate::validation::{self, InputErrorCode};
+use crate::DATA_DIR;
+
+/// Hex digits of the content hash used as the import scope.
+const SCOPE_HASH_LENGTH: usize = 16;
+
+const DEFAULT_MAX_SOURCE_MB: u64 = 4096;
+
+/// How long a failed source is reported as failed before a request prepares it again.
+const FAILED_SOURCE_RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// What a custom source file contains once decompressed, detected from its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceFormat {
+    Osm,
+    OsmPbf,
+    Osc,
+}
+
+impl SourceFormat {
+    /// Whether the file is an `osmChange`, which is batched like a delta.
+    pub fn is_change(self) -> bool {
+        self == SourceFormat::Osc
+    }
+}
+
+/// A local file or HTTP(S) download identified by the hash of its content, so the same
+/// extract is batched once however it is named.
+#[derive(Debug, Clone)]
+pub struct CustomSource {
+    location: String,
+    path: String,
+    format: SourceFormat,
+    compression: Compression,
+    content_hash: String,
+}
+
+impl CustomSource {
+    /// The path or URL the source was requested by.
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    /// Where the file is on the local disk; a download's staging copy for URLs.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn format(&self) -> SourceFormat {
+        self.format
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// SHA-256 of the file, hex encoded.
+    pub fn content_hash(&self) -> &str {
+        &self.content_hash
+    }
+
+    pub(crate) fn scope(&self) -> &str {
+        &self.content_hash[..SCOPE_HASH_LENGTH]
+    }
+
+    fn is_url(&self) -> bool {
+        is_url(&self.location)
+    }
+}
+
+#[derive(Debug)]
+pub enum CustomSourceStatus {
+    SourceReady(CustomSource),
+    SourcePreparing,
+    SourceError(ServiceError),
+}
+
+struct PreparedSource {
+    modified: Option<SystemTime>,
+    size: u64,
+    source: CustomSource,
+}
+
+static PREPARED: LazyLock<Mutex<HashMap<String, PreparedSource>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+static PREPARING: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+static FAILED: LazyLock<Mutex<HashMap<String, (ServiceError, Instant)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn is_url(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Where a URL source is downloaded to, shared through the storage backend like
+/// Geofabrik downloads. URL sources are treated as immutable: the download is keyed
+/// by the URL alone and never revalidated, so changed data needs a new URL.
+fn download_path(url: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, url.as_bytes());
+    format!(
+        "{}/sources/{}.download",
+        DATA_DIR,
+        &hex(digest.as_ref())[..SCOPE_HASH_LENGTH]
+    )
+}
+
+/// Resolves a source for the API without blocking on downloads or hashing: the first
+/// request starts preparing it in the background and later ones see the result.
+/// Local paths must lie inside `CUSTOM_SOURCE_DIR`; without it only URLs are accepted.
+pub async fn resolve_custom_source(location: &str) -> CustomSourceStatus {
+    if let Err(e) = check_location_allowed(location) {
+        return CustomSourceStatus::SourceError(e);
+    }
+
+    {
+        let mut failed = FAILED.lock().unwrap();
+        match failed.get(location) {
+            Some((error, at)) if at.elapsed() < FAILED_SOURCE_RETRY_AFTER => {
+                return CustomSourceStatus::SourceError(error.clone());
+            }
+            Some(_) => {
+                failed.remove(location);
+            }
+            None => {}
+        }
+    }
+
+    match cached_source(location).await {
+        Ok(Some(source)) => return CustomSourceStatus::SourceReady(source),
+        Ok(None) => {}
+        Err(e) => return CustomSourceStatus::SourceError(ServiceError::from_import_error(&e)),
+    }
+
+    if PREPARING.lock().unwrap().insert(location.to_string()) {
+        let location = location.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = prepare_custom_source(&location, &CancellationToken::new()).await {
+                error!("💥 Failed to prepare source {}: {}", location, e);
+                let mut error = ServiceError::from_import_error(&e);
+                error.message = format!("Failed to prepare source: {}", error.message);
+                FAILED
+                    .lock()
+                    .unwrap()
+                    .insert(location.clone(), (error, Instant::now()));
+            }
+            PREPARING.lock().unwrap().remove(&location);
+        });
+    }
+    CustomSourceStatus::SourcePreparing
+}
+
+fn check_location_allowed(location: &str) -> Result<(), ServiceError> {
+    if location.is_empty() {
+        return Err(ServiceError::invalid_argument("source is empty"));
+    }
+    if is_url(location) {
+        return check_host_allowed(location);
+    }
+
+    let Ok(source_dir) = std::env::var("CUSTOM_SOURCE_DIR") else {
+        return Err(ServiceError::invalid_argument(
+            "Local sources are disabled (set CUSTOM_SOURCE_DIR)",
+        ));
+    };
+    let allowed = Path::new(&source_dir)
+        .canonicalize()
+        .ok()
+        .zip(Path::new(location).canonicalize().ok())
+        .is_some_and(|(source_dir, path)| path.starts_with(source_dir));
+    if !allowed {
+        return Err(ServiceError::invalid_argument(format!(
+            "Source {} is not a file inside CUSTOM_SOURCE_DIR",
+            location
+        )));
+    }
+    Ok(())
+}
+
+/// The hosts URL sources may come from, from `CUSTOM_SOURCE_HOSTS`. Without it any
+/// host is accepted, but only at public addresses.
+fn allowed_hosts() -> Option<Vec<String>> {
+    let hosts = std::env::var("CUSTOM_SOURCE_HOSTS").ok()?;
+    Some(
+        hosts
+            .split(',')
+            .map(|host| host.trim().to_ascii_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect(),
+    )
+}
+
+fn check_host_allowed(url: &str) -> Result<(), ServiceError> {
+    let url = reqwest::Url::parse(url)
+        .map_err(|e| ServiceError::invalid_argument(format!("Invalid source URL: {}", e)))?;
+    host_allowed(&url, allowed_hosts().as_deref()).map_err(ServiceError::invalid_argument)
+}
+
+/// Checks what can be told from the URL alone. Host names are checked again when they
+/// are resolved, by [`PublicAddresses`].
+fn host_allowed(url: &reqwest::Url, allowed_hosts: Option<&[String]>) -> Result<(), String> {
+    let Some(host) = url.host_str().map(str::to_ascii_lowercase) else {
+        return Err("Source URL has no host".to_string());
+    };
+    if let Some(hosts) = allowed_hosts {
+        return match hosts.contains(&host) {
+            true => Ok(()),
+            false => Err(format!(
+                "Source host {} is not in CUSTOM_SOURCE_HOSTS",
+                host
+            )),
+        };
+    }
+    // IPv6 hosts come in brackets.
+    match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) if !is_public(ip) => Err(format!("Source host {} is not a public address", host)),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `ip` is reachable on the internet, rather than loopback, private,
+/// link-local (which includes cloud metadata services) or otherwise reserved.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // Carrier-grade NAT, 100.64.0.0/10.
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    // Unique local fc00::/7 and link-local fe80::/10.
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Resolves host names for source downloads to their public addresses only, so a name
+/// pointing at an internal service, before or after a redirect, is never connected to.
+struct PublicAddresses;
+
+impl reqwest::dns::Resolve for PublicAddresses {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|address| is_public(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// The client URL sources are downloaded with. Redirects are checked like the URL the
+/// client asked for.
+fn source_client() -> Result<reqwest::Client> {
+    let hosts = allowed_hosts();
+    let public_only = hosts.is_none();
+    let mut builder =
+        reqwest::Client::builder().redirect(reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= 10 {
+                return attempt.error("too many redirects");
+            }
+            match host_allowed(attempt.url(), hosts.as_deref()) {
+                Ok(()) => attempt.follow(),
+                Err(e) => attempt.error(e),
+            }
+        }));
+    // Allowed hosts may well be internal mirrors.
+    if public_only {
+        builder = builder.dns_resolver(Arc::new(PublicAddresses));
+    }
+    Ok(builder.build()?)
+}
+
+fn max_source_bytes() -> u64 {
+    std::env::var("CUSTOM_SOURCE_MAX_MB")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MAX_SOURCE_MB)
+        * 1024
+        * 1024
+}
+
+/// Like `fetch_source_file`, but through [`source_client`] and with the size capped,
+/// since the URL comes from a client.
+async fn fetch_url_source(url: &str, path: &str, cancel: &CancellationToken) -> Result<()> {
+    let storage = crate::storage::storage();
+    if storage.fetch(path).await? {
+        return crate::check_source_file(path).await;
+    }
+
+    let client = source_client()?;
+    crate::utils::download_file_with(&client, url, path, Some(max_source_bytes()), cancel).await?;
+    crate::check_source_file(path).await?;
+    storage.upload(path).await
+}
+
+/// A previous result for `location`, unless the local file changed since.
+async fn cached_source(location: &str) -> Result<Option<CustomSource>> {
+    let path = if is_url(location) {
+        download_path(location)
+    } else {
+        location.to_string()
+    };
+    let Ok(metadata) = tokio::fs::metadata(&path).await else {
+        return Ok(None);
+    };
+
+    let prepared = PREPARED.lock().unwrap();
+    Ok(prepared
+        .get(location)
+        .filter(|prepared| {
+            prepared.size == metadata.len() && prepared.modified == metadata.modified().ok()
+        })
+        .map(|prepared| prepared.source.clone()))
+}
+
+/// Downloads a URL source if not downloaded yet, then hashes the file and detects its
+/// format. An existing download is reused as is; see [`download_path`].
+pub async fn prepare_custom_source(
+    location: &str,
+    cancel: &CancellationToken,
+) -> Result<CustomSource> {
+    if let Some(source) = cached_source(location).await? {
+        return Ok(source);
+    }
+
+    let path = if is_url(location) {
+        let path = download_path(location);
+        if !Path::new(&path).exists() {
+            info!("⬇️ Downloading source {}", location);
+            fetch_url_source(location, &path, cancel).await?;
+        }
+        path
+    } else {
+        if !Path::new(location).is_file() {
+            return Err(ServiceError::invalid_argument(format!(
+                "Source file {} does not exist",
+                location
+            ))
+            .into());
+        }
+        location.to_string()
+    };
+
+    let metadata = tokio::fs::metadata(&path).await?;
+    let hashed_path = path.clone();
+    let (content_hash, (format, compression)) =
+        tokio::task::spawn_blocking(move || hash_and_detect(&hashed_path)).await??;
+
+    let source = CustomSource {
+        location: location.to_string(),
+        path,
+        format,
+        compression,
+        content_hash,
+    };
+    info!(
+        "🔎 Source {} is {:?} ({:?} compression) with content hash {}",
+        location,
+        source.format,
+        source.compression,
+        source.scope()
+    );
+
+    PREPARED.lock().unwrap().insert(
+        location.to_string(),
+        PreparedSource {
+            modified: metadata.modified().ok(),
+            size: metadata.len(),
+            source: source.clone(),
+        },
+    );
+    Ok(source)
+}
+
+fn hash_and_detect(path: &str) -> Result<(String, (SourceFormat, Compression))> {
+    let mut file = std::fs::File::open(path)?;
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        context.update(&buffer[..read]);
+    }
+    let content_hash = hex(context.finish().as_ref());
+    Ok((content_hash, detect_format(path)?))
+}
+
+fn detect_format(path: &str) -> Result<(SourceFormat, Compression)> {
+    let mut head = Vec::new();
+    std::fs::File::open(path)?
+        .take(4096)
+        .read_to_end(&mut head)?;
+
+    // A PBF file starts with the length and type of its first blob.
+    if head.get(4..).is_some_and(|blob_header| {
+        blob_header
+            .windows(b"OSMHeader".len())
+            .take(8)
+            .any(|window| window == b"OSMHeader")
+    }) {
+        return Ok((SourceFormat::OsmPbf, Compression::None));
+    }
+
+    let compression = if head.starts_with(&[0x1f, 0x8b]) {
+        Compression::Gzip
+    } else if head.starts_with(b"BZh") {
+        Compression::Bzip2
+    } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Compression::Zstd
+    } else {
+        Compression::None
+    };
+    if compression != Compression::None {
+        let file = Box::new(std::io::BufReader::new(std::fs::File::open(path)?));
+        head.clear();
+        compression
+            .decoder(file)?
+            .take(4096)
+            .read_to_end(&mut head)?;
+    }
+
+    match validation::root_tag(&head) {
+        Some("osm") => Ok((SourceFormat::Osm, compression)),
+        Some("osmChange") => Ok((SourceFormat::Osc, compression)),
+        _ => Err(validation::invalid_input(
+            InputErrorCode::UnknownFormat,
+            format!("{} is neither OSM XML, osmChange nor PBF", path),
+        )),
+    }
+}
+
+/// Puts the source next to its batches as `xml_file`, or as `xml_file` plus the
+/// compression extension since batching decompresses as it reads. Returns the path.
+pub(crate) async fn prepare_input(
+    source: &CustomSource,
+    xml_file: &str,
+    cancel: &CancellationToken,
+) -> Result<String> {
+    if source.format == SourceFormat::OsmPbf {
+        crate::utils::convert_pbf_to_xml(&source.path, xml_file, cancel).await?;
+        return Ok(xml_file.to_string());
+    }
+
+    let input_file = format!("{}{}", xml_file, source.compression.extension());
+    if !Path::new(&input_file).exists() {
+        // Batching may outlive the source file's name, so it reads its own link to the
+        // data, or a copy where the import dir is on another filesystem.
+        let temp_file = format!("{}.temp", input_file);
+        if tokio::fs::hard_link(&source.path, &temp_file)
+            .await
+            .is_err()
+        {
+            tokio::fs::copy(&source.path, &temp_file).await?;
+        }
+        tokio::fs::rename(&temp_file, &input_file).await?;
+    }
+    Ok(input_file)
+}
+
+/// The source URL written into batch headers; local paths stay private to the server.
+pub(crate) fn provenance_source(source: &CustomSource) -> Option<String> {
+    source.is_url().then(|| source.location.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::MetadataExt;
+
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("source-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn detects_format_from_content() {
+        let dir = test_dir("detect");
+        let osm = dir.join("extract.bin");
+        std::fs::write(&osm, "<?xml version='1.0'?>\n<osm version=\"0.6\"></osm>\n").unwrap();
+        let osc = dir.join("change.bin");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(b"<?xml version='1.0'?>\n<osmChange version=\"0.6\"></osmChange>\n")
+            .unwrap();
+        std::fs::write(&osc, encoder.finish().unwrap()).unwrap();
+
+        assert_eq!(
+            detect_format(osm.to_str().unwrap()).unwrap(),
+            (SourceFormat::Osm, Compression::None)
+        );
+        assert_eq!(
+            detect_format(osc.to_str().unwrap()).unwrap(),
+            (SourceFormat::Osc, Compression::Gzip)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn prepare_input_links_the_source() {
+        let dir = test_dir("link");
+        let path = dir.join("extract.osm");
+        std::fs::write(&path, "<osm version=\"0.6\"></osm>\n").unwrap();
+        let (content_hash, (format, compression)) =
+            hash_and_detect(path.to_str().unwrap()).unwrap();
+        assert_eq!(content_hash.len(), 64);
+        let source = CustomSource {
+            location: path.to_str().unwrap().to_string(),
+            path: path.to_str().unwrap().to_string(),
+            format,
+            compression,
+            content_hash,
+        };
+
+        let xml_file = dir.join("import.osm");
+        let input = prepare_input(
+            &source,
+            xml_file.to_str().unwrap(),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::metadata(&input).unwrap().ino(),
+            std::fs::metadata(&path).unwrap().ino()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recognizes_non_public_addresses() {
+        for ip in [
+            "10.0.0.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "100.128.0.1", "2a00:1450:4001::1"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn checks_source_hosts() {
+        let url = |url: &str| reqwest::Url::parse(url).unwrap();
+        assert!(host_allowed(&url("http://169.254.169.254/latest/meta-data/"), None).is_err());
+        assert!(host_allowed(&url("http://[::1]:8080/extract.osm"), None).is_err());
+        assert!(host_allowed(&url("http://10.1.2.3/extract.osm"), None).is_err());
+        assert!(host_allowed(&url("https://example.org/extract.osm"), None).is_ok());
+
+        let hosts = ["mirror.internal".to_string()];
+        assert!(host_allowed(&url("http://Mirror.Internal/extract.osm"), Some(&hosts)).is_ok());
+        assert!(host_allowed(&url("https://example.org/extract.osm"), Some(&hosts)).is_err());
+    }
+
+    #[tokio::test]
+    async fn retries_failed_sources_after_an_interval() {
+        let location = "https://example.org/retry-test.osm";
+        let error = ServiceError::invalid_argument("broken source");
+        FAILED
+            .lock()
+            .unwrap()
+            .insert(location.to_string(), (error.clone(), Instant::now()));
+        assert!(matches!(
+            resolve_custom_source(location).await,
+            CustomSourceStatus::SourceError(e) if e.message == "broken source"
+        ));
+
+        // Marked as preparing so the retry does not start a download.
+        PREPARING.lock().unwrap().insert(location.to_string());
+        let failed_at = Instant::now() - FAILED_SOURCE_RETRY_AFTER;
+        FAILED
+            .lock()
+            .unwrap()
+            .insert(location.to_string(), (error, failed_at));
+        assert!(matches!(
+            resolve_custom_source(location).await,
+            CustomSourceStatus::SourcePreparing
+        ));
+        assert!(!FAILED.lock().unwrap().contains_key(location));
+        PREPARING.lock().unwrap().remove(location);
+    }
+
+    /// Serves `response` once on a local port and returns its URL.
+    async fn serve_once(response: String) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+        format!("http://{}/extract.osm", address)
+    }
+
+    #[tokio::test]
+    async fn caps_download_size() {
+        let dir = test_dir("cap");
+        let output = dir.join("extract.osm");
+        let output = output.to_str().unwrap();
+        let body = "x".repeat(100);
+        let client = reqwest::Client::new();
+        let cancel = CancellationToken::new();
+
+        let declared = serve_once(format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 100\r\nConnection: close\r\n\r\n{}",
+            body
+        ))
+        .await;
+        let e = crate::utils::download_file_with(&client, &declared, output, Some(50), &cancel)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("larger than the limit"), "{}", e);
+
+        // Without a Content-Length the limit is enforced while streaming.
+        let streamed = serve_once(format!(
+            "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n{}",
+            body
+        ))
+        .await;
+        let e = crate::utils::download_file_with(&client, &streamed, output, Some(50), &cancel)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("larger than the limit"), "{}", e);
+        assert!(!Path::new(&format!("{}.temp", output)).exists());
+        assert!(!Path::new(output).exists());
+
+        let small = serve_once(format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 100\r\nConnection: close\r\n\r\n{}",
+            body
+        ))
+        .await;
+        crate::utils::download_file_with(&client, &small, output, Some(100), &cancel)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(output).unwrap(), body);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
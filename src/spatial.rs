@@ -0,0 +1,365 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::{Read, SeekFrom, Write};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, BufReader};
+
+use crate::reader::MemberRef;
+use crate::ElementType;
+
+// 0.1° cells: fine enough to cut a country extract into small areas, coarse enough
+// that a city-sized query only touches a handful of cells.
+const CELLS_PER_DEGREE: f64 = 10.0;
+const GRID_COLUMNS: u32 = 360 * CELLS_PER_DEGREE as u32;
+const GRID_ROWS: u32 = 180 * CELLS_PER_DEGREE as u32;
+
+// cell (u32) + id (i64), little endian, sorted by (cell, id).
+const RECORD_SIZE: u64 = 12;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BoundingBox {
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+}
+
+impl BoundingBox {
+    pub fn new(min_lon: f64, min_lat: f64, max_lon: f64, max_lat: f64) -> Result<Self, String> {
+        let valid_lon = |lon: f64| (-180.0..=180.0).contains(&lon);
+        let valid_lat = |lat: f64| (-90.0..=90.0).contains(&lat);
+        if !(valid_lon(min_lon) && valid_lon(max_lon) && valid_lat(min_lat) && valid_lat(max_lat)) {
+            return Err("Invalid bbox: coordinates out of range".to_string());
+        }
+        if min_lon > max_lon || min_lat > max_lat {
+            return Err("Invalid bbox: min must not exceed max".to_string());
+        }
+        Ok(BoundingBox {
+            min_lon,
+            min_lat,
+            max_lon,
+            max_lat,
+        })
+    }
+
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        (self.min_lat..=self.max_lat).contains(&lat) && (self.min_lon..=self.max_lon).contains(&lon)
+    }
+}
+
+fn cell_column(lon: f64) -> u32 {
+    (((lon + 180.0) * CELLS_PER_DEGREE) as u32).min(GRID_COLUMNS - 1)
+}
+
+fn cell_row(lat: f64) -> u32 {
+    (((lat + 90.0) * CELLS_PER_DEGREE) as u32).min(GRID_ROWS - 1)
+}
+
+fn cell_for(lat: f64, lon: f64) -> u32 {
+    cell_row(lat) * GRID_COLUMNS + cell_column(lon)
+}
+
+/// Collects grid cells per element while batching. Nodes carry their own coordinates;
+/// ways take the cells of their nodes and relations the cells of their node and way
+/// members, as far as those appear earlier in the same file.
+///
+/// With a journal, every element's cells are also appended to a file so an interrupted
+/// run can rebuild this state instead of re-reading the input from the start.
+///
+/// Memory grows with the input: the cell of every node is kept until the end so ways
+/// and relations can look it up, roughly 16 bytes per node plus hash map overhead, or a
+/// few GB for a large country extract.
+#[derive(Default)]
+pub struct SpatialIndexBuilder {
+    node_cells: HashMap<i64, u32>,
+    way_cells: HashMap<i64, Vec<u32>>,
+    entries: HashMap<ElementType, Vec<(u32, i64)>>,
+    journal: Option<std::io::BufWriter<std::fs::File>>,
+    journal_len: u64,
+}
+
+impl SpatialIndexBuilder {
+    pub fn with_journal(journal_path: &str) -> Result<Self> {
+        Ok(SpatialIndexBuilder {
+            journal: Some(std::io::BufWriter::new(std::fs::File::create(
+                journal_path,
+            )?)),
+            ..Default::default()
+        })
+    }
+
+    /// Replays the first `journal_len` bytes of a journal, as reported by `checkpoint`,
+    /// and keeps appending after them.
+    pub fn resume(journal_path: &str, journal_len: u64) -> Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(journal_path)?;
+        if file.metadata()?.len() < journal_len {
+            anyhow::bail!("{} is shorter than its checkpoint", journal_path);
+        }
+        file.set_len(journal_len)?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut builder = SpatialIndexBuilder::default();
+        let mut rest = bytes.as_slice();
+        while !rest.is_empty() {
+            // element type (u8) + id (i64) + cell count (u32) + cells (u32 each)
+            let (header, tail) = rest
+                .split_at_checked(13)
+                .ok_or_else(|| anyhow::anyhow!("Truncated spatial journal record"))?;
+            let element_type = *ElementType::ALL
+                .get(header[0] as usize)
+                .ok_or_else(|| anyhow::anyhow!("Invalid spatial journal record"))?;
+            let id = i64::from_le_bytes(header[1..9].try_into().unwrap());
+            let count = u32::from_le_bytes(header[9..13].try_into().unwrap()) as usize;
+            let (cell_bytes, tail) = tail
+                .split_at_checked(count * 4)
+                .ok_or_else(|| anyhow::anyhow!("Truncated spatial journal record"))?;
+            let cells: Vec<u32> = cell_bytes
+                .chunks_exact(4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect();
+            builder.insert(element_type, id, cells);
+            rest = tail;
+        }
+
+        builder.journal = Some(std::io::BufWriter::new(file));
+        builder.journal_len = journal_len;
+        Ok(builder)
+    }
+
+    /// Flushes the journal and returns its length.
+    pub fn checkpoint(&mut self) -> Result<u64> {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.flush()?;
+        }
+        Ok(self.journal_len)
+    }
+
+    pub fn add_node(&mut self, id: i64, lat: f64, lon: f64) -> Result<()> {
+        self.record(ElementType::Node, id, vec![cell_for(lat, lon)])
+    }
+
+    pub fn add_way(&mut self, id: i64, node_refs: &[i64]) -> Result<()> {
+        let mut cells: Vec<u32> = node_refs
+            .iter()
+            .filter_map(|node_id| self.node_cells.get(node_id).copied())
+            .collect();
+        cells.sort_unstable();
+        cells.dedup();
+        self.record(ElementType::Way, id, cells)
+    }
+
+    pub fn add_relation(&mut self, id: i64, members: &[MemberRef]) -> Result<()> {
+        let mut cells = Vec::new();
+        for member in members {
+            match member {
+                MemberRef::Node(node_id) => cells.extend(self.node_cells.get(node_id)),
+                MemberRef::Way(way_id) => {
+                    cells.extend(self.way_cells.get(way_id).into_iter().flatten())
+                }
+                MemberRef::Relation(_) | MemberRef::Other => {}
+            }
+        }
+        cells.sort_unstable();
+        cells.dedup();
+        self.record(ElementType::Relation, id, cells)
+    }
+
+    fn record(&mut self, element_type: ElementType, id: i64, cells: Vec<u32>) -> Result<()> {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.write_all(&[element_type.index() as u8])?;
+            journal.write_all(&id.to_le_bytes())?;
+            journal.write_all(&(cells.len() as u32).to_le_bytes())?;
+            for cell in &cells {
+                journal.write_all(&cell.to_le_bytes())?;
+            }
+            self.journal_len += 13 + 4 * cells.len() as u64;
+        }
+        self.insert(element_type, id, cells);
+        Ok(())
+    }
+
+    fn insert(&mut self, element_type: ElementType, id: i64, cells: Vec<u32>) {
+        let entries = self.entries.entry(element_type).or_default();
+        entries.extend(cells.iter().map(|&cell| (cell, id)));
+        match element_type {
+            ElementType::Node => {
+                if let Some(&cell) = cells.first() {
+                    self.node_cells.insert(id, cell);
+                }
+            }
+            ElementType::Way => {
+                self.way_cells.insert(id, cells);
+            }
+            ElementType::Relation => {}
+        }
+    }
+
+    pub async fn write(
+        &mut self,
+        element_type: ElementType,
+        spatial_index_path: &str,
+    ) -> Result<()> {
+        let mut entries = self.entries.remove(&element_type).unwrap_or_default();
+        entries.sort_unstable();
+        entries.dedup();
+
+        let mut bytes = Vec::with_capacity(entries.len() * RECORD_SIZE as usize);
+        for (cell, id) in entries {
+            bytes.extend_from_slice(&cell.to_le_bytes());
+            bytes.extend_from_slice(&id.to_le_bytes());
+        }
+
+        let temp_path = format!("{}.temp", spatial_index_path);
+        fs::write(&temp_path, bytes).await?;
+        fs::rename(&temp_path, spatial_index_path).await?;
+        Ok(())
+    }
+}
+
+/// Returns the ids of every element that has at least one cell overlapping `bbox`.
+/// Cells in one grid row are contiguous, so each row costs one binary search.
+pub async fn find_ids_in_bbox(spatial_index_path: &str, bbox: &BoundingBox) -> Result<Vec<i64>> {
+    let file = fs::File::open(spatial_index_path).await?;
+    let record_count = file.metadata().await?.len() / RECORD_SIZE;
+    // Buffered so scanning the records of a row does not cost a read per record.
+    let mut file = BufReader::new(file);
+    let mut buffer = [0u8; RECORD_SIZE as usize];
+
+    let mut ids = Vec::new();
+    for row in cell_row(bbox.min_lat)..=cell_row(bbox.max_lat) {
+        let first_cell = row * GRID_COLUMNS + cell_column(bbox.min_lon);
+        let last_cell = row * GRID_COLUMNS + cell_column(bbox.max_lon);
+
+        let mut low = 0;
+        let mut high = record_count;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            file.seek(SeekFrom::Start(mid * RECORD_SIZE)).await?;
+            file.read_exact(&mut buffer).await?;
+            if u32::from_le_bytes(buffer[0..4].try_into().unwrap()) < first_cell {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        file.seek(SeekFrom::Start(low * RECORD_SIZE)).await?;
+        for _ in low..record_count {
+            file.read_exact(&mut buffer).await?;
+            if u32::from_le_bytes(buffer[0..4].try_into().unwrap()) > last_cell {
+                break;
+            }
+            ids.push(i64::from_le_bytes(buffer[4..12].try_into().unwrap()));
+        }
+    }
+
+    ids.sort_unstable();
+    ids.dedup();
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn finds_elements_by_cell() {
+        let dir = std::env::temp_dir().join(format!("spatial-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let mut builder = SpatialIndexBuilder::default();
+        // Two rows of nodes around Dhaka and one far away in Lisbon.
+        for id in 1..=200 {
+            let lat = if id % 2 == 0 { 23.71 } else { 23.81 };
+            builder
+                .add_node(id, lat, 90.30 + id as f64 * 0.001)
+                .unwrap();
+        }
+        builder.add_node(1000, 38.72, -9.14).unwrap();
+        builder.add_way(1, &[2, 3]).unwrap();
+        builder.add_way(2, &[1000, 99_999]).unwrap();
+        builder
+            .add_relation(1, &[MemberRef::Way(2), MemberRef::Node(4)])
+            .unwrap();
+        for element_type in ElementType::ALL {
+            builder
+                .write(element_type, &path(element_type.as_str()))
+                .await
+                .unwrap();
+        }
+
+        let dhaka = BoundingBox::new(90.0, 23.5, 90.6, 24.0).unwrap();
+        let nodes = find_ids_in_bbox(&path("node"), &dhaka).await.unwrap();
+        assert_eq!(nodes, (1..=200).collect::<Vec<i64>>());
+        let upper_row = BoundingBox::new(90.0, 23.8, 90.6, 24.0).unwrap();
+        let nodes = find_ids_in_bbox(&path("node"), &upper_row).await.unwrap();
+        assert_eq!(nodes, (1..=200).step_by(2).collect::<Vec<i64>>());
+
+        assert_eq!(find_ids_in_bbox(&path("way"), &dhaka).await.unwrap(), [1]);
+        let lisbon = BoundingBox::new(-9.2, 38.7, -9.1, 38.8).unwrap();
+        assert_eq!(find_ids_in_bbox(&path("way"), &lisbon).await.unwrap(), [2]);
+        assert_eq!(
+            find_ids_in_bbox(&path("relation"), &lisbon).await.unwrap(),
+            [1]
+        );
+        assert_eq!(
+            find_ids_in_bbox(&path("relation"), &dhaka).await.unwrap(),
+            [1]
+        );
+        let nowhere = BoundingBox::new(0.0, 0.0, 1.0, 1.0).unwrap();
+        assert!(find_ids_in_bbox(&path("node"), &nowhere)
+            .await
+            .unwrap()
+            .is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_boxes() {
+        assert!(BoundingBox::new(-181.0, 0.0, 0.0, 1.0).is_err());
+        assert!(BoundingBox::new(0.0, 1.0, 1.0, 0.0).is_err());
+        assert!(BoundingBox::new(0.0, 0.0, 0.0, 0.0).is_ok());
+    }
+
+    #[tokio::test]
+    async fn journal_replays_up_to_the_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("spatial-journal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let mut builder = SpatialIndexBuilder::with_journal(&path("journal")).unwrap();
+        builder.add_node(1, 23.71, 90.40).unwrap();
+        builder.add_node(2, 38.72, -9.14).unwrap();
+        let journal_len = builder.checkpoint().unwrap();
+        builder.add_node(3, 23.71, 90.40).unwrap();
+        builder.checkpoint().unwrap();
+        drop(builder);
+
+        // Ways written after the resume still find the cells of replayed nodes.
+        let mut builder = SpatialIndexBuilder::resume(&path("journal"), journal_len).unwrap();
+        builder.add_way(1, &[2, 3]).unwrap();
+        for element_type in [ElementType::Node, ElementType::Way] {
+            builder
+                .write(element_type, &path(element_type.as_str()))
+                .await
+                .unwrap();
+        }
+
+        let dhaka = BoundingBox::new(90.0, 23.5, 90.6, 24.0).unwrap();
+        let lisbon = BoundingBox::new(-9.2, 38.7, -9.1, 38.8).unwrap();
+        assert_eq!(find_ids_in_bbox(&path("node"), &dhaka).await.unwrap(), [1]);
+        assert!(find_ids_in_bbox(&path("way"), &dhaka)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(find_ids_in_bbox(&path("way"), &lisbon).await.unwrap(), [1]);
+        assert!(SpatialIndexBuilder::resume(&path("journal"), journal_len + 100).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
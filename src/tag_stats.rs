@@ -0,0 +1,536 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use tokio::fs;
+
+use crate::ElementType;
+
+/// Distinct values counted per key and element type. Later values of keys such as
+/// `name` are only counted in total.
+const MAX_VALUES_PER_KEY: usize = 1000;
+
+/// Whether batching gathers tag statistics. Set `TAG_STATISTICS=1` to enable them.
+pub(crate) fn enabled() -> bool {
+    std::env::var("TAG_STATISTICS").is_ok_and(|value| value == "1")
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TypeCounts {
+    pub nodes: u64,
+    pub ways: u64,
+    pub relations: u64,
+}
+
+impl TypeCounts {
+    pub fn total(&self) -> u64 {
+        self.nodes + self.ways + self.relations
+    }
+
+    fn add(&mut self, element_type: ElementType, count: u64) {
+        match element_type {
+            ElementType::Node => self.nodes += count,
+            ElementType::Way => self.ways += count,
+            ElementType::Relation => self.relations += count,
+        }
+    }
+}
+
+/// How often a key, or a value of one key, is used.
+#[derive(Debug, Clone)]
+pub struct TagCount {
+    pub text: String,
+    pub counts: TypeCounts,
+    /// Distinct values counted for a key; zero for values.
+    pub distinct_values: u64,
+    /// Whether the key had more distinct values than were counted.
+    pub values_capped: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TagStatistics {
+    pub elements: TypeCounts,
+    pub tagged_elements: TypeCounts,
+    /// The most used keys, or only the requested key.
+    pub keys: Vec<TagCount>,
+    /// The most used values of the requested key.
+    pub values: Vec<TagCount>,
+}
+
+#[derive(Default)]
+struct KeyStatistics {
+    count: u64,
+    values: HashMap<String, u64>,
+    /// Uses of values beyond `MAX_VALUES_PER_KEY`.
+    other_values: u64,
+}
+
+/// Tag usage of one element type.
+#[derive(Default)]
+pub(crate) struct TypeStatistics {
+    elements: u64,
+    tagged: u64,
+    keys: HashMap<String, KeyStatistics>,
+}
+
+// `elements` and `tagged` lines, then a `key count other key` line per key
+// followed by a `value count value` line per counted value. Keys and values come last
+// on their line, with backslashes and newlines escaped.
+impl TypeStatistics {
+    async fn write(&self, path: &str) -> Result<()> {
+        let mut content = format!("elements {}\ntagged {}\n", self.elements, self.tagged);
+        let mut keys: Vec<_> = self.keys.iter().collect();
+        keys.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(b.0)));
+        for (key, statistics) in keys {
+            content.push_str(&format!(
+                "key {} {} {}\n",
+                statistics.count,
+                statistics.other_values,
+                escape(key)
+            ));
+            let mut values: Vec<_> = statistics.values.iter().collect();
+            values.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+            for (value, count) in values {
+                content.push_str(&format!("value {} {}\n", count, escape(value)));
+            }
+        }
+
+        let temp_path = format!("{}.temp", path);
+        fs::write(&temp_path, content).await?;
+        fs::rename(&temp_path, path).await?;
+        Ok(())
+    }
+
+    pub(crate) fn parse(content: &str) -> Result<Self> {
+        let mut statistics = TypeStatistics::default();
+        let mut current_key: Option<String> = None;
+        for line in content.lines() {
+            let (kind, rest) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow::anyhow!("Invalid tag statistics line {:?}", line))?;
+            match kind {
+                "elements" => statistics.elements = rest.parse()?,
+                "tagged" => statistics.tagged = rest.parse()?,
+                "key" => {
+                    let mut fields = rest.splitn(3, ' ');
+                    let (Some(count), Some(other_values), Some(key)) =
+                        (fields.next(), fields.next(), fields.next())
+                    else {
+                        anyhow::bail!("Invalid tag statistics key {:?}", line);
+                    };
+                    let key = unescape(key);
+                    statistics.keys.insert(
+                        key.clone(),
+                        KeyStatistics {
+                            count: count.parse()?,
+                            values: HashMap::new(),
+                            other_values: other_values.parse()?,
+                        },
+                    );
+                    current_key = Some(key);
+                }
+                "value" => {
+                    let (count, value) = rest.split_once(' ').ok_or_else(|| {
+                        anyhow::anyhow!("Invalid tag statistics value {:?}", line)
+                    })?;
+                    current_key
+                        .as_ref()
+                        .and_then(|key| statistics.keys.get_mut(key))
+                        .ok_or_else(|| anyhow::anyhow!("Tag statistics value before any key"))?
+                        .values
+                        .insert(unescape(value), count.parse()?);
+                }
+                _ => anyhow::bail!("Invalid tag statistics line {:?}", line),
+            }
+        }
+        Ok(statistics)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Combines the statistics of the given element types into the `limit` most used keys,
+/// or the `limit` most used values of `key`.
+pub(crate) fn summarize(
+    statistics: &[(ElementType, TypeStatistics)],
+    key: Option<&str>,
+    limit: usize,
+) -> TagStatistics {
+    let mut summary = TagStatistics::default();
+    let mut keys: HashMap<&str, TagCount> = HashMap::new();
+    let mut values: HashMap<&str, TagCount> = HashMap::new();
+
+    for (element_type, type_statistics) in statistics {
+        summary
+            .elements
+            .add(*element_type, type_statistics.elements);
+        summary
+            .tagged_elements
+            .add(*element_type, type_statistics.tagged);
+
+        for (name, key_statistics) in &type_statistics.keys {
+            if key.is_some_and(|key| key != name) {
+                continue;
+            }
+            let entry = keys.entry(name).or_insert_with(|| tag_count(name));
+            entry.counts.add(*element_type, key_statistics.count);
+            entry.values_capped |= key_statistics.other_values > 0;
+
+            if key.is_none() {
+                continue;
+            }
+            for (value, count) in &key_statistics.values {
+                values
+                    .entry(value)
+                    .or_insert_with(|| tag_count(value))
+                    .counts
+                    .add(*element_type, *count);
+            }
+        }
+    }
+
+    // Values of different element types overlap, so distinct values are counted
+    // after merging them.
+    for (name, entry) in keys.iter_mut() {
+        let mut distinct: Vec<&str> = statistics
+            .iter()
+            .filter_map(|(_, type_statistics)| type_statistics.keys.get(*name))
+            .flat_map(|key_statistics| key_statistics.values.keys().map(String::as_str))
+            .collect();
+        distinct.sort_unstable();
+        distinct.dedup();
+        entry.distinct_values = distinct.len() as u64;
+    }
+
+    summary.keys = most_used(keys.into_values().collect(), limit);
+    if key.is_some() {
+        summary.values = most_used(values.into_values().collect(), limit);
+    }
+    summary
+}
+
+fn tag_count(text: &str) -> TagCount {
+    TagCount {
+        text: text.to_string(),
+        counts: TypeCounts::default(),
+        distinct_values: 0,
+        values_capped: false,
+    }
+}
+
+fn most_used(mut counts: Vec<TagCount>, limit: usize) -> Vec<TagCount> {
+    counts.sort_by(|a, b| {
+        b.counts
+            .total()
+            .cmp(&a.counts.total())
+            .then_with(|| a.text.cmp(&b.text))
+    });
+    counts.truncate(limit);
+    counts
+}
+
+/// Counts keys and values per element type while batching. Journals every element's
+/// tags so an interrupted run can rebuild the counts, like the spatial index.
+pub(crate) struct TagStatisticsBuilder {
+    types: [TypeStatistics; 3],
+    journal: Option<std::io::BufWriter<std::fs::File>>,
+    journal_len: u64,
+}
+
+impl TagStatisticsBuilder {
+    pub fn with_journal(journal_path: &str) -> Result<Self> {
+        Ok(TagStatisticsBuilder {
+            types: Default::default(),
+            journal: Some(std::io::BufWriter::new(std::fs::File::create(
+                journal_path,
+            )?)),
+            journal_len: 0,
+        })
+    }
+
+    /// Replays the first `journal_len` bytes of a journal, as reported by `checkpoint`,
+    /// and keeps appending after them.
+    pub fn resume(journal_path: &str, journal_len: u64) -> Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(journal_path)?;
+        if file.metadata()?.len() < journal_len {
+            anyhow::bail!("{} is shorter than its checkpoint", journal_path);
+        }
+        file.set_len(journal_len)?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut builder = TagStatisticsBuilder {
+            types: Default::default(),
+            journal: None,
+            journal_len: 0,
+        };
+        let mut rest = bytes.as_slice();
+        while !rest.is_empty() {
+            // element type (u8) + tag count (u32) + per tag key and value, each as a
+            // length (u32) and UTF-8 bytes
+            let header = take(&mut rest, 5)?;
+            let Some(&element_type) = ElementType::ALL.get(header[0] as usize) else {
+                anyhow::bail!("Invalid tag journal record");
+            };
+            let count = u32::from_le_bytes(header[1..5].try_into().unwrap());
+            let mut tags = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                tags.push((take_text(&mut rest)?, take_text(&mut rest)?));
+            }
+            builder.insert(element_type, &tags);
+        }
+
+        builder.journal = Some(std::io::BufWriter::new(file));
+        builder.journal_len = journal_len;
+        Ok(builder)
+    }
+
+    /// Flushes the journal and returns its length.
+    pub fn checkpoint(&mut self) -> Result<u64> {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.flush()?;
+        }
+        Ok(self.journal_len)
+    }
+
+    pub fn add(&mut self, element_type: ElementType, tags: &[(String, String)]) -> Result<()> {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.write_all(&[element_type.index() as u8])?;
+            journal.write_all(&(tags.len() as u32).to_le_bytes())?;
+            self.journal_len += 5;
+            for (key, value) in tags {
+                for text in [key, value] {
+                    journal.write_all(&(text.len() as u32).to_le_bytes())?;
+                    journal.write_all(text.as_bytes())?;
+                    self.journal_len += 4 + text.len() as u64;
+                }
+            }
+        }
+        self.insert(element_type, tags);
+        Ok(())
+    }
+
+    fn insert(&mut self, element_type: ElementType, tags: &[(String, String)]) {
+        let statistics = &mut self.types[element_type.index()];
+        statistics.elements += 1;
+        if !tags.is_empty() {
+            statistics.tagged += 1;
+        }
+        for (key, value) in tags {
+            let key_statistics = match statistics.keys.get_mut(key) {
+                Some(key_statistics) => key_statistics,
+                None => statistics.keys.entry(key.clone()).or_default(),
+            };
+            key_statistics.count += 1;
+            if let Some(count) = key_statistics.values.get_mut(value) {
+                *count += 1;
+            } else if key_statistics.values.len() < MAX_VALUES_PER_KEY {
+                key_statistics.values.insert(value.clone(), 1);
+            } else {
+                key_statistics.other_values += 1;
+            }
+        }
+    }
+
+    /// Writes the statistics of `element_type` to `path`.
+    pub async fn write(&self, element_type: ElementType, path: &str) -> Result<()> {
+        self.types[element_type.index()].write(path).await
+    }
+}
+
+fn take<'a>(rest: &mut &'a [u8], length: usize) -> Result<&'a [u8]> {
+    let (taken, tail) = rest
+        .split_at_checked(length)
+        .ok_or_else(|| anyhow::anyhow!("Truncated tag journal record"))?;
+    *rest = tail;
+    Ok(taken)
+}
+
+fn take_text(rest: &mut &[u8]) -> Result<String> {
+    let length = u32::from_le_bytes(take(rest, 4)?.try_into().unwrap()) as usize;
+    Ok(String::from_utf8(take(rest, length)?.to_vec())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn unjournaled() -> TagStatisticsBuilder {
+        TagStatisticsBuilder {
+            types: Default::default(),
+            journal: None,
+            journal_len: 0,
+        }
+    }
+
+    fn take_types(builder: TagStatisticsBuilder) -> Vec<(ElementType, TypeStatistics)> {
+        ElementType::ALL.into_iter().zip(builder.types).collect()
+    }
+
+    fn texts(counts: &[TagCount]) -> Vec<(&str, u64)> {
+        counts
+            .iter()
+            .map(|count| (count.text.as_str(), count.counts.total()))
+            .collect()
+    }
+
+    fn sample(builder: &mut TagStatisticsBuilder) {
+        let node = ElementType::Node;
+        let way = ElementType::Way;
+        builder
+            .add(node, &tags(&[("amenity", "cafe"), ("name", "A")]))
+            .unwrap();
+        builder.add(node, &tags(&[("amenity", "bank")])).unwrap();
+        builder.add(node, &[]).unwrap();
+        builder
+            .add(way, &tags(&[("highway", "residential"), ("name", "B")]))
+            .unwrap();
+        builder
+            .add(way, &tags(&[("highway", "primary"), ("amenity", "cafe")]))
+            .unwrap();
+        builder
+            .add(way, &tags(&[("highway", "residential")]))
+            .unwrap();
+    }
+
+    #[test]
+    fn summarizes_the_most_used_keys() {
+        let mut builder = unjournaled();
+        sample(&mut builder);
+        let summary = summarize(&take_types(builder), None, 2);
+
+        assert_eq!(summary.elements.nodes, 3);
+        assert_eq!(summary.elements.ways, 3);
+        assert_eq!(summary.tagged_elements.total(), 5);
+        // Ties are broken by name, and only `limit` keys are returned.
+        assert_eq!(texts(&summary.keys), [("amenity", 3), ("highway", 3)]);
+        assert_eq!(summary.keys[0].counts.nodes, 2);
+        assert_eq!(summary.keys[0].counts.ways, 1);
+        // Distinct values are counted across element types.
+        assert_eq!(summary.keys[0].distinct_values, 2);
+        assert!(summary.values.is_empty());
+    }
+
+    #[test]
+    fn summarizes_the_values_of_one_key() {
+        let mut builder = unjournaled();
+        sample(&mut builder);
+        let statistics = take_types(builder);
+
+        let summary = summarize(&statistics, Some("highway"), 10);
+        assert_eq!(texts(&summary.keys), [("highway", 3)]);
+        assert_eq!(texts(&summary.values), [("residential", 2), ("primary", 1)]);
+
+        let summary = summarize(&statistics, Some("highway"), 1);
+        assert_eq!(texts(&summary.values), [("residential", 2)]);
+
+        let summary = summarize(&statistics, Some("missing"), 10);
+        assert!(summary.keys.is_empty() && summary.values.is_empty());
+    }
+
+    #[test]
+    fn caps_distinct_values_per_key() {
+        let mut builder = unjournaled();
+        for i in 0..=MAX_VALUES_PER_KEY {
+            builder
+                .add(ElementType::Node, &tags(&[("name", &i.to_string())]))
+                .unwrap();
+        }
+        let summary = summarize(&take_types(builder), Some("name"), 1);
+        assert_eq!(
+            summary.keys[0].counts.total(),
+            MAX_VALUES_PER_KEY as u64 + 1
+        );
+        assert_eq!(summary.keys[0].distinct_values, MAX_VALUES_PER_KEY as u64);
+        assert!(summary.keys[0].values_capped);
+    }
+
+    #[test]
+    fn replays_the_journal_up_to_the_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("tag-stats-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let journal = dir.join("tags.journal");
+        let journal = journal.to_str().unwrap();
+
+        let mut builder = TagStatisticsBuilder::with_journal(journal).unwrap();
+        sample(&mut builder);
+        let checkpoint = builder.checkpoint().unwrap();
+        // Written after the checkpoint, so lost when resuming from it.
+        builder
+            .add(ElementType::Relation, &tags(&[("type", "route")]))
+            .unwrap();
+        drop(builder);
+
+        let mut resumed = TagStatisticsBuilder::resume(journal, checkpoint).unwrap();
+        resumed
+            .add(ElementType::Node, &tags(&[("line\nbreak", "back\\slash")]))
+            .unwrap();
+        let checkpoint = resumed.checkpoint().unwrap();
+        let resumed = TagStatisticsBuilder::resume(journal, checkpoint).unwrap();
+
+        let mut expected = unjournaled();
+        sample(&mut expected);
+        expected
+            .add(ElementType::Node, &tags(&[("line\nbreak", "back\\slash")]))
+            .unwrap();
+        let resumed = summarize(&take_types(resumed), None, 10);
+        let expected = summarize(&take_types(expected), None, 10);
+        assert_eq!(texts(&resumed.keys), texts(&expected.keys));
+        assert_eq!(resumed.elements.relations, 0);
+        assert_eq!(resumed.elements.total(), expected.elements.total());
+
+        assert!(TagStatisticsBuilder::resume(journal, checkpoint + 1).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn written_statistics_parse_back() {
+        let dir = std::env::temp_dir().join(format!("tag-stats-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("node.tags");
+        let path = path.to_str().unwrap();
+
+        let mut builder = unjournaled();
+        sample(&mut builder);
+        builder
+            .add(ElementType::Node, &tags(&[("line\nbreak", "back\\slash")]))
+            .unwrap();
+        builder.write(ElementType::Node, path).await.unwrap();
+
+        let parsed = TypeStatistics::parse(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let summary = summarize(&[(ElementType::Node, parsed)], Some("line\nbreak"), 10);
+        assert_eq!(summary.elements.nodes, 4);
+        assert_eq!(summary.tagged_elements.nodes, 3);
+        assert_eq!(texts(&summary.values), [("back\\slash", 1)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
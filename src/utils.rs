@@ -1,11 +1,26 @@
 use anyhow::Result;
-use flate2::read::GzDecoder;
-use reqwest;
-use std::path::Path;
+use flate2::read::MultiGzDecoder;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use tokio::fs;
-use tracing::{error, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, warn};
 
-pub async fn download_file(url: &str, output_path: &str) -> Result<()> {
+use crate::error::{ErrorCode, ServiceError};
+use crate::jobs::{check_cancelled, ImportCancelled};
+
+pub async fn download_file(url: &str, output_path: &str, cancel: &CancellationToken) -> Result<()> {
+    download_file_with(&reqwest::Client::new(), url, output_path, None, cancel).await
+}
+
+/// `download_file` through `client`, failing as soon as more than `max_bytes` arrive.
+pub(crate) async fn download_file_with(
+    client: &reqwest::Client,
+    url: &str,
+    output_path: &str,
+    max_bytes: Option<u64>,
+    cancel: &CancellationToken,
+) -> Result<()> {
     use futures_util::StreamExt;
     use tokio::io::AsyncWriteExt;
 
@@ -13,72 +28,204 @@ pub async fn download_file(url: &str, output_path: &str) -> Result<()> {
         fs::create_dir_all(parent).await?;
     }
 
-    let response = reqwest::get(url).await?;
-    if !response.status().is_success() {
-        anyhow::bail!("Download failed with status: {}", response.status());
+    let unavailable = |e: reqwest::Error| {
+        ServiceError::new(
+            ErrorCode::UpstreamUnavailable,
+            format!("Download failed: {}", e),
+        )
+        .with_detail("url", url)
+    };
+
+    let response = client.get(url).send().await.map_err(unavailable)?;
+    let status = response.status();
+    if !status.is_success() {
+        let code = match status {
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE => {
+                ErrorCode::UpstreamNotFound
+            }
+            _ => ErrorCode::UpstreamUnavailable,
+        };
+        return Err(
+            ServiceError::new(code, format!("Download failed with status: {}", status))
+                .with_detail("url", url)
+                .with_detail("status", status.as_u16())
+                .into(),
+        );
+    }
+
+    let too_large = |max_bytes: u64| {
+        ServiceError::invalid_argument(format!(
+            "Download is larger than the limit of {} bytes",
+            max_bytes
+        ))
+        .with_detail("url", url)
+    };
+    if let (Some(max_bytes), Some(length)) = (max_bytes, response.content_length()) {
+        if length > max_bytes {
+            return Err(too_large(max_bytes).into());
+        }
     }
 
-    let mut file = tokio::fs::File::create(output_path).await?;
+    // A partial download must never be mistaken for a complete one.
+    let temp_path = format!("{}.temp", output_path);
+    let mut file = tokio::fs::File::create(&temp_path).await?;
     let mut stream = response.bytes_stream();
+    let mut downloaded = 0;
 
     while let Some(chunk) = stream.next().await {
-        let chunk = chunk?;
+        check_cancelled(cancel)?;
+        let chunk = chunk.map_err(unavailable)?;
+        downloaded += chunk.len() as u64;
+        // The length header is optional and may be wrong, so the body is counted too.
+        if let Some(max_bytes) = max_bytes.filter(|&max_bytes| downloaded > max_bytes) {
+            drop(file);
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(too_large(max_bytes).into());
+        }
         file.write_all(&chunk).await?;
     }
 
     file.flush().await?;
+    fs::rename(&temp_path, output_path).await?;
     Ok(())
 }
 
-pub async fn decompress_gz(input_path: &str, output_path: &str) -> Result<()> {
-    if Path::new(output_path).exists() {
-        return Ok(());
+/// How a file on disk is compressed, told by its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Bzip2,
+    Zstd,
+}
+
+pub(crate) type InputStream = Box<dyn BufRead + Send>;
+
+impl Compression {
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".gz") {
+            Compression::Gzip
+        } else if path.ends_with(".bz2") {
+            Compression::Bzip2
+        } else if path.ends_with(".zst") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
     }
 
-    let gz_data = fs::read(input_path).await?;
-    let mut decoder = GzDecoder::new(&gz_data[..]);
-    let mut decompressed = Vec::new();
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Bzip2 => ".bz2",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// Decompresses `source` as it is read. Multi-member gzip and multi-stream bzip2
+    /// files, as written by parallel compressors, are read to the end.
+    pub(crate) fn decoder(self, source: InputStream) -> Result<InputStream> {
+        Ok(match self {
+            Compression::None => source,
+            Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(source))),
+            Compression::Bzip2 => {
+                Box::new(BufReader::new(bzip2::bufread::MultiBzDecoder::new(source)))
+            }
+            Compression::Zstd => Box::new(BufReader::new(
+                zstd::stream::read::Decoder::with_buffer(source)?,
+            )),
+        })
+    }
+}
 
-    use std::io::Read;
-    decoder.read_to_end(&mut decompressed)?;
+/// Lowercase hex encoding, as used for SHA-256 digests and SigV4 signatures.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
 
-    fs::write(output_path, decompressed).await?;
-    Ok(())
+/// The file name batches are named after: the input's name without its compression
+/// extension, e.g. `000_000_001.osc` for `000_000_001.osc.gz`.
+pub(crate) fn uncompressed_filename(path: &str) -> Result<String> {
+    let filename = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow::anyhow!("Invalid input path {}", path))?;
+    let extension = Compression::from_path(path).extension();
+    Ok(filename[..filename.len() - extension.len()].to_string())
 }
 
-pub async fn convert_pbf_to_xml(pbf_file: &str, xml_file: &str) -> Result<()> {
-    let pbf_metadata = fs::metadata(pbf_file).await?;
+/// Opens an input file, decompressed by its extension, `offset` bytes into its
+/// uncompressed content. Compressed files are decompressed up to there.
+pub(crate) fn open_input(path: &str, offset: u64) -> Result<InputStream> {
+    let mut file = std::fs::File::open(path)?;
+    let compression = Compression::from_path(path);
+    if compression == Compression::None {
+        file.seek(SeekFrom::Start(offset))?;
+        return Ok(Box::new(BufReader::new(file)));
+    }
 
-    if pbf_metadata.len() < 1000 {
-        error!(
-            "PBF file is suspiciously small ({} bytes) - likely a 404 error page",
-            pbf_metadata.len()
+    let mut input = compression.decoder(Box::new(BufReader::new(file)))?;
+    let skipped = std::io::copy(&mut (&mut input).take(offset), &mut std::io::sink())?;
+    if skipped != offset {
+        anyhow::bail!(
+            "{} ends after {} uncompressed bytes, before offset {}",
+            path,
+            skipped,
+            offset
         );
-        anyhow::bail!("Downloaded PBF file appears to be invalid (too small)");
     }
+    Ok(input)
+}
+
+pub async fn convert_pbf_to_xml(
+    pbf_file: &str,
+    xml_file: &str,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    crate::validation::check_pbf_source(pbf_file).await?;
 
     let xml_temp_file = format!("{}.temp", xml_file);
+    let (input_format, output_format) = if pbf_file.ends_with(".osh.pbf") {
+        ("osh.pbf", "osh")
+    } else {
+        ("osm.pbf", "osm")
+    };
 
     let osmium_result = tokio::process::Command::new("osmium")
-        .args(&[
+        .args([
             "cat",
             pbf_file,
             "-F",
-            "osm.pbf",
+            input_format,
             "-o",
             &xml_temp_file,
             "-f",
-            "osm",
+            output_format,
         ])
-        .output()
-        .await;
+        .kill_on_drop(true)
+        .output();
 
+    // Dropping the pending output future kills osmium.
+    let osmium_result = tokio::select! {
+        result = osmium_result => result,
+        _ = cancel.cancelled() => return Err(ImportCancelled.into()),
+    };
+
+    let mut failure = ServiceError::new(
+        ErrorCode::ConversionFailed,
+        "PBF to XML conversion failed. Please install osmium-tool: 'sudo apt-get install osmium-tool' or similar for your OS.",
+    )
+    .with_detail("file", pbf_file);
     match osmium_result {
         Ok(output) if output.status.success() => {
             fs::rename(&xml_temp_file, xml_file).await?;
             return Ok(());
         }
         Ok(output) => {
+            if let Some(code) = output.status.code() {
+                failure = failure.with_detail("exit_code", code);
+            }
             error!(
                 "osmium-tool failed with exit code: {:?}",
                 output.status.code()
@@ -95,5 +242,172 @@ pub async fn convert_pbf_to_xml(pbf_file: &str, xml_file: &str) -> Result<()> {
         }
     }
 
-    anyhow::bail!("PBF to XML conversion failed. Please install osmium-tool: 'sudo apt-get install osmium-tool' or similar for your OS.");
+    Err(failure.into())
+}
+
+/// The `osmosis_replication_timestamp` header option of a PBF file, which extracts
+/// such as Geofabrik's carry. Missing or unreadable headers are not an error.
+pub async fn pbf_replication_timestamp(pbf_file: &str) -> Option<String> {
+    let output = tokio::process::Command::new("osmium")
+        .args([
+            "fileinfo",
+            "-g",
+            "header.option.osmosis_replication_timestamp",
+            pbf_file,
+        ])
+        .output()
+        .await;
+
+    match output {
+        Ok(output) if output.status.success() => {
+            let timestamp = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            Some(timestamp).filter(|timestamp| !timestamp.is_empty())
+        }
+        Ok(output) => {
+            warn!(
+                "Cannot read replication timestamp of {}: {}",
+                pbf_file,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            warn!("Cannot read replication timestamp of {}: {}", pbf_file, e);
+            None
+        }
+    }
+}
+
+/// The `timestamp` of a replication `state.txt`, which escapes colons as `\:`.
+pub async fn state_replication_timestamp(state_file: &str) -> Result<Option<String>> {
+    let state = fs::read_to_string(state_file).await?;
+    Ok(state
+        .lines()
+        .find_map(|line| line.strip_prefix("timestamp="))
+        .map(|timestamp| timestamp.trim().replace("\\:", ":")))
+}
+
+/// Removes every `*.temp` file below `dir`, left behind by an interrupted import.
+pub async fn remove_temp_files(dir: &str) -> Result<()> {
+    let mut pending = vec![PathBuf::from(dir)];
+    while let Some(dir) = pending.pop() {
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                pending.push(entry.path());
+            } else if entry.file_name().to_string_lossy().ends_with(".temp") {
+                fs::remove_file(entry.path()).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const CONTENT: &str =
+        "<?xml version='1.0'?>\n<osm version=\"0.6\">\n  <node id=\"1\"/>\n</osm>\n";
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("utils-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn bzip2(data: &[u8]) -> Vec<u8> {
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd(data: &[u8]) -> Vec<u8> {
+        zstd::encode_all(data, 0).unwrap()
+    }
+
+    /// Compresses the two halves of `CONTENT` separately and concatenates them, as
+    /// parallel compressors do.
+    fn in_two_members(compress: fn(&[u8]) -> Vec<u8>) -> Vec<u8> {
+        let (first, second) = CONTENT.as_bytes().split_at(CONTENT.len() / 2);
+        [compress(first), compress(second)].concat()
+    }
+
+    fn read_to_string(mut input: InputStream) -> String {
+        let mut content = String::new();
+        input.read_to_string(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn decodes_every_compression() {
+        let dir = test_dir("codecs");
+        let files: [(&str, Vec<u8>); 7] = [
+            ("plain.osm", CONTENT.as_bytes().to_vec()),
+            ("single.osm.gz", gzip(CONTENT.as_bytes())),
+            ("multi.osm.gz", in_two_members(gzip)),
+            ("single.osm.bz2", bzip2(CONTENT.as_bytes())),
+            ("multi.osm.bz2", in_two_members(bzip2)),
+            ("single.osm.zst", zstd(CONTENT.as_bytes())),
+            ("multi.osm.zst", in_two_members(zstd)),
+        ];
+        for (name, data) in files {
+            let path = dir.join(name);
+            std::fs::write(&path, data).unwrap();
+            let input = open_input(path.to_str().unwrap(), 0).unwrap();
+            assert_eq!(read_to_string(input), CONTENT, "{}", name);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resumes_at_a_decompressed_offset() {
+        let dir = test_dir("offset");
+        // Past the end of the first member, so resuming has to cross into the second.
+        let offset = CONTENT.len() / 2 + 3;
+        let files: [(&str, Vec<u8>); 4] = [
+            ("plain.osm", CONTENT.as_bytes().to_vec()),
+            ("multi.osm.gz", in_two_members(gzip)),
+            ("multi.osm.bz2", in_two_members(bzip2)),
+            ("multi.osm.zst", in_two_members(zstd)),
+        ];
+        for (name, data) in files {
+            let path = dir.join(name);
+            std::fs::write(&path, data).unwrap();
+            let input = open_input(path.to_str().unwrap(), offset as u64).unwrap();
+            assert_eq!(read_to_string(input), CONTENT[offset..], "{}", name);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_offsets_past_the_decompressed_end() {
+        let dir = test_dir("past-end");
+        let path = dir.join("short.osm.gz");
+        std::fs::write(&path, gzip(CONTENT.as_bytes())).unwrap();
+        let offset = CONTENT.len() as u64 + 1;
+        let e = open_input(path.to_str().unwrap(), offset).err().unwrap();
+        assert!(
+            e.to_string().contains(&format!(
+                "ends after {} uncompressed bytes, before offset {}",
+                CONTENT.len(),
+                offset
+            )),
+            "{}",
+            e
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
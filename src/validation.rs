@@ -0,0 +1,727 @@
+use anyhow::Result;
+use quick_xml::events::Event;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use tokio::fs;
+use tokio_util::sync::CancellationToken;
+
+use crate::jobs::check_cancelled;
+use crate::utils::{self, Compression};
+use crate::ElementType;
+
+/// Written into an import directory when its input failed validation, so clients see
+/// why until the import is started again or deleted.
+const MARKER_FILE: &str = "invalid_input";
+
+/// PBF limits from the format specification.
+const MAX_BLOB_HEADER_SIZE: u32 = 64 * 1024;
+const MAX_BLOB_SIZE: u64 = 32 * 1024 * 1024;
+
+const SUPPORTED_PBF_FEATURES: [&str; 3] = ["OsmSchema-V0.6", "DenseNodes", "HistoricalInformation"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputErrorCode {
+    EmptyFile,
+    /// An HTML error page saved in place of the requested file.
+    HtmlPage,
+    UnknownFormat,
+    InvalidPbfHeader,
+    TruncatedFile,
+    CorruptCompression,
+    MalformedXml,
+    UnexpectedRoot,
+    UnsortedIds,
+}
+
+const INPUT_ERROR_CODES: [InputErrorCode; 9] = [
+    InputErrorCode::EmptyFile,
+    InputErrorCode::HtmlPage,
+    InputErrorCode::UnknownFormat,
+    InputErrorCode::InvalidPbfHeader,
+    InputErrorCode::TruncatedFile,
+    InputErrorCode::CorruptCompression,
+    InputErrorCode::MalformedXml,
+    InputErrorCode::UnexpectedRoot,
+    InputErrorCode::UnsortedIds,
+];
+
+impl InputErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            InputErrorCode::EmptyFile => "empty_file",
+            InputErrorCode::HtmlPage => "html_page",
+            InputErrorCode::UnknownFormat => "unknown_format",
+            InputErrorCode::InvalidPbfHeader => "invalid_pbf_header",
+            InputErrorCode::TruncatedFile => "truncated_file",
+            InputErrorCode::CorruptCompression => "corrupt_compression",
+            InputErrorCode::MalformedXml => "malformed_xml",
+            InputErrorCode::UnexpectedRoot => "unexpected_root",
+            InputErrorCode::UnsortedIds => "unsorted_ids",
+        }
+    }
+
+    fn from_str(code: &str) -> Option<Self> {
+        INPUT_ERROR_CODES
+            .into_iter()
+            .find(|candidate| candidate.as_str() == code)
+    }
+}
+
+/// An input file that cannot be batched. Returned inside `anyhow::Error`; callers tell
+/// it apart with `downcast_ref`.
+#[derive(Debug, Clone)]
+pub struct InvalidInput {
+    pub code: InputErrorCode,
+    pub message: String,
+}
+
+impl std::fmt::Display for InvalidInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid input ({}): {}",
+            self.code.as_str(),
+            self.message
+        )
+    }
+}
+
+impl std::error::Error for InvalidInput {}
+
+pub(crate) fn invalid_input(code: InputErrorCode, message: String) -> anyhow::Error {
+    InvalidInput { code, message }.into()
+}
+
+/// Whether batching first reads the whole XML input to validate it, with
+/// `INPUT_VALIDATION=full`. Otherwise batching checks the input as it reads it.
+pub(crate) fn full_validation_enabled() -> bool {
+    std::env::var("INPUT_VALIDATION").as_deref() == Ok("full")
+}
+
+/// How element ids must be ordered within each element type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IdOrder {
+    /// Extracts list every element once, by ascending id.
+    Ascending,
+    /// History files list all versions of an element together.
+    NonDecreasing,
+    /// Change files are grouped by action, so ids need not be ordered.
+    Any,
+}
+
+impl IdOrder {
+    pub(crate) fn for_import_type(import_type: &str) -> Self {
+        match import_type {
+            "full" => IdOrder::Ascending,
+            "history" => IdOrder::NonDecreasing,
+            _ => IdOrder::Any,
+        }
+    }
+}
+
+/// Checks the ids of a stream of elements against an `IdOrder`.
+pub(crate) struct IdOrderCheck {
+    source: String,
+    id_order: IdOrder,
+    last_ids: [Option<i64>; 3],
+}
+
+impl IdOrderCheck {
+    pub(crate) fn new(source: &str, id_order: IdOrder) -> Self {
+        IdOrderCheck {
+            source: source.to_string(),
+            id_order,
+            last_ids: [None; 3],
+        }
+    }
+
+    pub(crate) fn check(&mut self, element_type: ElementType, id: i64) -> Result<()> {
+        let last_id = &mut self.last_ids[element_type.index()];
+        let in_order = match (*last_id, self.id_order) {
+            (_, IdOrder::Any) | (None, _) => true,
+            (Some(last), IdOrder::Ascending) => id > last,
+            (Some(last), IdOrder::NonDecreasing) => id >= last,
+        };
+        if !in_order {
+            return Err(invalid_input(
+                InputErrorCode::UnsortedIds,
+                format!(
+                    "{} lists {} {} after {}",
+                    self.source,
+                    element_type,
+                    id,
+                    last_id.unwrap_or_default()
+                ),
+            ));
+        }
+        *last_id = Some(id);
+        Ok(())
+    }
+}
+
+/// Cheap checks for a downloaded or fetched source file, told apart by its extension:
+/// not empty, not an HTML page, the right magic, and for PBF every blob header.
+pub(crate) async fn check_source(path: &str) -> Result<()> {
+    let path = path.to_string();
+    tokio::task::spawn_blocking(move || check_source_blocking(&path)).await?
+}
+
+/// The PBF checks of `check_source` for a file of any name.
+pub(crate) async fn check_pbf_source(path: &str) -> Result<()> {
+    let path = path.to_string();
+    tokio::task::spawn_blocking(move || {
+        check_head(&path)?;
+        check_pbf(&path)
+    })
+    .await?
+}
+
+/// The first bytes of a file that is neither empty nor an HTML page.
+fn check_head(path: &str) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    std::fs::File::open(path)?
+        .take(4096)
+        .read_to_end(&mut head)?;
+
+    if head.is_empty() {
+        return Err(invalid_input(
+            InputErrorCode::EmptyFile,
+            format!("{} is empty", path),
+        ));
+    }
+    if looks_like_html(&head) {
+        return Err(invalid_input(
+            InputErrorCode::HtmlPage,
+            format!("{} is an HTML page, not OSM data", path),
+        ));
+    }
+    Ok(head)
+}
+
+fn check_source_blocking(path: &str) -> Result<()> {
+    let head = check_head(path)?;
+    if path.ends_with(".pbf") {
+        return check_pbf(path);
+    }
+
+    let compression = Compression::from_path(path);
+    let xml_extension = [".osm", ".osc", ".osh"]
+        .iter()
+        .any(|extension| path.ends_with(&format!("{}{}", extension, compression.extension())));
+    if !xml_extension {
+        return Ok(());
+    }
+
+    let magic: &[u8] = match compression {
+        Compression::None => b"",
+        Compression::Gzip => &[0x1f, 0x8b],
+        Compression::Bzip2 => b"BZh",
+        Compression::Zstd => &[0x28, 0xb5, 0x2f, 0xfd],
+    };
+    if !head.starts_with(magic) {
+        return Err(invalid_input(
+            InputErrorCode::UnknownFormat,
+            format!("{} is not {:?} compressed", path, compression),
+        ));
+    }
+
+    let mut decoded = Vec::new();
+    if let Err(e) = utils::open_input(path, 0)?
+        .take(4096)
+        .read_to_end(&mut decoded)
+    {
+        return Err(invalid_input(
+            InputErrorCode::CorruptCompression,
+            format!("{} cannot be decompressed: {}", path, e),
+        ));
+    }
+    match root_tag(&decoded) {
+        Some(_) => Ok(()),
+        None => Err(invalid_input(
+            InputErrorCode::UnexpectedRoot,
+            format!(
+                "{} does not start with an <osm> or <osmChange> element",
+                path
+            ),
+        )),
+    }
+}
+
+fn looks_like_html(head: &[u8]) -> bool {
+    let start = String::from_utf8_lossy(&head[..head.len().min(512)]).to_ascii_lowercase();
+    let start = start.trim_start();
+    start.starts_with("<!doctype html") || start.starts_with("<html") || start.contains("<head>")
+}
+
+/// The root element name when `head` is the start of an OSM XML document.
+pub(crate) fn root_tag(head: &[u8]) -> Option<&'static str> {
+    let mut reader = quick_xml::Reader::from_reader(head);
+    let mut buffer = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buffer) {
+            Ok(Event::Start(ref e) | Event::Empty(ref e)) => {
+                return match e.local_name().as_ref() {
+                    b"osm" => Some("osm"),
+                    b"osmChange" => Some("osmChange"),
+                    _ => None,
+                };
+            }
+            Ok(Event::Eof) | Err(_) => return None,
+            Ok(_) => buffer.clear(),
+        }
+    }
+}
+
+/// Walks every blob header of a PBF file and reads the features its header block
+/// requires, without decoding any data blob.
+fn check_pbf(path: &str) -> Result<()> {
+    let invalid = |message: String| invalid_input(InputErrorCode::InvalidPbfHeader, message);
+    let truncated = || {
+        invalid_input(
+            InputErrorCode::TruncatedFile,
+            format!("{} ends in the middle of a blob", path),
+        )
+    };
+
+    let mut file = std::fs::File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let mut blobs = 0;
+    loop {
+        let mut length = [0; 4];
+        match file.read(&mut length[..1])? {
+            0 => break,
+            _ => file.read_exact(&mut length[1..]).map_err(|_| truncated())?,
+        }
+        let length = u32::from_be_bytes(length);
+        if length == 0 || length > MAX_BLOB_HEADER_SIZE {
+            return Err(invalid(format!(
+                "{} has a blob header of {} bytes at blob {}",
+                path, length, blobs
+            )));
+        }
+
+        let mut blob_header = vec![0; length as usize];
+        file.read_exact(&mut blob_header).map_err(|_| truncated())?;
+        let (blob_type, data_size) = parse_blob_header(&blob_header)
+            .ok_or_else(|| invalid(format!("{} has a malformed blob header", path)))?;
+        if data_size > MAX_BLOB_SIZE {
+            return Err(invalid(format!(
+                "{} has a blob of {} bytes",
+                path, data_size
+            )));
+        }
+
+        if blobs == 0 {
+            if blob_type != "OSMHeader" {
+                return Err(invalid(format!(
+                    "{} starts with a {:?} blob instead of OSMHeader",
+                    path, blob_type
+                )));
+            }
+            let mut blob = vec![0; data_size as usize];
+            file.read_exact(&mut blob).map_err(|_| truncated())?;
+            check_header_block(path, &blob)?;
+        } else {
+            let end = file.stream_position()? + data_size;
+            if end > file_size {
+                return Err(truncated());
+            }
+            file.seek(SeekFrom::Start(end))?;
+        }
+        blobs += 1;
+    }
+
+    if blobs == 0 {
+        return Err(invalid_input(
+            InputErrorCode::EmptyFile,
+            format!("{} has no blobs", path),
+        ));
+    }
+    Ok(())
+}
+
+fn check_header_block(path: &str, blob: &[u8]) -> Result<()> {
+    let invalid = |message: String| invalid_input(InputErrorCode::InvalidPbfHeader, message);
+
+    let mut raw = None;
+    let mut zlib_data = None;
+    for (field, value) in ProtobufFields(blob) {
+        match (field, value) {
+            (1, FieldValue::Bytes(bytes)) => raw = Some(bytes.to_vec()),
+            (3, FieldValue::Bytes(bytes)) => zlib_data = Some(bytes),
+            _ => {}
+        }
+    }
+    let header_block = match (raw, zlib_data) {
+        (Some(raw), _) => raw,
+        (None, Some(zlib_data)) => {
+            let mut header_block = Vec::new();
+            flate2::read::ZlibDecoder::new(zlib_data)
+                .read_to_end(&mut header_block)
+                .map_err(|e| invalid(format!("{} has an unreadable header block: {}", path, e)))?;
+            header_block
+        }
+        (None, None) => {
+            return Err(invalid(format!(
+                "{} has a header block in an unsupported compression",
+                path
+            )))
+        }
+    };
+
+    for (field, value) in ProtobufFields(&header_block) {
+        if let (4, FieldValue::Bytes(feature)) = (field, value) {
+            let feature = String::from_utf8_lossy(feature);
+            if !SUPPORTED_PBF_FEATURES.contains(&feature.as_ref()) {
+                return Err(invalid(format!(
+                    "{} requires the unsupported feature {}",
+                    path, feature
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_blob_header(blob_header: &[u8]) -> Option<(String, u64)> {
+    let mut blob_type = None;
+    let mut data_size = None;
+    let mut fields = ProtobufFields(blob_header);
+    for (field, value) in fields.by_ref() {
+        match (field, value) {
+            (1, FieldValue::Bytes(bytes)) => {
+                blob_type = Some(String::from_utf8_lossy(bytes).into_owned())
+            }
+            (3, FieldValue::Varint(size)) => data_size = Some(size),
+            _ => {}
+        }
+    }
+    if !fields.0.is_empty() {
+        return None;
+    }
+    Some((blob_type?, data_size?))
+}
+
+enum FieldValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// The top-level fields of a protobuf message, stopping at the first malformed one.
+struct ProtobufFields<'a>(&'a [u8]);
+
+impl<'a> ProtobufFields<'a> {
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.0.split_first()?;
+            self.0 = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+impl<'a> Iterator for ProtobufFields<'a> {
+    type Item = (u64, FieldValue<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let remaining = self.0;
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => FieldValue::Varint(self.varint()?),
+            2 => {
+                let length = self.varint()? as usize;
+                if length > self.0.len() {
+                    self.0 = remaining;
+                    return None;
+                }
+                let (bytes, rest) = self.0.split_at(length);
+                self.0 = rest;
+                FieldValue::Bytes(bytes)
+            }
+            _ => {
+                self.0 = remaining;
+                return None;
+            }
+        };
+        Some((key >> 3, value))
+    }
+}
+
+/// Reads the whole XML input once: it must be well-formed, have an `osm` or
+/// `osmChange` root and list the elements of each type in `id_order`.
+pub(crate) async fn check_xml(
+    path: &str,
+    id_order: IdOrder,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let path = path.to_string();
+    let cancel = cancel.clone();
+    tokio::task::spawn_blocking(move || check_xml_blocking(&path, id_order, &cancel)).await?
+}
+
+fn check_xml_blocking(path: &str, id_order: IdOrder, cancel: &CancellationToken) -> Result<()> {
+    let compressed = Compression::from_path(path) != Compression::None;
+    let mut reader = quick_xml::Reader::from_reader(utils::open_input(path, 0)?);
+    let mut buffer = Vec::new();
+    let mut depth = 0usize;
+    let mut root = None;
+    let mut id_order = IdOrderCheck::new(path, id_order);
+    let mut events = 0u64;
+
+    loop {
+        events += 1;
+        if events.is_multiple_of(100_000) {
+            check_cancelled(cancel)?;
+        }
+
+        let event = match reader.read_event_into(&mut buffer) {
+            Ok(event) => event,
+            Err(quick_xml::Error::Io(e)) => {
+                let code = if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                    InputErrorCode::TruncatedFile
+                } else if compressed {
+                    InputErrorCode::CorruptCompression
+                } else {
+                    return Err(anyhow::Error::from(std::io::Error::new(
+                        e.kind(),
+                        e.to_string(),
+                    )));
+                };
+                return Err(invalid_input(
+                    code,
+                    format!("{} cannot be read: {}", path, e),
+                ));
+            }
+            Err(e) => {
+                return Err(invalid_input(
+                    InputErrorCode::MalformedXml,
+                    format!(
+                        "{} is not well-formed at byte {}: {}",
+                        path,
+                        reader.error_position(),
+                        e
+                    ),
+                ));
+            }
+        };
+
+        let (element, is_empty) = match &event {
+            Event::Start(e) => (e, false),
+            Event::Empty(e) => (e, true),
+            Event::End(_) => {
+                depth = depth.saturating_sub(1);
+                buffer.clear();
+                continue;
+            }
+            Event::Eof => break,
+            _ => {
+                buffer.clear();
+                continue;
+            }
+        };
+
+        if root.is_none() {
+            let name = element.local_name();
+            if name.as_ref() != b"osm" && name.as_ref() != b"osmChange" {
+                return Err(invalid_input(
+                    InputErrorCode::UnexpectedRoot,
+                    format!(
+                        "{} has a <{}> root instead of <osm> or <osmChange>",
+                        path,
+                        String::from_utf8_lossy(name.as_ref())
+                    ),
+                ));
+            }
+            root = Some(name.as_ref() == b"osm");
+        } else if depth == 1 && root == Some(true) {
+            let element_type = std::str::from_utf8(element.local_name().as_ref())
+                .ok()
+                .and_then(|name| name.parse::<ElementType>().ok());
+            if let Some(element_type) = element_type {
+                let id = element
+                    .try_get_attribute("id")
+                    .ok()
+                    .flatten()
+                    .and_then(|id| std::str::from_utf8(&id.value).ok()?.parse::<i64>().ok())
+                    .ok_or_else(|| {
+                        invalid_input(
+                            InputErrorCode::MalformedXml,
+                            format!(
+                                "{} has an element without a valid id near byte {}",
+                                path,
+                                reader.buffer_position()
+                            ),
+                        )
+                    })?;
+                id_order.check(element_type, id)?;
+            }
+        }
+
+        if !is_empty {
+            depth += 1;
+        }
+        buffer.clear();
+    }
+
+    match root {
+        None => Err(invalid_input(
+            InputErrorCode::EmptyFile,
+            format!("{} has no root element", path),
+        )),
+        Some(_) if depth > 0 => Err(invalid_input(
+            InputErrorCode::TruncatedFile,
+            format!("{} ends before its root element is closed", path),
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Remembers why the input of an import was rejected.
+pub(crate) async fn record(import_dir: &str, invalid: &InvalidInput) -> Result<()> {
+    let marker = format!("{}/{}", import_dir, MARKER_FILE);
+    fs::write(
+        &marker,
+        format!("{}\n{}\n", invalid.code.as_str(), invalid.message),
+    )
+    .await?;
+    Ok(())
+}
+
+pub(crate) async fn clear(import_dir: &str) -> Result<()> {
+    match fs::remove_file(format!("{}/{}", import_dir, MARKER_FILE)).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// An XML error met while batching. Syntax errors and truncation make the input
+/// invalid; other read errors are returned as they are.
+pub(crate) fn xml_error(error: quick_xml::Error, offset: u64) -> anyhow::Error {
+    match error {
+        quick_xml::Error::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => truncated_xml(),
+        quick_xml::Error::Io(e) => std::io::Error::new(e.kind(), e.to_string()).into(),
+        e => invalid_input(
+            InputErrorCode::MalformedXml,
+            format!("The input is not well-formed at byte {}: {}", offset, e),
+        ),
+    }
+}
+
+pub(crate) fn truncated_xml() -> anyhow::Error {
+    invalid_input(
+        InputErrorCode::TruncatedFile,
+        "The input ends before its root element is closed".to_string(),
+    )
+}
+
+/// Why the input of the import in `import_dir` was rejected, if it was.
+pub(crate) async fn recorded(import_dir: &str) -> Option<InvalidInput> {
+    let content = fs::read_to_string(Path::new(import_dir).join(MARKER_FILE))
+        .await
+        .ok()?;
+    let (code, message) = content.split_once('\n')?;
+    Some(InvalidInput {
+        code: InputErrorCode::from_str(code)?,
+        message: message.trim_end().to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::OsmReader;
+
+    fn code(result: Result<impl Sized>) -> InputErrorCode {
+        match result {
+            Ok(_) => panic!("input was accepted"),
+            Err(e) => e.downcast_ref::<InvalidInput>().unwrap().code,
+        }
+    }
+
+    fn read_all(xml: &str) -> Result<usize> {
+        let mut reader = OsmReader::new(xml.as_bytes())?;
+        let mut elements = 0;
+        while reader.next_element()?.is_some() {
+            elements += 1;
+        }
+        Ok(elements)
+    }
+
+    #[test]
+    fn id_order_check() {
+        let mut ascending = IdOrderCheck::new("extract", IdOrder::Ascending);
+        ascending.check(ElementType::Node, 1).unwrap();
+        ascending.check(ElementType::Way, 1).unwrap();
+        ascending.check(ElementType::Node, 2).unwrap();
+        assert_eq!(
+            code(ascending.check(ElementType::Node, 2)),
+            InputErrorCode::UnsortedIds
+        );
+
+        let mut history = IdOrderCheck::new("history", IdOrder::NonDecreasing);
+        history.check(ElementType::Node, 2).unwrap();
+        history.check(ElementType::Node, 2).unwrap();
+        assert!(history.check(ElementType::Node, 1).is_err());
+
+        let mut change = IdOrderCheck::new("change", IdOrder::Any);
+        change.check(ElementType::Node, 2).unwrap();
+        change.check(ElementType::Node, 1).unwrap();
+    }
+
+    #[test]
+    fn reader_rejects_bad_xml() {
+        let complete = r#"<osm version="0.6"><node id="1" lat="1" lon="2"/><node id="2" lat="1" lon="2"></node></osm>"#;
+        assert_eq!(read_all(complete).unwrap(), 2);
+
+        assert_eq!(
+            code(read_all(
+                r#"<osm version="0.6"><node id="1" lat="1" lon="2"/>"#
+            )),
+            InputErrorCode::TruncatedFile
+        );
+        assert_eq!(
+            code(read_all(
+                r#"<osm version="0.6"><node id="1"><tag k="a" v="b"/>"#
+            )),
+            InputErrorCode::TruncatedFile
+        );
+        assert_eq!(
+            code(read_all(r#"<osm version="0.6"><node id="1"></way></osm>"#)),
+            InputErrorCode::MalformedXml
+        );
+        assert_eq!(
+            code(read_all("<gpx></gpx>")),
+            InputErrorCode::UnexpectedRoot
+        );
+    }
+
+    #[tokio::test]
+    async fn full_pass_checks_id_order() {
+        let dir = std::env::temp_dir().join(format!("validation-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("extract.osm");
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            path,
+            r#"<osm version="0.6"><node id="2" lat="1" lon="2"/><node id="1" lat="1" lon="2"/></osm>"#,
+        )
+        .unwrap();
+        let cancel = CancellationToken::new();
+
+        assert_eq!(
+            code(check_xml(path, IdOrder::Ascending, &cancel).await),
+            InputErrorCode::UnsortedIds
+        );
+        check_xml(path, IdOrder::Any, &cancel).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}